@@ -0,0 +1,243 @@
+use crate::config::OverflowPolicy;
+use futures::{
+    future,
+    stream::Stream,
+    task::{Context, Poll, Waker},
+};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+// State that is shared between the sender and the receiver of a bounded channel.
+struct Shared<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    // Number of messages that were dropped because the channel was full.
+    dropped: u64,
+    is_closed: bool,
+    rx_waker: Option<Waker>,
+    tx_waker: Option<Waker>,
+}
+
+// Create a bounded channel that buffers at most `capacity` messages.
+// If the channel is full, the overflow policy decides whether the oldest message is dropped,
+// or the message is kept back by the sender until the receiver took a message from the channel.
+pub fn bounded<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        capacity: capacity.max(1),
+        dropped: 0,
+        is_closed: false,
+        rx_waker: None,
+        tx_waker: None,
+    }));
+    let tx = BoundedSender {
+        shared: shared.clone(),
+        policy,
+        backlog: VecDeque::new(),
+    };
+    let rx = BoundedReceiver { shared };
+    (tx, rx)
+}
+
+pub struct BoundedSender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    policy: OverflowPolicy,
+    // Messages that did not fit into the full channel with the backpressure policy.
+    // They are moved into the channel by `flush` once the receiver caught up.
+    backlog: VecDeque<T>,
+}
+
+impl<T> BoundedSender<T> {
+    // Send a message through the channel without waiting for the receiver.
+    // With the backpressure policy, a message that does not fit into the full channel is kept
+    // in the backlog, which holds at most as many messages as the channel. The sender should
+    // `flush` the backlog before it produces new messages.
+    // Fails if the receiver was dropped.
+    pub fn send(&mut self, message: T) -> Result<(), String> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.is_closed {
+            return Err("Channel receiver was closed".into());
+        }
+        if !self.backlog.is_empty() || shared.queue.len() >= shared.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    shared.queue.pop_front();
+                    shared.dropped += 1;
+                }
+                OverflowPolicy::Backpressure => {
+                    if self.backlog.len() >= shared.capacity {
+                        self.backlog.pop_front();
+                        shared.dropped += 1;
+                    }
+                    self.backlog.push_back(message);
+                    return Ok(());
+                }
+            }
+        }
+        shared.queue.push_back(message);
+        if let Some(waker) = shared.rx_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    // Whether messages are kept back because the receiver does not keep up.
+    pub fn is_backlogged(&self) -> bool {
+        !self.backlog.is_empty()
+    }
+
+    // Wait until all messages of the backlog were moved into the channel.
+    // Fails if the receiver was dropped.
+    pub async fn flush(&mut self) -> Result<(), String> {
+        future::poll_fn(|cx: &mut Context<'_>| self.poll_flush(cx)).await
+    }
+
+    // Number of messages that were dropped because the receiver did not keep up.
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().unwrap().dropped
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), String>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.is_closed {
+            return Poll::Ready(Err("Channel receiver was closed".into()));
+        }
+        while shared.queue.len() < shared.capacity {
+            match self.backlog.pop_front() {
+                Some(message) => shared.queue.push_back(message),
+                None => break,
+            }
+        }
+        if !shared.queue.is_empty() {
+            if let Some(waker) = shared.rx_waker.take() {
+                waker.wake();
+            }
+        }
+        if self.backlog.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        shared.tx_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.is_closed = true;
+        if let Some(waker) = shared.rx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct BoundedReceiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Stream for BoundedReceiver<T> {
+    type Item = T;
+
+    // Return the next buffered message, or None if the sender was dropped and all messages
+    // have been received.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.queue.pop_front() {
+            Some(message) => {
+                if let Some(waker) = shared.tx_waker.take() {
+                    waker.wake();
+                }
+                Poll::Ready(Some(message))
+            }
+            None if shared.is_closed => Poll::Ready(None),
+            None => {
+                shared.rx_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.is_closed = true;
+        if let Some(waker) = shared.tx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{FutureExt, StreamExt};
+
+    // Receive all messages that are currently in the channel.
+    fn receive_all(rx: &mut BoundedReceiver<u32>) -> Vec<u32> {
+        let mut messages = Vec::new();
+        while let Some(Some(message)) = rx.next().now_or_never() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_messages() {
+        let (mut tx, mut rx) = bounded(2, OverflowPolicy::DropOldest);
+        for message in 1..=3 {
+            tx.send(message).unwrap();
+        }
+        assert!(!tx.is_backlogged());
+        assert_eq!(tx.dropped(), 1);
+        assert_eq!(receive_all(&mut rx), vec![2, 3]);
+    }
+
+    #[test]
+    fn backpressure_keeps_messages_back_until_the_receiver_caught_up() {
+        let (mut tx, mut rx) = bounded(2, OverflowPolicy::Backpressure);
+        for message in 1..=3 {
+            tx.send(message).unwrap();
+        }
+        // Sending does not wait for the receiver, the message is kept in the backlog.
+        assert!(tx.is_backlogged());
+        assert!(tx.flush().now_or_never().is_none());
+        assert_eq!(receive_all(&mut rx), vec![1, 2]);
+        assert!(tx.flush().now_or_never().unwrap().is_ok());
+        assert!(!tx.is_backlogged());
+        assert_eq!(receive_all(&mut rx), vec![3]);
+        assert_eq!(tx.dropped(), 0);
+
+        // The backlog is bounded by the capacity of the channel.
+        for message in 4..=9 {
+            tx.send(message).unwrap();
+        }
+        assert_eq!(tx.dropped(), 2);
+        assert!(tx.flush().now_or_never().is_none());
+        assert_eq!(receive_all(&mut rx), vec![4, 5]);
+        assert!(tx.flush().now_or_never().unwrap().is_ok());
+        assert_eq!(receive_all(&mut rx), vec![8, 9]);
+    }
+
+    #[test]
+    fn closed_channels_are_detected() {
+        let (mut tx, mut rx) = bounded(2, OverflowPolicy::Backpressure);
+        tx.send(1).unwrap();
+        drop(tx);
+        assert_eq!(receive_all(&mut rx), vec![1]);
+        assert_eq!(rx.next().now_or_never(), Some(None));
+
+        let (mut tx, rx) = bounded::<u32>(1, OverflowPolicy::Backpressure);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        drop(rx);
+        assert!(tx.send(3).is_err());
+        assert!(tx.flush().now_or_never().unwrap().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgGroup};
 
 pub fn subscribe_cmd<'a, 'b>() -> App<'a, 'b> {
     App::new("subscribe")
@@ -40,26 +40,130 @@ pub fn unsubscribe_cmd<'a, 'b>() -> App<'a, 'b> {
         )
 }
 
+pub fn catch_up_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("catch-up")
+        .about("receive the messages of a gossip-sub topic that other peers retained")
+        .usage("p2p catch-up --topic <topic>")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("topic")
+                .help("the topic to catch up on")
+                .short("t")
+                .long("topic")
+                .value_name("topic")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
 pub fn publish_cmd<'a, 'b>() -> App<'a, 'b> {
     App::new("publish")
     .about("publish data to certain gossip-sub topic")
-    .usage("p2p publish --topic <topic> [SUBCOMMAND]")
+    .usage("p2p publish --topic <topic> [--at <time>|--in <secs>] [--expire-after <secs>] [SUBCOMMAND]")
     .settings(&[AppSettings::DisableHelpSubcommand, AppSettings::DisableHelpFlags, AppSettings::DisableVersion])
     .subcommand(
         App::new("led")
-            .about("send LED configuration;\tUSAGE: p2p publish -t <topic> led (on|off|blink -f <frequency>)")
+            .about("send LED configuration;\tUSAGE: p2p publish -t <topic> led [-c <channel>] (on|off|brightness|color|blink|on-for|pattern)")
+            .arg(
+                Arg::with_name("channel")
+                    .help("the index of the LED or channel, all LEDs if not set")
+                    .short("c")
+                    .long("channel")
+                    .value_name("channel")
+                    .takes_value(true),
+            )
             .subcommand(App::new("on").about("LED on"))
             .subcommand(App::new("off").about("LED off"))
             .subcommand(
-                App::new("blink").about("blink LED").arg(
-                    Arg::with_name("frequency")
-                        .help("the frequency in seconds in which the led should blink")
-                        .short("f")
-                        .long("freq")
-                        .value_name("frequency")
+                App::new("brightness").about("set the brightness, i.g. the PWM duty cycle").arg(
+                    Arg::with_name("value")
+                        .help("the brightness in percent")
+                        .short("v")
+                        .long("value")
+                        .value_name("percent")
                         .takes_value(true)
                         .required(true),
                 ),
+            )
+            .subcommand(
+                App::new("color").about("set the color of an RGB LED").arg(
+                    Arg::with_name("value")
+                        .help("the color as hex value, e.g. ff8000")
+                        .short("v")
+                        .long("value")
+                        .value_name("rrggbb")
+                        .takes_value(true)
+                        .required(true),
+                ),
+            )
+            .subcommand(
+                App::new("blink")
+                    .about("blink LED;\tUSAGE: blink (-f <frequency>|--on <ms> --off <ms>)")
+                    .arg(
+                        Arg::with_name("frequency")
+                            .help("the frequency in Hz in which the led should blink")
+                            .short("f")
+                            .long("freq")
+                            .value_name("frequency")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("on")
+                            .help("the duration of the on phase in milliseconds")
+                            .long("on")
+                            .value_name("ms")
+                            .takes_value(true)
+                            .requires("off"),
+                    )
+                    .arg(
+                        Arg::with_name("off")
+                            .help("the duration of the off phase in milliseconds")
+                            .long("off")
+                            .value_name("ms")
+                            .takes_value(true)
+                            .requires("on"),
+                    )
+                    .group(
+                        ArgGroup::with_name("timing")
+                            .args(&["frequency", "on"])
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                App::new("on-for").about("turn LED on for a duration, and off afterwards").arg(
+                    Arg::with_name("duration")
+                        .help("the duration in milliseconds")
+                        .short("d")
+                        .long("duration")
+                        .value_name("ms")
+                        .takes_value(true)
+                        .required(true),
+                ),
+            )
+            .subcommand(
+                App::new("pattern")
+                    .about("show a sequence of colors;\tUSAGE: pattern -s <rrggbb:ms>,... [-r <repeat>]")
+                    .arg(
+                        Arg::with_name("steps")
+                            .help("the steps as comma separated color and duration in milliseconds, e.g. ff0000:500,000000:500")
+                            .short("s")
+                            .long("steps")
+                            .value_name("steps")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("repeat")
+                            .help("the number of times the pattern is shown, 0 repeats it forever; default 1")
+                            .short("r")
+                            .long("repeat")
+                            .value_name("repeat")
+                            .takes_value(true),
+                    ),
             ),
     )
     .subcommand(
@@ -75,6 +179,27 @@ pub fn publish_cmd<'a, 'b>() -> App<'a, 'b> {
                 .required(true),
         ),
     )
+    .subcommand(
+        App::new("custom")
+        .about("send a message of a registered application type;\tUSAGE: p2p publish -t <topic> custom --type <type> -v <value>")
+        .arg(
+            Arg::with_name("type")
+                .help("the tag under which the message type is registered")
+                .long("type")
+                .value_name("type")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("value")
+                .help("the value of the message, in the format that the type parses")
+                .short("v")
+                .long("value")
+                .value_name("value")
+                .takes_value(true)
+                .required(true),
+        ),
+    )
     .arg(
         Arg::with_name("topic")
             .help("the topic to which the data should be published")
@@ -84,12 +209,34 @@ pub fn publish_cmd<'a, 'b>() -> App<'a, 'b> {
             .takes_value(true)
             .required(true),
     )
+    .arg(
+        Arg::with_name("at")
+            .help("execute the message at a time of day in UTC (HH:MM[:SS]), or at a UNIX timestamp")
+            .long("at")
+            .value_name("time")
+            .takes_value(true)
+            .conflicts_with("in"),
+    )
+    .arg(
+        Arg::with_name("in")
+            .help("execute the message after a delay in seconds")
+            .long("in")
+            .value_name("secs")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("expire-after")
+            .help("ignore the message if it is not executed within this many seconds")
+            .long("expire-after")
+            .value_name("secs")
+            .takes_value(true),
+    )
 }
 
 pub fn get_record_cmd<'a, 'b>() -> App<'a, 'b> {
     App::new("get-record")
         .about("query for a kademlia record")
-        .usage("p2p get-record --key <key>")
+        .usage("p2p get-record --key <key> [--quorum <quorum>] [--format <format>|--out <path>]")
         .settings(&[
             AppSettings::DisableHelpSubcommand,
             AppSettings::DisableHelpFlags,
@@ -104,12 +251,37 @@ pub fn get_record_cmd<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("quorum")
+                .help("the number of peers that have to respond: one, majority, all or a number")
+                .short("q")
+                .long("quorum")
+                .value_name("quorum")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("the encoding in which the value is printed")
+                .long("format")
+                .value_name("format")
+                .possible_values(&["utf8", "hex", "base64"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("out")
+                .help("write the value to a file instead of printing it")
+                .short("o")
+                .long("out")
+                .value_name("path")
+                .takes_value(true)
+                .conflicts_with("format"),
+        )
 }
 
 pub fn put_record_cmd<'a, 'b>() -> App<'a, 'b> {
     App::new("put-record")
         .about("publish a record to the kademlia DHT")
-        .usage("p2p put-record --key <key> --value <value>")
+        .usage("p2p put-record --key <key> (--value <value>|--file <path>|--hex <hex>|--base64 <base64>) [--quorum <quorum>] [--ttl <seconds>] [--signed]")
         .settings(&[
             AppSettings::DisableHelpSubcommand,
             AppSettings::DisableHelpFlags,
@@ -126,19 +298,204 @@ pub fn put_record_cmd<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("value")
-                .help("the value of the record")
+                .help("the value of the record as string")
                 .short("v")
                 .long("value")
                 .value_name("value")
-                .takes_value(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .help("read the value of the record from a file")
+                .short("f")
+                .long("file")
+                .value_name("path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hex")
+                .help("the value of the record as hex string")
+                .long("hex")
+                .value_name("hex")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("base64")
+                .help("the value of the record as base64 string")
+                .long("base64")
+                .value_name("base64")
+                .takes_value(true),
+        )
+        .group(
+            ArgGroup::with_name("input")
+                .args(&["value", "file", "hex", "base64"])
                 .required(true),
         )
+        .arg(
+            Arg::with_name("quorum")
+                .help("the number of peers that have to respond: one, majority, all or a number")
+                .short("q")
+                .long("quorum")
+                .value_name("quorum")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .help("the time in seconds after which the record expires, 0 for no expiry")
+                .long("ttl")
+                .value_name("seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("signed")
+                .help("sign the record and publish it under the key /pk/<local-peer-id>/<key>")
+                .short("s")
+                .long("signed"),
+        )
 }
 
 pub fn connect_cmd<'a, 'b>() -> App<'a, 'b> {
     App::new("connect")
         .about("explicitly connect a new peer")
-        .usage("p2p connect -a <multi-address>")
+        .usage("p2p connect (-a <multi-address>|-p <peer-id>)")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("address")
+                .help("the mutliaddress of the peer, optionally ending with /p2p/<peer-id>")
+                .short("a")
+                .long("address")
+                .value_name("addr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .help("the peer id, its address is looked up in the kademlia DHT if unknown")
+                .short("p")
+                .long("peer")
+                .value_name("peer-id")
+                .takes_value(true),
+        )
+        .group(
+            ArgGroup::with_name("target")
+                .args(&["address", "peer"])
+                .required(true),
+        )
+}
+
+pub fn provide_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("provide")
+        .about("announce the local peer as provider for a key")
+        .usage("p2p provide --key <key>")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("key")
+                .help("the key, e.g. a capability like \"has-led\"")
+                .short("k")
+                .long("key")
+                .value_name("key")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+pub fn stop_providing_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("stop-providing")
+        .about("stop announcing the local peer as provider for a key")
+        .usage("p2p stop-providing --key <key>")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("key")
+                .help("the key that is provided")
+                .short("k")
+                .long("key")
+                .value_name("key")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+pub fn find_providers_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("find-providers")
+        .about("query the kademlia DHT for providers of a key")
+        .usage("p2p find-providers --key <key>")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("key")
+                .help("the key that is provided")
+                .short("k")
+                .long("key")
+                .value_name("key")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+// Subcommand with a peer id as only argument.
+fn peer_cmd<'a, 'b>(name: &'a str, about: &'a str, usage: &'a str) -> App<'a, 'b> {
+    App::new(name)
+        .about(about)
+        .usage(usage)
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("peer")
+                .help("the peer id")
+                .short("p")
+                .long("peer")
+                .value_name("peer-id")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+pub fn ban_cmd<'a, 'b>() -> App<'a, 'b> {
+    peer_cmd(
+        "ban",
+        "disconnect a peer and reject any further connections and messages from it",
+        "p2p ban --peer <peer-id>",
+    )
+}
+
+pub fn unban_cmd<'a, 'b>() -> App<'a, 'b> {
+    peer_cmd(
+        "unban",
+        "remove a peer from the ban list",
+        "p2p unban --peer <peer-id>",
+    )
+}
+
+pub fn disconnect_cmd<'a, 'b>() -> App<'a, 'b> {
+    peer_cmd(
+        "disconnect",
+        "close all connections to a peer",
+        "p2p disconnect --peer <peer-id>",
+    )
+}
+
+// Subcommand with a multiaddress as only argument.
+fn addr_cmd<'a, 'b>(name: &'a str, about: &'a str, usage: &'a str) -> App<'a, 'b> {
+    App::new(name)
+        .about(about)
+        .usage(usage)
         .settings(&[
             AppSettings::DisableHelpSubcommand,
             AppSettings::DisableHelpFlags,
@@ -155,6 +512,190 @@ pub fn connect_cmd<'a, 'b>() -> App<'a, 'b> {
         )
 }
 
+pub fn pin_cmd<'a, 'b>() -> App<'a, 'b> {
+    addr_cmd(
+        "pin",
+        "keep a peer connected by redialing it whenever the connection closes",
+        "p2p pin -a <multi-address>",
+    )
+}
+
+pub fn unpin_cmd<'a, 'b>() -> App<'a, 'b> {
+    addr_cmd(
+        "unpin",
+        "stop redialing a pinned peer",
+        "p2p unpin -a <multi-address>",
+    )
+}
+
+// Subcommand for a rendezvous point, with a namespace and an optional address of the point.
+fn rendezvous_cmd<'a, 'b>(name: &'a str, about: &'a str, usage: &'a str) -> App<'a, 'b> {
+    App::new(name)
+        .about(about)
+        .usage(usage)
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("namespace")
+                .help("the namespace, e.g. the name of an application")
+                .short("n")
+                .long("namespace")
+                .value_name("namespace")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("address")
+                .help("the multiaddress of the rendezvous point, defaults to the configured one")
+                .short("a")
+                .long("address")
+                .value_name("addr")
+                .takes_value(true),
+        )
+}
+
+pub fn register_cmd<'a, 'b>() -> App<'a, 'b> {
+    rendezvous_cmd(
+        "register",
+        "register the local peer under a namespace at a rendezvous point",
+        "p2p register --namespace <namespace> [-a <multi-address>] [--ttl <seconds>]",
+    )
+    .arg(
+        Arg::with_name("ttl")
+            .help("time in seconds after which the registration expires, defaults to 2 hours")
+            .long("ttl")
+            .value_name("seconds")
+            .takes_value(true),
+    )
+}
+
+pub fn unregister_cmd<'a, 'b>() -> App<'a, 'b> {
+    rendezvous_cmd(
+        "unregister",
+        "remove the registration of the local peer from a rendezvous point",
+        "p2p unregister --namespace <namespace> [-a <multi-address>]",
+    )
+}
+
+pub fn discover_cmd<'a, 'b>() -> App<'a, 'b> {
+    rendezvous_cmd(
+        "discover",
+        "discover the peers that registered under a namespace at a rendezvous point",
+        "p2p discover --namespace <namespace> [-a <multi-address>]",
+    )
+}
+
+pub fn share_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("share")
+        .about("share a file, and announce it in the kademlia DHT by its root hash")
+        .usage("p2p share --file <path>")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("file")
+                .help("path of the file, it must not change while it is shared")
+                .short("f")
+                .long("file")
+                .value_name("path")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+pub fn fetch_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("fetch")
+        .about("download a shared file by its root hash, or resume an interrupted download")
+        .usage("p2p fetch --hash <root-hash> --out <path>")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("hash")
+                .help("the root hash that was returned when sharing the file")
+                .long("hash")
+                .value_name("root-hash")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("out")
+                .help("path to which the file is written, chunks are kept in <path>.part meanwhile")
+                .short("o")
+                .long("out")
+                .value_name("path")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+pub fn release_cmd<'a, 'b>() -> App<'a, 'b> {
+    App::new("release")
+        .about("sign and announce a new version to the nodes that trust the local signing key")
+        .usage("p2p release --file <path> --version <version> [--target <target>]")
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::DisableHelpFlags,
+            AppSettings::DisableVersion,
+        ])
+        .arg(
+            Arg::with_name("file")
+                .help("path of the payload, it must not change while it is shared")
+                .short("f")
+                .long("file")
+                .value_name("path")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("version")
+                .help("the version of the release, e.g. 1.2.0")
+                .short("v")
+                .long("version")
+                .value_name("version")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("target")
+                .help("the target of the payload, defaults to the target of the local node")
+                .long("target")
+                .value_name("target")
+                .takes_value(true),
+        )
+}
+
+// Build App to parse the arguments that the program is started with.
+pub fn startup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("p2p-network")
+        .version("0.1.0")
+        .author("Elena Frank")
+        .about("Basic Peer-to-Peer network using the libp2p concepts and protocols")
+        .arg(
+            Arg::with_name("config")
+                .help("path to a JSON file with the node configuration")
+                .short("c")
+                .long("config")
+                .value_name("file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .help("time in seconds after which a command fails, overrides the configuration")
+                .short("t")
+                .long("timeout")
+                .value_name("seconds")
+                .takes_value(true),
+        )
+}
+
 // Build App for Command Line Interface to parse user input.
 pub fn build_app<'a, 'b>() -> App<'a, 'b> {
     App::new("p2p")
@@ -163,10 +704,31 @@ pub fn build_app<'a, 'b>() -> App<'a, 'b> {
         .about("CLI for the p2p-network interaction")
         .subcommand(subscribe_cmd())
         .subcommand(unsubscribe_cmd())
+        .subcommand(catch_up_cmd())
         .subcommand(publish_cmd())
         .subcommand(get_record_cmd())
         .subcommand(put_record_cmd())
         .subcommand(connect_cmd())
+        .subcommand(provide_cmd())
+        .subcommand(stop_providing_cmd())
+        .subcommand(find_providers_cmd())
+        .subcommand(ban_cmd())
+        .subcommand(unban_cmd())
+        .subcommand(disconnect_cmd())
+        .subcommand(pin_cmd())
+        .subcommand(unpin_cmd())
+        .subcommand(register_cmd())
+        .subcommand(unregister_cmd())
+        .subcommand(discover_cmd())
+        .subcommand(share_cmd())
+        .subcommand(fetch_cmd())
+        .subcommand(release_cmd())
+        .subcommand(App::new("updates").about("show the announced updates and their state"))
+        .subcommand(
+            App::new("peers")
+                .about("show the connected, pinned and banned peers, and the listening addresses"),
+        )
+        .subcommand(App::new("stats").about("show statistics of the swarm task"))
         .subcommand(App::new("shutdown").about("shutdown the app"))
         .settings(&[
             AppSettings::DisableHelpSubcommand,
@@ -0,0 +1,441 @@
+use libp2p::Multiaddr;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, time::Duration};
+
+// Configuration of the node that is loaded from a JSON file on startup.
+// All fields are optional, fields that are not set fall back to their default value.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Capacities and overflow policy of the channels between swarm task and user task.
+    pub channels: ChannelConfig,
+    // Expiry and republishing of kademlia records.
+    pub dht: DhtConfig,
+    // Options of the transport layer.
+    pub transport: TransportConfig,
+    // Discovery of peers.
+    pub discovery: DiscoveryConfig,
+    // Limits on the number of connections.
+    pub limits: LimitsConfig,
+    // Management of known peers.
+    pub peers: PeersConfig,
+    // Execution of commands.
+    pub commands: CommandsConfig,
+    // Distribution of signed firmware and application updates.
+    pub updates: UpdatesConfig,
+    // Rate limits on published and received gossip messages.
+    pub rate_limits: RateLimitsConfig,
+    // End-to-end encryption of the payload of gossip messages.
+    pub encryption: EncryptionConfig,
+    // Messages that are retained for peers that subscribe later.
+    pub retention: RetentionConfig,
+    // LEDs of the device, that are set by received LED commands.
+    pub led: LedConfig,
+}
+
+impl Config {
+    // Load the configuration from a JSON file.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let bytes =
+            fs::read(path).map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("Invalid config file {}: {}", path, e))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    // Number of commands that can be buffered for the swarm task.
+    pub command_capacity: usize,
+    // Number of command results that can be buffered for the user task.
+    pub result_capacity: usize,
+    // Number of received gossip messages that can be buffered for the user task.
+    pub message_capacity: usize,
+    // What to do with received gossip messages if the user task is too slow to process them.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            command_capacity: 8,
+            result_capacity: 8,
+            message_capacity: 64,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    // Drop the oldest buffered message to make room for the new one.
+    DropOldest,
+    // Stop polling the swarm until the receiver took the messages that did not fit into the channel.
+    // This stalls gossipsub so that remote peers are slowed down instead of messages being lost.
+    // Commands are still handled meanwhile.
+    Backpressure,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TransportConfig {
+    // Path to a swarm key file with the pre-shared key of a private network.
+    // If set, the node only connects to peers that use the same key.
+    pub swarm_key_file: Option<String>,
+    // Multiaddress on which the node listens for WebSocket connections, e.g. "/ip4/0.0.0.0/tcp/8080/ws".
+    // Requires the `websocket` feature.
+    pub ws_listen_addr: Option<String>,
+    // Use an in-memory transport instead of TCP, so that the node can only connect to other nodes
+    // within the same process. Intended for tests.
+    pub memory: bool,
+    // Addresses on which the node listens. If not set, it listens on a TCP port, respectively
+    // memory address, that is assigned by the OS.
+    // An empty list disables direct listening, e.g. for nodes behind a NAT that are only reachable
+    // via relays.
+    pub listen_addrs: Option<Vec<Multiaddr>>,
+    // Limits of the Yamux stream multiplexing.
+    pub yamux: YamuxLimits,
+    // Circuit relay, to connect to nodes that are not directly reachable.
+    pub relay: RelayConfig,
+    // Detection of the reachability via dial-backs of connected peers.
+    pub autonat: AutoNatConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    // Enable the relay protocol: relay connections for other nodes, and dial or listen on
+    // /p2p-circuit addresses.
+    pub enabled: bool,
+    // Relays via which the node listens, each address has to end with /p2p/<relay-peer-id>.
+    // Requires that the relay protocol is enabled.
+    pub relays: Vec<Multiaddr>,
+    // Time in seconds after which an idle relayed connection is closed.
+    pub connection_idle_timeout_secs: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            enabled: false,
+            relays: Vec::new(),
+            connection_idle_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AutoNatConfig {
+    // Time in seconds between two probes of the reachability of the local node, 0 disables
+    // probing.
+    pub probe_interval_secs: u64,
+    // Dial back remote peers that probe their reachability.
+    pub server: bool,
+    // Only dial back peers that connected from a global IP address, so that a peer in the same
+    // LAN does not report a node behind a NAT as public.
+    pub only_global_ips: bool,
+}
+
+impl AutoNatConfig {
+    pub fn probe_interval(&self) -> Option<Duration> {
+        non_zero_secs(self.probe_interval_secs)
+    }
+}
+
+impl Default for AutoNatConfig {
+    fn default() -> Self {
+        AutoNatConfig {
+            probe_interval_secs: 5 * 60,
+            server: true,
+            only_global_ips: true,
+        }
+    }
+}
+
+// Per connection limits of the stream multiplexing.
+// Each substream buffers up to `max_buffer_size` bytes of received data.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct YamuxLimits {
+    // Receive window per substream in bytes, at least 256 KiB.
+    pub receive_window_size: u32,
+    // Maximum size of the receive buffer per substream in bytes.
+    pub max_buffer_size: usize,
+    // Maximum number of concurrent substreams.
+    pub max_num_streams: usize,
+}
+
+// Same defaults as in the yamux config.
+impl Default for YamuxLimits {
+    fn default() -> Self {
+        YamuxLimits {
+            receive_window_size: 256 * 1024,
+            max_buffer_size: 1024 * 1024,
+            max_num_streams: 8192,
+        }
+    }
+}
+
+// Limits on the number of connections, so that a busy network does not exhaust the memory of the
+// node. A limit set to null disables it, by default no limits apply.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // Maximum number of established connections in total.
+    pub max_established: Option<u32>,
+    // Maximum number of established incoming connections.
+    pub max_established_incoming: Option<u32>,
+    // Maximum number of established outgoing connections.
+    pub max_established_outgoing: Option<u32>,
+    // Maximum number of established connections to a single peer.
+    pub max_established_per_peer: Option<u32>,
+    // Maximum number of incoming connections that are currently being established.
+    pub max_pending_incoming: Option<u32>,
+    // Maximum number of outgoing connections that are currently being established.
+    pub max_pending_outgoing: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PeersConfig {
+    // File in which banned peers are persisted, so that they stay banned after a restart.
+    // If not set, bans only last until the node is stopped.
+    pub ban_list_file: Option<String>,
+    // Addresses of peers that are dialed on startup and redialed whenever their connection closes.
+    pub pinned: Vec<Multiaddr>,
+    // Delay in seconds before a pinned peer is redialed. It doubles with each failed dial.
+    pub reconnect_backoff_secs: u64,
+    // Maximum delay in seconds between two dials of a pinned peer.
+    pub max_reconnect_backoff_secs: u64,
+}
+
+impl Default for PeersConfig {
+    fn default() -> Self {
+        PeersConfig {
+            ban_list_file: None,
+            pinned: Vec::new(),
+            reconnect_backoff_secs: 1,
+            max_reconnect_backoff_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    // Time in seconds after which a command fails if it did not complete, e.g. a dial or a
+    // kademlia query. Kademlia queries use the same timeout.
+    pub timeout_secs: u64,
+    // Time in milliseconds during which the acknowledgments of a published device command are
+    // collected.
+    pub ack_window_ms: u64,
+}
+
+impl CommandsConfig {
+    pub fn timeout(&self) -> Result<Duration, String> {
+        non_zero_secs(self.timeout_secs).ok_or_else(|| "The command timeout can not be 0".into())
+    }
+
+    pub fn ack_window(&self) -> Duration {
+        Duration::from_millis(self.ack_window_ms)
+    }
+}
+
+// Same default as the kademlia query timeout.
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        CommandsConfig {
+            timeout_secs: 60,
+            ack_window_ms: 2000,
+        }
+    }
+}
+
+// Rate limits of gossip messages. A limit that is not set disables it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitsConfig {
+    // Limit on the messages that the local node publishes to a topic.
+    pub publish: Option<RateLimit>,
+    // Limit on the messages that are received on a topic from the same source peer.
+    pub incoming: Option<RateLimit>,
+    // Limits of specific topics, that replace the limits above for that topic.
+    pub topics: HashMap<String, TopicRateLimits>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TopicRateLimits {
+    pub publish: Option<RateLimit>,
+    pub incoming: Option<RateLimit>,
+}
+
+// Token bucket that allows on average `per_sec` messages per second, and bursts of up to `burst`
+// messages.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    // Hex encoded 32 byte keys of the topics whose messages are encrypted. Messages of other
+    // topics are sent in plain text.
+    pub topic_keys: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LedConfig {
+    // Number of LEDs or channels, that commands address by their index.
+    pub channels: u8,
+    // Print each output that the simulated driver sets.
+    pub verbose: bool,
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        LedConfig {
+            channels: 1,
+            verbose: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    // Topics whose received and published messages are retained, so that peers can catch up on
+    // them after they subscribed.
+    pub topics: HashMap<String, RetentionPolicy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetentionPolicy {
+    // Retain the last N messages of the topic.
+    Last(usize),
+    // Retain the latest message of each message type, e.g. the last `SetLed`.
+    LatestPerType,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UpdatesConfig {
+    // Peer ids of the signing keys of the maintainers whose releases are installed.
+    // The node only receives updates if at least one maintainer is trusted.
+    pub trusted_publishers: Vec<String>,
+    // File with the ed25519 key with which the local node signs releases. It is generated if the
+    // file does not exist.
+    pub signing_key_file: Option<String>,
+    // Target of the local node, e.g. "riscv64gc-unknown-linux-gnu". Only releases for this
+    // target are installed.
+    pub target: String,
+    // Version that is installed, only newer releases are installed.
+    pub version: String,
+    // Directory to which the payload of an update is downloaded.
+    pub staging_dir: String,
+    // Program and arguments that are invoked to apply a staged update, with the path of the
+    // payload and the version appended. Without hook, updates are only staged.
+    pub apply_hook: Vec<String>,
+    // Time in seconds after which fetching the payload of an update fails, other commands are
+    // only handled once it completed.
+    pub fetch_timeout_secs: u64,
+    // Time in seconds after which the apply hook is killed and the update fails.
+    pub apply_timeout_secs: u64,
+}
+
+impl UpdatesConfig {
+    pub fn fetch_timeout(&self) -> Result<Duration, String> {
+        non_zero_secs(self.fetch_timeout_secs)
+            .ok_or_else(|| "The update fetch timeout can not be 0".into())
+    }
+
+    pub fn apply_timeout(&self) -> Result<Duration, String> {
+        non_zero_secs(self.apply_timeout_secs)
+            .ok_or_else(|| "The apply hook timeout can not be 0".into())
+    }
+}
+
+impl Default for UpdatesConfig {
+    fn default() -> Self {
+        UpdatesConfig {
+            trusted_publishers: Vec::new(),
+            signing_key_file: None,
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            version: env!("CARGO_PKG_VERSION").into(),
+            staging_dir: "updates".into(),
+            apply_hook: Vec::new(),
+            fetch_timeout_secs: 600,
+            apply_timeout_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    // Discover peers in the local network via mDNS.
+    pub mdns: bool,
+    // Rendezvous point at which peers are registered and discovered, if no other one is given.
+    pub rendezvous_point: Option<Multiaddr>,
+    // Act as rendezvous point, i.g. accept registrations of other peers.
+    pub rendezvous_server: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            mdns: true,
+            rendezvous_point: None,
+            rendezvous_server: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DhtConfig {
+    // Time in seconds after which a published record expires, if no TTL is set for the record.
+    // 0 for records that never expire.
+    pub record_ttl_secs: u64,
+    // Interval in seconds in which stored records are replicated to the closest peers.
+    // 0 to disable replication.
+    pub replication_interval_secs: u64,
+    // Interval in seconds in which the local peer republishes the records it published.
+    // 0 to disable republishing.
+    pub publication_interval_secs: u64,
+}
+
+impl DhtConfig {
+    pub fn record_ttl(&self) -> Option<Duration> {
+        non_zero_secs(self.record_ttl_secs)
+    }
+
+    pub fn replication_interval(&self) -> Option<Duration> {
+        non_zero_secs(self.replication_interval_secs)
+    }
+
+    pub fn publication_interval(&self) -> Option<Duration> {
+        non_zero_secs(self.publication_interval_secs)
+    }
+}
+
+// Same defaults as in the libp2p kademlia config.
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            record_ttl_secs: 36 * 60 * 60,
+            replication_interval_secs: 60 * 60,
+            publication_interval_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+fn non_zero_secs(secs: u64) -> Option<Duration> {
+    (secs != 0).then(|| Duration::from_secs(secs))
+}
@@ -0,0 +1,422 @@
+// Control of the LEDs, or other actuators with one or more channels, of a device.
+// The controller applies received `LedCommand`s and runs their patterns over time, the driver
+// sets the outputs of the channels on the hardware.
+
+use crate::types::{LedCommand, LedState, Rgb};
+use std::{
+    cmp,
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+// Maximum duration of a blink phase or a pattern step, so that the end of a step can always be
+// computed.
+pub const MAX_STEP_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Number of outputs that the simulated driver records.
+const MAX_HISTORY: usize = 1024;
+
+// Sets the outputs of the channels on the hardware.
+pub trait LedDriver {
+    // Number of channels, that are addressed by their index.
+    fn channels(&self) -> u8;
+
+    // Set the output of a channel, black turns it off. Single color LEDs use the brightness of
+    // the color.
+    // Fails if the hardware could not be set.
+    fn set(&mut self, channel: u8, output: Rgb) -> Result<(), String>;
+}
+
+// Driver for devices without LEDs, that records the outputs so that commands can be verified.
+pub struct SimulatedDriver {
+    outputs: Vec<Rgb>,
+    // Most recent outputs that were set, oldest first.
+    history: VecDeque<(u8, Rgb)>,
+    // Print each output that is set.
+    verbose: bool,
+}
+
+impl SimulatedDriver {
+    pub fn new(channels: u8, verbose: bool) -> Self {
+        SimulatedDriver {
+            outputs: vec![Rgb::BLACK; channels as usize],
+            history: VecDeque::new(),
+            verbose,
+        }
+    }
+
+    // Current output of each channel.
+    pub fn outputs(&self) -> &[Rgb] {
+        &self.outputs
+    }
+
+    pub fn history(&self) -> Vec<(u8, Rgb)> {
+        self.history.iter().copied().collect()
+    }
+}
+
+impl LedDriver for SimulatedDriver {
+    fn channels(&self) -> u8 {
+        self.outputs.len() as u8
+    }
+
+    fn set(&mut self, channel: u8, output: Rgb) -> Result<(), String> {
+        if self.verbose {
+            println!("> LED {} set to {}\n", channel, output);
+        }
+        self.outputs[channel as usize] = output;
+        self.history.push_back((channel, output));
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+        Ok(())
+    }
+}
+
+// Reason why a LED command was not applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedError {
+    // The command is invalid, or addresses a channel that does not exist.
+    Invalid(String),
+    // The driver failed to set an output.
+    Driver(String),
+}
+
+impl fmt::Display for LedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedError::Invalid(reason) => write!(f, "{}", reason),
+            LedError::Driver(error) => write!(f, "Failed to set LED: {}", error),
+        }
+    }
+}
+
+// Output of a step of a pattern.
+#[derive(Debug, Clone, Copy)]
+enum StepOutput {
+    // The color of the channel.
+    On,
+    Off,
+    Color(Rgb),
+}
+
+// Pattern that a channel is running.
+struct Animation {
+    steps: Vec<(StepOutput, Duration)>,
+    // Number of times the steps are run, forever if None.
+    repeat: Option<u32>,
+    index: usize,
+    iteration: u32,
+    // End of the current step.
+    step_end: Instant,
+}
+
+impl Animation {
+    fn new(steps: Vec<(StepOutput, Duration)>, repeat: Option<u32>, now: Instant) -> Self {
+        let step_end = now + steps[0].1;
+        Animation {
+            steps,
+            repeat,
+            index: 0,
+            iteration: 0,
+            step_end,
+        }
+    }
+
+    // Advance to the step at that time, returns false once the pattern finished.
+    fn advance(&mut self, now: Instant) -> bool {
+        // Skip the cycles that were missed, e.g. because the device was suspended.
+        let cycle: Duration = self.steps.iter().map(|(_, duration)| *duration).sum();
+        if self.repeat.is_none() && now > self.step_end + cycle {
+            self.index = 0;
+            self.step_end = now + self.steps[0].1;
+        }
+        while now >= self.step_end {
+            self.index += 1;
+            if self.index == self.steps.len() {
+                self.index = 0;
+                self.iteration += 1;
+                if Some(self.iteration) == self.repeat {
+                    return false;
+                }
+            }
+            self.step_end += self.steps[self.index].1;
+        }
+        true
+    }
+}
+
+struct Channel {
+    color: Rgb,
+    // Brightness in percent, that the PWM duty cycle is set to.
+    brightness: u8,
+    is_on: bool,
+    animation: Option<Animation>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel {
+            color: Rgb::WHITE,
+            brightness: 100,
+            is_on: false,
+            animation: None,
+        }
+    }
+}
+
+impl Channel {
+    fn output(&self) -> Rgb {
+        let step = self
+            .animation
+            .as_ref()
+            .map(|animation| animation.steps[animation.index].0);
+        let color = match step {
+            Some(StepOutput::On) => self.color,
+            Some(StepOutput::Off) => Rgb::BLACK,
+            Some(StepOutput::Color(color)) => color,
+            None if self.is_on => self.color,
+            None => Rgb::BLACK,
+        };
+        color.dimmed(self.brightness)
+    }
+}
+
+// Applies LED commands to the channels of a driver.
+// Time is passed explicitly, so that patterns can be verified without waiting.
+pub struct LedController<D> {
+    driver: D,
+    channels: Vec<Channel>,
+}
+
+impl<D: LedDriver> LedController<D> {
+    // Create a controller, that turns all channels off.
+    pub fn new(mut driver: D) -> Result<Self, String> {
+        let channels: Vec<Channel> = (0..driver.channels()).map(|_| Channel::default()).collect();
+        for (index, channel) in channels.iter().enumerate() {
+            driver.set(index as u8, channel.output())?;
+        }
+        Ok(LedController { driver, channels })
+    }
+
+    pub fn driver(&self) -> &D {
+        &self.driver
+    }
+
+    // Apply a command to the channel, or to all channels if it has none.
+    // Fails if the channel does not exist, the command is invalid or the driver failed.
+    pub fn apply(&mut self, command: &LedCommand, now: Instant) -> Result<(), LedError> {
+        validate(&command.state).map_err(LedError::Invalid)?;
+        let indices = match command.channel {
+            Some(index) if (index as usize) < self.channels.len() => index..index + 1,
+            Some(index) => {
+                return Err(LedError::Invalid(format!("LED {} does not exist", index)));
+            }
+            None => 0..self.channels.len() as u8,
+        };
+        for index in indices {
+            let channel = &mut self.channels[index as usize];
+            match &command.state {
+                LedState::On => {
+                    channel.is_on = true;
+                    channel.animation = None;
+                }
+                LedState::Off => {
+                    channel.is_on = false;
+                    channel.animation = None;
+                }
+                // Brightness and color change the appearance of a running pattern, otherwise
+                // they turn the LED on.
+                LedState::Brightness(brightness) => {
+                    channel.brightness = *brightness;
+                    channel.is_on |= channel.animation.is_none();
+                }
+                LedState::Color(color) => {
+                    channel.color = *color;
+                    channel.is_on |= channel.animation.is_none();
+                }
+                LedState::Blink { on, off } => {
+                    let steps = vec![(StepOutput::On, *on), (StepOutput::Off, *off)];
+                    channel.animation = Some(Animation::new(steps, None, now));
+                }
+                LedState::OnFor(duration) => {
+                    let steps = vec![(StepOutput::On, *duration)];
+                    channel.is_on = false;
+                    channel.animation = Some(Animation::new(steps, Some(1), now));
+                }
+                LedState::Pattern { steps, repeat } => {
+                    let steps = steps
+                        .iter()
+                        .map(|step| (StepOutput::Color(step.color), step.duration))
+                        .collect();
+                    let repeat = Some(*repeat).filter(|repeat| *repeat > 0);
+                    channel.is_on = false;
+                    channel.animation = Some(Animation::new(steps, repeat, now));
+                }
+            }
+            self.driver
+                .set(index, channel.output())
+                .map_err(LedError::Driver)?;
+        }
+        Ok(())
+    }
+
+    // Advance the patterns of the channels to that time.
+    // Fails if the driver failed to set an output.
+    pub fn update(&mut self, now: Instant) -> Result<(), String> {
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            let output = channel.output();
+            if let Some(animation) = &mut channel.animation {
+                if !animation.advance(now) {
+                    channel.animation = None;
+                }
+            }
+            if channel.output() != output {
+                self.driver.set(index as u8, channel.output())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Time at which the next step of a pattern starts, None if no pattern is running.
+    pub fn next_update(&self) -> Option<Instant> {
+        self.channels
+            .iter()
+            .filter_map(|channel| channel.animation.as_ref())
+            .map(|animation| animation.step_end)
+            .fold(None, |next, step_end| match next {
+                Some(next) => Some(cmp::min(next, step_end)),
+                None => Some(step_end),
+            })
+    }
+}
+
+// Check the values of a command, so that e.g. a step without duration can not stall the
+// controller.
+fn validate(state: &LedState) -> Result<(), String> {
+    let durations = match state {
+        LedState::Blink { on, off } => vec![*on, *off],
+        LedState::OnFor(duration) => vec![*duration],
+        LedState::Pattern { steps, .. } => steps.iter().map(|step| step.duration).collect(),
+        _ => Vec::new(),
+    };
+    if durations
+        .iter()
+        .any(|duration| *duration > MAX_STEP_DURATION)
+    {
+        return Err("Durations can be at most 24h".into());
+    }
+    match state {
+        LedState::Brightness(brightness) if *brightness > 100 => {
+            Err("The brightness has to be a percentage".into())
+        }
+        LedState::Blink { on, off } if on.as_millis() == 0 || off.as_millis() == 0 => {
+            Err("Blink durations have to be at least 1ms".into())
+        }
+        LedState::OnFor(duration) if duration.as_millis() == 0 => {
+            Err("The duration has to be at least 1ms".into())
+        }
+        LedState::Pattern { steps, .. } if steps.is_empty() => {
+            Err("A pattern needs at least one step".into())
+        }
+        LedState::Pattern { steps, .. } if steps.iter().any(|s| s.duration.as_millis() == 0) => {
+            Err("Pattern steps have to last at least 1ms".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PatternStep;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn command(channel: Option<u8>, state: LedState) -> LedCommand {
+        LedCommand { channel, state }
+    }
+
+    #[test]
+    fn blink_and_timed_on() {
+        let mut leds = LedController::new(SimulatedDriver::new(2, false)).unwrap();
+        let start = Instant::now();
+        let blink = LedState::Blink {
+            on: ms(100),
+            off: ms(300),
+        };
+        leds.apply(&command(Some(0), blink), start).unwrap();
+        leds.apply(&command(Some(1), LedState::OnFor(ms(250))), start)
+            .unwrap();
+        assert_eq!(leds.driver().outputs(), &[Rgb::WHITE, Rgb::WHITE]);
+        assert_eq!(leds.next_update(), Some(start + ms(100)));
+
+        leds.update(start + ms(100)).unwrap();
+        assert_eq!(leds.driver().outputs(), &[Rgb::BLACK, Rgb::WHITE]);
+        leds.update(start + ms(250)).unwrap();
+        assert_eq!(leds.driver().outputs(), &[Rgb::BLACK, Rgb::BLACK]);
+        leds.update(start + ms(400)).unwrap();
+        assert_eq!(leds.driver().outputs(), &[Rgb::WHITE, Rgb::BLACK]);
+        assert_eq!(leds.next_update(), Some(start + ms(500)));
+
+        leds.apply(&command(None, LedState::Off), start + ms(450))
+            .unwrap();
+        assert_eq!(leds.next_update(), None);
+        assert!(leds.apply(&command(Some(2), LedState::On), start).is_err());
+    }
+
+    #[test]
+    fn pattern_with_color_and_brightness() {
+        let mut leds = LedController::new(SimulatedDriver::new(1, false)).unwrap();
+        let start = Instant::now();
+        leds.apply(&command(None, LedState::Brightness(50)), start)
+            .unwrap();
+        assert_eq!(leds.driver().outputs(), &[Rgb::new(127, 127, 127)]);
+
+        let red = Rgb::new(255, 0, 0);
+        let pattern = LedState::Pattern {
+            steps: vec![
+                PatternStep {
+                    color: red,
+                    duration: ms(10),
+                },
+                PatternStep {
+                    color: Rgb::BLACK,
+                    duration: ms(20),
+                },
+            ],
+            repeat: 2,
+        };
+        leds.apply(&command(None, pattern), start).unwrap();
+        leds.update(start + ms(10)).unwrap();
+        leds.update(start + ms(35)).unwrap();
+        leds.update(start + ms(60)).unwrap();
+        assert_eq!(leds.next_update(), None);
+        let history: Vec<Rgb> = leds.driver().history()[2..]
+            .iter()
+            .map(|(_, rgb)| *rgb)
+            .collect();
+        let dimmed_red = Rgb::new(127, 0, 0);
+        assert_eq!(
+            history,
+            vec![dimmed_red, Rgb::BLACK, dimmed_red, Rgb::BLACK]
+        );
+
+        let invalid = LedState::Pattern {
+            steps: Vec::new(),
+            repeat: 1,
+        };
+        assert!(leds.apply(&command(None, invalid), start).is_err());
+        let too_long = LedState::Blink {
+            on: ms(1),
+            off: Duration::from_secs(u64::MAX),
+        };
+        assert!(leds.apply(&command(None, too_long), start).is_err());
+        assert!(leds
+            .apply(&command(None, LedState::Brightness(101)), start)
+            .is_err());
+    }
+}
@@ -0,0 +1,12 @@
+// Network part of the p2p-network, that is shared by the binary and the integration tests.
+// The swarm task is controlled by sending `Command`s through a channel, and returns the outcome
+// of each command as `CommandResult`.
+
+pub mod channel;
+pub mod config;
+pub mod led;
+pub mod registry;
+pub mod schedule;
+pub mod sensors;
+pub mod swarm;
+pub mod types;
@@ -33,35 +33,73 @@
 //!
 
 mod cli;
-mod swarm;
-mod types;
 mod user;
 use async_std::task;
-use futures::{channel::mpsc as channel, join};
-use swarm::SwarmTask;
-use types::*;
+use futures::{channel::mpsc, join};
+use p2p_network::{
+    channel,
+    config::Config,
+    led::{LedController, SimulatedDriver},
+    registry::MessageRegistry,
+    sensors::Temperature,
+    swarm::SwarmTask,
+    types::*,
+};
 use user::UserTask;
 
 fn main() {
+    // Load the node configuration from the file passed via --config, use defaults otherwise.
+    let matches = cli::startup_app().get_matches();
+    let mut config = match matches.value_of("config").map(Config::from_file) {
+        Some(Ok(config)) => config,
+        Some(Err(err)) => return println!("{}", err),
+        None => Config::default(),
+    };
+    if let Some(timeout) = matches.value_of("timeout") {
+        match timeout.parse() {
+            Ok(secs) => config.commands.timeout_secs = secs,
+            Err(_) => return println!("The timeout has to be a number of seconds"),
+        }
+    }
+    let channels = config.channels.clone();
+
     // Channel for sending commands from user task to swarm task
-    let (cmd_tx, cmd_rx) = channel::unbounded::<Command>();
+    let (cmd_tx, cmd_rx) = mpsc::channel::<Command>(channels.command_capacity);
 
     // Channel for returning the outcome of a command
-    let (cmd_res_tx, cmd_res_rx) = channel::unbounded::<CommandResult>();
+    let (cmd_res_tx, cmd_res_rx) = mpsc::channel::<CommandResult>(channels.result_capacity);
 
     // Channel for forwarding incoming messages from remote peers
-    let (msg_tx, msg_rx) = channel::unbounded::<(Topic, GossipMessage)>();
+    let (msg_tx, msg_rx) =
+        channel::bounded::<(Topic, GossipMessage)>(channels.message_capacity, channels.overflow);
+
+    // Application specific message types are registered here, together with the handler that is
+    // invoked for each received message of the type.
+    let mut registry = MessageRegistry::new();
+    // Received temperatures are only printed by the user task.
+    if let Err(err) = registry.register(|_: &str, _: &Temperature| {}) {
+        return println!("Failed to register message type: {}", err);
+    }
+
+    // The LEDs are simulated, so that LED commands can be tried on any device.
+    let driver = SimulatedDriver::new(config.led.channels, config.led.verbose);
+    let leds = match LedController::new(driver) {
+        Ok(leds) => leds,
+        Err(err) => return println!("Failed to initialize the LEDs: {}", err),
+    };
 
     // Start a future for polling user input and deciding how to handle messages.
     let input_handle = task::spawn(async {
-        let user_task = UserTask::new(cmd_tx, cmd_res_rx, msg_rx);
+        let user_task = UserTask::new(cmd_tx, cmd_res_rx, msg_rx, registry, leds);
         user_task.run().await
     });
 
     // Start a future for polling the swarm and managing swarm interaction.
-    let swarm_handle = task::spawn(async {
-        let swarm_task = SwarmTask::new(cmd_rx, cmd_res_tx, msg_tx).await;
-        swarm_task.run().await
+    let swarm_handle = task::spawn(async move {
+        match SwarmTask::new(cmd_rx, cmd_res_tx, msg_tx, &config).await {
+            Ok(swarm_task) => swarm_task.run().await,
+            Err(err) => println!("Failed to create swarm: {}", err),
+        }
     });
 
     // Poll both futures simultaneously untill both returned.
@@ -0,0 +1,143 @@
+// Registry of the application specific message types, that are published as
+// `GossipMessage::Custom` under their type tag.
+// New device features can add a payload type and register it with a handler, instead of adding a
+// variant to `GossipMessage` and extending the command line interface and the user task for it.
+
+use crate::types::GossipMessage;
+use std::{collections::BTreeMap, fmt};
+
+// Payload of an application specific message type.
+pub trait Payload: Sized + fmt::Debug {
+    // Tag under which the type is registered, and that is sent along with each message.
+    const TAG: &'static str;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(data: &[u8]) -> Result<Self, String>;
+
+    // Parse the payload from the value that is passed via the command line.
+    fn parse(input: &str) -> Result<Self, String>;
+}
+
+impl GossipMessage {
+    // Create the message for a payload of a registered type.
+    pub fn custom<T: Payload>(payload: &T) -> Self {
+        GossipMessage::Custom {
+            tag: T::TAG.into(),
+            data: payload.encode(),
+        }
+    }
+}
+
+type Handler = Box<dyn FnMut(&str, &[u8]) -> Result<String, String> + Send>;
+
+struct Entry {
+    parse: fn(&str) -> Result<Vec<u8>, String>,
+    // Decode the data, invoke the handler and describe the payload.
+    handle: Handler,
+}
+
+// Registered message types by their tag.
+#[derive(Default)]
+pub struct MessageRegistry {
+    types: BTreeMap<String, Entry>,
+}
+
+impl MessageRegistry {
+    pub fn new() -> Self {
+        MessageRegistry::default()
+    }
+
+    // Register a message type with the handler that is invoked with the topic and the payload of
+    // each received message of that type.
+    pub fn register<T, F>(&mut self, mut handler: F) -> Result<(), String>
+    where
+        T: Payload + 'static,
+        F: FnMut(&str, &T) + Send + 'static,
+    {
+        if self.types.contains_key(T::TAG) {
+            return Err(format!("Message type {} is already registered", T::TAG));
+        }
+        let entry = Entry {
+            parse: |input| T::parse(input).map(|payload| payload.encode()),
+            handle: Box::new(move |topic, data| {
+                let payload = T::decode(data)?;
+                handler(topic, &payload);
+                Ok(format!("{:?}", payload))
+            }),
+        };
+        self.types.insert(T::TAG.into(), entry);
+        Ok(())
+    }
+
+    // Tags of the registered message types, in alphabetical order.
+    pub fn tags(&self) -> Vec<&str> {
+        self.types.keys().map(String::as_str).collect()
+    }
+
+    // Parse the command line input for a message of a registered type.
+    pub fn parse(&self, tag: &str, input: &str) -> Result<GossipMessage, String> {
+        let entry = self
+            .types
+            .get(tag)
+            .ok_or_else(|| format!("Unknown message type {}", tag))?;
+        let data = (entry.parse)(input)?;
+        Ok(GossipMessage::Custom {
+            tag: tag.into(),
+            data,
+        })
+    }
+
+    // Decode a received message of a registered type and pass it to the handler of the type.
+    // Returns the description of the payload.
+    pub fn handle(&mut self, topic: &str, tag: &str, data: &[u8]) -> Result<String, String> {
+        let entry = self
+            .types
+            .get_mut(tag)
+            .ok_or_else(|| format!("Unknown message type {}", tag))?;
+        (entry.handle)(topic, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::Temperature;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn registered_types_are_parsed_and_handled() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = MessageRegistry::new();
+        let handled = received.clone();
+        registry
+            .register(move |topic: &str, t: &Temperature| {
+                handled.lock().unwrap().push((topic.to_string(), t.clone()))
+            })
+            .unwrap();
+        assert!(registry.register(|_: &str, _: &Temperature| {}).is_err());
+        assert_eq!(registry.tags(), vec!["temperature"]);
+
+        let message = registry.parse("temperature", "-12").unwrap();
+        let data = match message {
+            GossipMessage::Custom { tag, data } => {
+                assert_eq!(tag, "temperature");
+                data
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        };
+        assert_eq!(
+            registry.handle("sensors", "temperature", &data),
+            Ok("Temperature(-12)".into())
+        );
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![("sensors".to_string(), Temperature(-12))]
+        );
+
+        assert!(registry.parse("temperature", "warm").is_err());
+        assert!(registry.parse("humidity", "50").is_err());
+        assert!(registry.handle("sensors", "temperature", &[1]).is_err());
+        assert!(registry.handle("sensors", "humidity", &data).is_err());
+    }
+}
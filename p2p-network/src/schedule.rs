@@ -0,0 +1,279 @@
+// Timer queue for received messages that are executed at a later time.
+// Peers only share the wall clock, hence times are milliseconds since the UNIX epoch. The clock
+// is passed to the scheduler, so that tests can mock it instead of waiting.
+
+use crate::types::Schedule;
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+// Bound the memory of the queue, remote peers decide what is queued.
+pub const MAX_QUEUED: usize = 1024;
+// Messages can be scheduled at most this far ahead.
+pub const MAX_LEAD_TIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Smaller numbers are no plausible timestamps, e.g. 2001-09-09. They are rather a time of day
+// without colon, like 1800.
+const MIN_TIMESTAMP_SECS: u64 = 1_000_000_000;
+
+// Source of the current time.
+pub trait Clock {
+    // Milliseconds since the UNIX epoch.
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+    }
+}
+
+// Clock that only advances when it is told so. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<Mutex<u64>>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration.as_millis() as u64;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        *self.now.lock().unwrap()
+    }
+}
+
+// What happens with a message that was passed to the scheduler.
+#[derive(Debug, PartialEq)]
+pub enum Scheduled<T> {
+    // The message has to be executed now.
+    Due(T),
+    // The message was queued until the execution time.
+    Queued(u64),
+    // The message expired and is ignored.
+    Expired,
+    // The message can't be queued and is ignored.
+    Rejected(String),
+}
+
+// Queue of the messages that wait for their execution time.
+pub struct Scheduler<T, C> {
+    clock: C,
+    // Messages with their schedule, by their execution time and the order in which they were
+    // queued.
+    queue: BTreeMap<(u64, u64), (Schedule, T)>,
+    queued: u64,
+}
+
+impl<T, C: Clock> Scheduler<T, C> {
+    pub fn new(clock: C) -> Self {
+        Scheduler {
+            clock,
+            queue: BTreeMap::new(),
+            queued: 0,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    // Return the message if it has to be executed now, otherwise queue it until its execution
+    // time. Messages that already expired are dropped, as are messages that are scheduled too far
+    // ahead or don't fit into the queue.
+    pub fn schedule(&mut self, schedule: &Schedule, message: T) -> Scheduled<T> {
+        let now = self.clock.now();
+        if schedule.is_expired(now) {
+            return Scheduled::Expired;
+        }
+        match schedule.execute_at {
+            Some(execute_at) if execute_at > now => {
+                if execute_at - now > MAX_LEAD_TIME.as_millis() as u64 {
+                    return Scheduled::Rejected(format!(
+                        "Messages can be scheduled at most {}s ahead",
+                        MAX_LEAD_TIME.as_secs()
+                    ));
+                }
+                if self.queue.len() >= MAX_QUEUED {
+                    return Scheduled::Rejected(format!(
+                        "At most {} messages can be scheduled",
+                        MAX_QUEUED
+                    ));
+                }
+                self.queue
+                    .insert((execute_at, self.queued), (*schedule, message));
+                self.queued += 1;
+                Scheduled::Queued(execute_at)
+            }
+            _ => Scheduled::Due(message),
+        }
+    }
+
+    // Remove the messages whose execution time was reached, in order of their execution time.
+    // Messages that expired meanwhile, e.g. because the device was suspended, are dropped.
+    pub fn due(&mut self) -> Vec<T> {
+        let now = self.clock.now();
+        let later = self.queue.split_off(&(now.saturating_add(1), 0));
+        mem::replace(&mut self.queue, later)
+            .into_iter()
+            .filter(|(_, (schedule, _))| !schedule.is_expired(now))
+            .map(|(_, (_, message))| message)
+            .collect()
+    }
+
+    // Time until the next queued message is due, None if the queue is empty.
+    pub fn next_due(&self) -> Option<Duration> {
+        let (execute_at, _) = self.queue.keys().next()?;
+        let remaining = execute_at.saturating_sub(self.clock.now());
+        Some(Duration::from_millis(remaining))
+    }
+}
+
+// Parse a time of day in UTC in the format HH:MM[:SS], or a UNIX timestamp in seconds.
+// A time of day refers to its next occurrence after now. Returns milliseconds since the epoch.
+// Numbers that are no plausible timestamps are rejected.
+pub fn parse_time(input: &str, now: u64) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "Invalid time {}, expected HH:MM[:SS] or a UNIX timestamp",
+            input
+        )
+    };
+    if let Ok(secs) = input.parse::<u64>() {
+        if secs < MIN_TIMESTAMP_SECS {
+            return Err(format!(
+                "Invalid timestamp {}, use HH:MM[:SS] for a time of day",
+                input
+            ));
+        }
+        return secs.checked_mul(1000).ok_or_else(invalid);
+    }
+    let parts = input
+        .split(':')
+        .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+        .collect::<Result<Vec<u64>, String>>()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [h, m] => (*h, *m, 0),
+        [h, m, s] => (*h, *m, *s),
+        _ => return Err(invalid()),
+    };
+    if hours >= 24 || minutes >= 60 || seconds >= 60 {
+        return Err(invalid());
+    }
+    let time = now - now % DAY_MILLIS + ((hours * 60 + minutes) * 60 + seconds) * 1000;
+    match time > now {
+        true => Ok(time),
+        false => Ok(time + DAY_MILLIS),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn messages_are_executed_at_their_time_until_they_expire() {
+        // 2021-06-01 12:00:00 UTC.
+        let start = 1_622_548_800_000;
+        let clock = MockClock::new(start);
+        let mut scheduler = Scheduler::new(clock.clone());
+        let at = |offset: u64, expires: Option<u64>| Schedule {
+            execute_at: Some(start + offset * 1000),
+            expires_at: expires.map(|expires| start + expires * 1000),
+        };
+
+        assert_eq!(
+            scheduler.schedule(&Schedule::default(), "now"),
+            Scheduled::Due("now")
+        );
+        assert_eq!(
+            scheduler.schedule(&at(20, None), "second"),
+            Scheduled::Queued(start + 20_000)
+        );
+        scheduler.schedule(&at(10, None), "first");
+        scheduler.schedule(&at(30, Some(40)), "suspended");
+        assert_eq!(scheduler.next_due(), Some(secs(10)));
+        assert!(scheduler.due().is_empty());
+
+        clock.advance(secs(20));
+        assert_eq!(scheduler.due(), vec!["first", "second"]);
+        assert_eq!(scheduler.next_due(), Some(secs(10)));
+
+        // The message expired before the scheduler was polled again.
+        clock.advance(secs(25));
+        assert!(scheduler.due().is_empty());
+        assert_eq!(scheduler.next_due(), None);
+        assert_eq!(
+            scheduler.schedule(&at(0, Some(30)), "late"),
+            Scheduled::Expired
+        );
+    }
+
+    #[test]
+    fn queue_and_lead_time_are_bounded() {
+        let start = 1_622_548_800_000;
+        let mut scheduler = Scheduler::new(MockClock::new(start));
+        let at = |offset: Duration| Schedule {
+            execute_at: Some(start + offset.as_millis() as u64),
+            expires_at: None,
+        };
+
+        assert!(matches!(
+            scheduler.schedule(&at(MAX_LEAD_TIME + secs(1)), 0),
+            Scheduled::Rejected(_)
+        ));
+        for i in 0..MAX_QUEUED {
+            assert!(matches!(
+                scheduler.schedule(&at(MAX_LEAD_TIME), i),
+                Scheduled::Queued(_)
+            ));
+        }
+        assert!(matches!(
+            scheduler.schedule(&at(secs(1)), MAX_QUEUED),
+            Scheduled::Rejected(_)
+        ));
+        // Messages that are due don't have to be queued.
+        assert_eq!(
+            scheduler.schedule(&Schedule::default(), MAX_QUEUED),
+            Scheduled::Due(MAX_QUEUED)
+        );
+    }
+
+    #[test]
+    fn times_of_day_refer_to_the_next_occurrence() {
+        let noon = 1_622_548_800_000;
+        assert_eq!(parse_time("18:00", noon), Ok(noon + 6 * 3_600_000));
+        assert_eq!(
+            parse_time("06:30:15", noon),
+            Ok(noon + 18 * 3_600_000 + 1_815_000)
+        );
+        assert_eq!(parse_time("12:00", noon), Ok(noon + DAY_MILLIS));
+        assert_eq!(parse_time("1622548800", 0), Ok(noon));
+        assert!(parse_time("1800", noon).is_err());
+        assert!(parse_time(&u64::MAX.to_string(), noon).is_err());
+        assert!(parse_time("24:00", noon).is_err());
+        assert!(parse_time("18:00:00:00", noon).is_err());
+        assert!(parse_time("six", noon).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+// Example of an application specific message type, that is registered in `main.rs`.
+
+use crate::registry::Payload;
+
+// Temperature in degrees Celsius, e.g. measured by a sensor of the board.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Temperature(pub i16);
+
+impl Payload for Temperature {
+    const TAG: &'static str = "temperature";
+
+    fn encode(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, String> {
+        match data {
+            [a, b] => Ok(Temperature(i16::from_be_bytes([*a, *b]))),
+            _ => Err("Invalid temperature".into()),
+        }
+    }
+
+    fn parse(input: &str) -> Result<Self, String> {
+        input
+            .parse()
+            .map(Temperature)
+            .map_err(|_| format!("Invalid temperature {}", input))
+    }
+}
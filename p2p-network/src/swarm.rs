@@ -1,84 +1,200 @@
-use crate::types::*;
+use crate::{channel::BoundedSender, config::Config, types::*};
+mod autonat;
+mod ban_list;
 mod behaviour;
+mod catch_up;
+mod encryption;
+mod file_exchange;
+mod peers;
+mod rate_limit;
+mod rendezvous;
+mod responder;
+mod signed_record;
 mod transport;
-use async_std::task::{self, Context, Poll};
+mod update_fetch;
+mod updates;
+use async_std::{future, task};
 use behaviour::{Behaviour, BehaviourEvent};
+use catch_up::CatchUpEvent;
+use file_exchange::{Download, FileExchangeEvent, FileRequest, FileResponse, Manifest};
 use futures::{
-    channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    channel::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender},
     prelude::*,
     select,
 };
 use libp2p::{
-    gossipsub::{error::PublishError, GossipsubEvent, GossipsubMessage},
-    kad::{GetRecordError, GetRecordOk, KademliaEvent, PutRecordOk, QueryId, QueryResult},
-    swarm::SwarmEvent,
+    core::connection::ListenerId,
+    gossipsub::{error::PublishError, GossipsubEvent},
+    kad::{
+        AddProviderOk, GetProvidersError, GetProvidersOk, GetRecordError, GetRecordOk,
+        KademliaEvent, PeerRecord, PutRecordOk, QueryId, QueryResult,
+    },
+    multiaddr::Protocol,
+    swarm::{DialError, SwarmEvent},
     Multiaddr, PeerId, Swarm,
 };
-use std::str::FromStr;
+use rendezvous::RendezvousEvent;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 use transport::TransportLayer;
+use update_fetch::UpdateFetch;
+use updates::{Updates, UPDATES_TOPIC};
+
+// Interval in which listening via relays is retried, if the listener closed.
+const RELAY_LISTEN_RETRY: Duration = Duration::from_secs(10);
 
 // Task to manage all swarm interaction and polling.
 pub struct SwarmTask {
     // The swarm that serves as entry-point for all network interaction.
     swarm: Swarm<Behaviour>,
     // Channel to receive commands from the user.
-    cmd_rx: UnboundedReceiver<Command>,
+    cmd_rx: Receiver<Command>,
     // Channel to return the outcome of a command to the user
-    cmd_res_tx: UnboundedSender<CommandResult>,
+    cmd_res_tx: Sender<CommandResult>,
     // Channel to forward gossibsub message that are received in the network.
-    message_tx: UnboundedSender<(Topic, GossipMessage)>,
+    // If the user task does not keep up, the channel either drops old messages or the swarm is
+    // not polled until the user task caught up, depending on the configured overflow policy.
+    message_tx: BoundedSender<(Topic, GossipMessage)>,
+    // Addresses on which the swarm listens.
+    listen_addrs: Vec<Multiaddr>,
+    // /p2p-circuit addresses on which the swarm listens via relays, with the current listener.
+    relay_listeners: Vec<(Multiaddr, Option<ListenerId>)>,
+    // Whether `start_listening` was called.
+    is_listening: bool,
+    // Rendezvous point that is used if a rendezvous command does not specify one.
+    rendezvous_point: Option<Multiaddr>,
+    // Time after which a command fails if it did not complete.
+    command_timeout: Duration,
+    // Time during which the acknowledgments of a published device command are collected.
+    ack_window: Duration,
+    // Releases that are signed by the local node, and updates that are announced to it.
+    updates: Updates,
+    // Time after which fetching the payload of an update fails.
+    update_fetch_timeout: Duration,
+    // Payload of an update that is fetched in the background.
+    update_fetch: Option<UpdateFetch>,
+    // Wakes the swarm task to install the updates that were announced.
+    install_tx: UnboundedSender<()>,
+    install_rx: UnboundedReceiver<()>,
+    // Outcome of the apply hooks that run in the background.
+    applied_tx: UnboundedSender<(UpdateManifest, UpdateState)>,
+    applied_rx: UnboundedReceiver<(UpdateManifest, UpdateState)>,
 }
 
 impl SwarmTask {
     // Create a new instance of a swarm task
     pub async fn new(
-        cmd_rx: UnboundedReceiver<Command>,
-        cmd_res_tx: UnboundedSender<CommandResult>,
-        message_tx: UnboundedSender<(Topic, GossipMessage)>,
-    ) -> Self {
+        cmd_rx: Receiver<Command>,
+        cmd_res_tx: Sender<CommandResult>,
+        message_tx: BoundedSender<(Topic, GossipMessage)>,
+        config: &Config,
+    ) -> Result<Self, String> {
         // Create transport layer
-        let transport = TransportLayer::new();
+        let transport = TransportLayer::new(&config.transport)?;
+        let listen_addrs = transport.listen_addrs();
+        let relay_listeners = transport
+            .relay_listen_addrs()
+            .into_iter()
+            .map(|addr| (addr, None))
+            .collect();
+        let command_timeout = config.commands.timeout()?;
+        let updates = Updates::new(&config.updates)?;
+        let update_fetch_timeout = config.updates.fetch_timeout()?;
+        let (install_tx, install_rx) = mpsc::unbounded();
+        let (applied_tx, applied_rx) = mpsc::unbounded();
 
         // Build Swarm based on the transport and behaviour protocols/
-        let swarm = Behaviour::build_swarm(transport).await;
+        let mut swarm = Behaviour::build_swarm(transport, config).await?;
+
+        // Receive the releases of trusted maintainers.
+        if updates.is_enabled() {
+            swarm
+                .behaviour_mut()
+                .subscribe(UPDATES_TOPIC.into())
+                .map_err(|e| format!("Failed to subscribe to updates: {:?}", e))?;
+        }
 
-        SwarmTask {
+        Ok(SwarmTask {
             swarm,
             cmd_rx,
             cmd_res_tx,
             message_tx,
-        }
+            listen_addrs,
+            relay_listeners,
+            is_listening: false,
+            rendezvous_point: config.discovery.rendezvous_point.clone(),
+            command_timeout,
+            ack_window: config.commands.ack_window(),
+            updates,
+            update_fetch_timeout,
+            update_fetch: None,
+            install_tx,
+            install_rx,
+            applied_tx,
+            applied_rx,
+        })
     }
 
-    // Start listening to swarm, block thread until a new listener was created or error occured.
-    pub async fn start_listening(&mut self) -> Result<Multiaddr, ()> {
-        // Listen to a multiaddress assigned by the OS.
-        Multiaddr::from_str("/ip4/0.0.0.0/tcp/0")
-            .map_err(|_| ())
-            .and_then(|a| Swarm::listen_on(&mut self.swarm, a).map_err(|_| ()))?;
+    pub fn local_peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
 
-        // Poll swarm until either the swarm starts listening, or an listener error occurs.
-        // On Success return the actual, OS assigned, listening address.
-        loop {
-            match self.swarm.next_event().await {
-                SwarmEvent::NewListenAddr(addr) => {
-                    println!("\n\nStarted Listening on: {}", addr);
-                    return Ok(addr);
+    // Start listening to swarm, block thread until the new listeners were created or error occured.
+    // Listening via relays is started in the background, because it requires a connection to
+    // the relay.
+    pub async fn start_listening(&mut self) -> Result<Vec<Multiaddr>, ()> {
+        self.is_listening = true;
+        let mut addrs = Vec::new();
+        for listen_addr in self.listen_addrs.clone() {
+            let is_websocket = transport::is_websocket(&listen_addr);
+            Swarm::listen_on(&mut self.swarm, listen_addr).map_err(|_| ())?;
+
+            // Poll swarm until either the swarm starts listening, or an listener error occurs.
+            // On Success add the actual, OS assigned, listening address.
+            loop {
+                match self.swarm.next_event().await {
+                    SwarmEvent::NewListenAddr(addr) => {
+                        println!("\n\nStarted Listening on: {}", addr);
+                        // Further addresses of a previous listener may be reported meanwhile.
+                        if transport::is_websocket(&addr) == is_websocket {
+                            addrs.push(addr);
+                            break;
+                        }
+                    }
+                    SwarmEvent::ListenerError { .. } => return Err(()),
+                    _ => {}
                 }
-                SwarmEvent::ListenerError { .. } => return Err(()),
-                _ => {}
+            }
+        }
+        self.listen_via_relays();
+        Ok(addrs)
+    }
+
+    // Listen via each relay whose listener is not active, e.g. because the connection to the
+    // relay closed or could not be established.
+    // The /p2p-circuit address is reported as listening address once the relay is connected.
+    fn listen_via_relays(&mut self) {
+        let closed = self.swarm.behaviour_mut().take_closed_listeners();
+        for (addr, listener) in self.relay_listeners.iter_mut() {
+            let is_active = matches!(listener, Some(id) if !closed.contains(id));
+            if !is_active {
+                *listener = Swarm::listen_on(&mut self.swarm, addr.clone()).ok();
             }
         }
     }
 
     // Kick off the swarm task in a future (asynchronous operation)
     pub async fn run(mut self) {
-        // Start listening to the swarm for incoming requests and queries from the network
-        if self.start_listening().await.is_err() {
+        // Start listening to the swarm for incoming requests and queries from the network,
+        // if `start_listening` was not called before.
+        if !self.is_listening && self.start_listening().await.is_err() {
             return println!("Failed to start listening. Aborting.");
         }
 
         println!("Local peer Id: {:?}\n", self.swarm.local_peer_id());
+        let mut relay_retry = task::sleep(RELAY_LISTEN_RETRY).boxed().fuse();
         loop {
             // Simultainously poll both futures, select the one that return first.
             select! {
@@ -100,19 +216,43 @@ impl SwarmTask {
                     },
                     None => break
                 }},
+                // Install the updates that were announced meanwhile.
+                _ = self.install_rx.next().fuse() => self.install_updates(),
+                // The apply hook of an update exited, install the next one.
+                applied = self.applied_rx.next().fuse() => {
+                    if let Some((manifest, state)) = applied {
+                        self.updates.finish(&manifest, state);
+                    }
+                    self.install_updates();
+                },
+                // Fetching the payload of an update timed out.
+                _ = Self::fetch_deadline(&self.update_fetch).fuse() => {
+                    if let Some(res) = self.update_fetch.as_ref().and_then(|f| f.check_timeout()) {
+                        self.fetched_update(res);
+                    }
+                },
+                // Listen again via relays whose listener closed.
+                _ = relay_retry => {
+                    self.listen_via_relays();
+                    relay_retry = task::sleep(RELAY_LISTEN_RETRY).boxed().fuse();
+                },
                 // BehaviourEvent that occured in the Swarm.
                 // swarm.next() only returns BehaviourEvents from gossibsub and kademlia
                 // swarm.next_event() returns all libp2p::swarm::SwarmeEvents, which includes apart from
                 // SwarmEvent::Behaviour(BehaviourEvent) also the swarm events for e.g. listening, connection established, ...
-                event = self.swarm.next().fuse() => {
-                    if let BehaviourEvent::Gossipsub(GossipsubEvent::Message {
-                        message: GossipsubMessage { data, topic, .. },
-                        ..
-                    }) = event {
-                        // Try to deserialize the received data back into the GossipMessage that it was serialzed from.
-                        if let Ok(msg) = serde_json::from_slice::<GossipMessage>(&data) {
+                // With the backpressure policy, the swarm is only polled once the user task took
+                // the messages that were kept back, while commands are still handled.
+                event = Self::next_event(&mut self.swarm, &mut self.message_tx).fuse() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    };
+                    self.inject_fetch_event(&event);
+                    if let BehaviourEvent::Gossipsub(GossipsubEvent::Message { message, .. }) = event {
+                        // Try to decrypt and deserialize the received data back into the GossipMessage that it was serialzed from.
+                        if let Some(msg) = self.swarm.behaviour().decode_message(&message) {
                             // Send message via channel to user task.
-                            let send = self.send_gossip_msg(topic.into_string(), msg).await;
+                            let send = self.send_gossip_msg(message.topic.into_string(), msg);
                             if send.is_err() {
                                 break;
                             }
@@ -123,82 +263,182 @@ impl SwarmTask {
         }
     }
 
+    // Wait until the gossip messages that were kept back were forwarded to the user task, then
+    // poll the swarm for the next behaviour event.
+    async fn next_event(
+        swarm: &mut Swarm<Behaviour>,
+        message_tx: &mut BoundedSender<(Topic, GossipMessage)>,
+    ) -> Result<BehaviourEvent, String> {
+        message_tx.flush().await?;
+        Ok(swarm.next().await)
+    }
+
     // Await the query result for a kademlia query to get or publish a record in the DHT.
-    fn await_query_result<T>(
+    async fn await_query_result<T>(
         &mut self,
         query_id: QueryId,
-        f: &dyn Fn(&QueryResult) -> Option<T>,
+        f: &(dyn Fn(&QueryResult) -> Option<T> + Sync),
     ) -> Result<T, String> {
-        task::block_on(async {
-            loop {
-                // Await next behaviour event
-                match self.swarm.next().await {
-                    BehaviourEvent::Kademlia(KademliaEvent::QueryResult { id, result, .. }) => {
-                        // Return if result was for the query was received.
-                        let is_query = id == query_id;
-                        if let Some(value) = is_query.then(|| f(&result)).flatten() {
-                            return Ok(value);
-                        }
+        // Return if result was for the query was received.
+        let is_match = |event: &BehaviourEvent| match event {
+            BehaviourEvent::Kademlia(KademliaEvent::QueryResult { id, result, .. })
+                if *id == query_id =>
+            {
+                f(result)
+            }
+            _ => None,
+        };
+        self.await_event(&is_match).await
+    }
+
+    // Poll the swarm until a behaviour event matches.
+    // Gossipsub messages that are received meanwhile are forwarded to the user task.
+    async fn await_event<T>(
+        &mut self,
+        f: &(dyn Fn(&BehaviourEvent) -> Option<T> + Sync),
+    ) -> Result<T, String> {
+        loop {
+            // Await next behaviour event
+            let event = self.swarm.next().await;
+            self.inject_fetch_event(&event);
+            match event {
+                BehaviourEvent::Gossipsub(GossipsubEvent::Message { message, .. }) => {
+                    // Try parse and send received gossipsub message to user task.
+                    if let Some(msg) = self.swarm.behaviour().decode_message(&message) {
+                        self.send_gossip_msg(message.topic.into_string(), msg)?;
                     }
-                    BehaviourEvent::Gossipsub(GossipsubEvent::Message {
-                        message: GossipsubMessage { data, topic, .. },
-                        ..
-                    }) => {
-                        // Try parse and send received gossipsub message to user task.
-                        if let Ok(msg) = serde_json::from_slice::<GossipMessage>(&data) {
-                            let send = self.send_gossip_msg(topic.into_string(), msg).await;
-                            if let Err(err) = send {
-                                return Err(err);
-                            }
-                        }
+                }
+                event => {
+                    if let Some(value) = f(&event) {
+                        return Ok(value);
                     }
-                    _ => {}
                 }
             }
-        })
+        }
+    }
+
+    // Connect to the given or the configured rendezvous point, and add it as known peer.
+    // Returns the peer id of the rendezvous point.
+    async fn connect_rendezvous_point(
+        &mut self,
+        point: Option<Multiaddr>,
+    ) -> Result<PeerId, String> {
+        let addr = point
+            .or_else(|| self.rendezvous_point.clone())
+            .ok_or_else(|| String::from("No rendezvous point is configured"))?;
+        let peer_id = match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+            _ => None,
+        };
+        match peer_id {
+            Some(peer_id) if self.swarm.is_connected(&peer_id) => Ok(peer_id),
+            _ => {
+                let (peer_id, addr) = self.dial_addr(addr).await?;
+                self.swarm.behaviour_mut().add_peer(peer_id, addr);
+                Ok(peer_id)
+            }
+        }
     }
 
-    async fn dial_addr(&mut self, addr: Multiaddr) -> Result<PeerId, String> {
+    // Dial an address and wait until the connection is established.
+    // If the address ends with /p2p/<peer-id>, the dial only succeeds if the remote has that id.
+    async fn dial_addr(&mut self, addr: Multiaddr) -> Result<(PeerId, Multiaddr), String> {
+        let expected_peer = match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+            _ => None,
+        };
         self.swarm
             .dial_addr(addr.clone())
             .map_err(|e| format!("{}", e))?;
-        task::block_on(async {
-            loop {
-                match self.swarm.next_event().await {
-                    SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(GossipsubEvent::Message {
-                        message: GossipsubMessage { data, topic, .. },
-                        ..
-                    })) => {
-                        // Try parse and send received gossipsub message to user task.
-                        if let Ok(msg) = serde_json::from_slice::<GossipMessage>(&data) {
-                            let send = self.send_gossip_msg(topic.into_string(), msg).await;
-                            if let Err(err) = send {
-                                return Err(err);
-                            }
-                        }
-                    }
-                    SwarmEvent::ConnectionEstablished {
-                        peer_id, endpoint, ..
-                    } if endpoint.get_remote_address() == &addr => {
-                        return Ok(peer_id);
-                    }
-                    SwarmEvent::UnreachableAddr {
-                        address,
-                        error,
-                        attempts_remaining: 0,
-                        ..
-                    } if address == addr => {
-                        return Err(format!("{}", error));
-                    }
-                    SwarmEvent::UnknownPeerUnreachableAddr { address, error }
-                        if address == addr =>
-                    {
-                        return Err(format!("{}", error));
+        self.await_dial(expected_peer, Some(&addr)).await
+    }
+
+    // Connect to a peer by its id, and wait until the connection is established.
+    // If no address of the peer is known, it is looked up in the DHT first.
+    // Returns None if the peer was connected already.
+    async fn dial_peer(&mut self, peer_id: PeerId) -> Result<Option<(PeerId, Multiaddr)>, String> {
+        if self.swarm.is_connected(&peer_id) {
+            return Ok(None);
+        }
+        match self.swarm.dial(&peer_id) {
+            Ok(()) => {}
+            Err(DialError::NoAddresses) => {
+                // Query the peers that are closest to the peer id, which includes the peer itself
+                // if it is part of the DHT.
+                let query_id = self.swarm.behaviour_mut().get_closest_peers(peer_id);
+                let is_match = |event: &QueryResult| match event {
+                    QueryResult::GetClosestPeers(_) => Some(()),
+                    _ => None,
+                };
+                self.await_query_result(query_id, &is_match).await?;
+
+                // Kademlia may have connected the peer during the query.
+                if self.swarm.is_connected(&peer_id) {
+                    return Ok(None);
+                }
+                self.swarm.dial(&peer_id).map_err(|e| match e {
+                    DialError::NoAddresses => format!("No address of peer {} was found", peer_id),
+                    e => format!("{}", e),
+                })?;
+            }
+            Err(e) => return Err(format!("{}", e)),
+        }
+        self.await_dial(Some(peer_id), None).await.map(Some)
+    }
+
+    // Poll the swarm until the dial succeeded or failed.
+    // The dial is identified by the peer id if it is known, otherwise by the dialed address.
+    // Returns the peer id and address of the established connection.
+    async fn await_dial(
+        &mut self,
+        peer: Option<PeerId>,
+        addr: Option<&Multiaddr>,
+    ) -> Result<(PeerId, Multiaddr), String> {
+        let is_dial = |peer_id: Option<&PeerId>, address: &Multiaddr| match &peer {
+            Some(peer) => peer_id == Some(peer),
+            None => addr == Some(address),
+        };
+        loop {
+            let event = self.swarm.next_event().await;
+            if let SwarmEvent::Behaviour(event) = &event {
+                self.inject_fetch_event(event);
+            }
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(GossipsubEvent::Message {
+                    message,
+                    ..
+                })) => {
+                    // Try parse and send received gossipsub message to user task.
+                    if let Some(msg) = self.swarm.behaviour().decode_message(&message) {
+                        self.send_gossip_msg(message.topic.into_string(), msg)?;
                     }
-                    _ => {}
                 }
+                SwarmEvent::ConnectionEstablished {
+                    peer_id, endpoint, ..
+                } if is_dial(Some(&peer_id), endpoint.get_remote_address()) => {
+                    return Ok((peer_id, endpoint.get_remote_address().clone()));
+                }
+                SwarmEvent::UnreachableAddr {
+                    peer_id,
+                    address,
+                    error,
+                    attempts_remaining: 0,
+                } if is_dial(Some(&peer_id), &address) => {
+                    return Err(format!("{}", error));
+                }
+                SwarmEvent::BannedPeer { peer_id, endpoint }
+                    if is_dial(Some(&peer_id), endpoint.get_remote_address()) =>
+                {
+                    return Err(format!("Peer {} is banned", peer_id));
+                }
+                SwarmEvent::UnknownPeerUnreachableAddr { address, error }
+                    if is_dial(None, &address) =>
+                {
+                    return Err(format!("{}", error));
+                }
+                _ => {}
             }
-        })
+        }
     }
 
     // Execute the command recieved from the user task.
@@ -208,7 +448,28 @@ impl SwarmTask {
     // For Gossibesub events, the result can directly be returned/
     // In case of kademlia queries, the swarm has to be polled for a Kademlia event
     // that signales the outcome of the query, via the await_query_result method.
+    // Commands that did not complete within the command timeout return CommandResult::Timeout.
+    // Fetching a file may take longer, instead the provider lookup, each dial and each request for
+    // a chunk time out individually.
+    // Publishing a device command completes once the ack window elapsed.
     async fn run_command(&mut self, cmd: Command) -> Result<(), String> {
+        let timeout = self.command_timeout;
+        let res = match cmd {
+            Command::Fetch { .. } | Command::PublishCommand { .. } => {
+                self.execute_command(cmd).await?
+            }
+            cmd => match future::timeout(timeout, self.execute_command(cmd)).await {
+                Ok(res) => res?,
+                Err(_) => CommandResult::Timeout(timeout),
+            },
+        };
+        self.cmd_res_tx
+            .send(res)
+            .await
+            .map_err(|err| format!("Returning command result to channel failed: {:?}", err))
+    }
+
+    async fn execute_command(&mut self, cmd: Command) -> Result<CommandResult, String> {
         let res = match cmd {
             Command::SubscribeGossipTopic(topic) => {
                 let res = self
@@ -227,98 +488,697 @@ impl SwarmTask {
                 CommandResult::UnsubscribResult(res)
             }
             Command::PublishGossipData { topic, data } => {
+                let behaviour = self.swarm.behaviour_mut();
+                let res = behaviour.check_publish_rate(&topic).and_then(|_| {
+                    behaviour
+                        .publish_data(topic, &data)
+                        .map_err(Self::publish_error)
+                });
+                CommandResult::PublishResult(res)
+            }
+            Command::PublishCommand { topic, data } => {
+                let res = self.publish_command(topic, data).await?;
+                CommandResult::PublishCommandResult(res)
+            }
+            // Acknowledgments are not rate limited, they are only published in response to
+            // received commands.
+            Command::Acknowledge { topic, id, status } => {
+                let data = GossipMessage::Ack { id, status };
                 let res = self
                     .swarm
                     .behaviour_mut()
                     .publish_data(topic, &data)
-                    .map_err(|e| match e {
-                        PublishError::InsufficientPeers => {
-                            "No known peers are subscribing to that topic.".into()
-                        }
-                        _ => format!("{:?}", e),
-                    });
-                CommandResult::PublishResult(res)
+                    .map(|_| ())
+                    .map_err(Self::publish_error);
+                CommandResult::AcknowledgeResult(res)
             }
-            Command::GetRecord(key) => {
+            Command::CatchUp(topic) => {
+                let res = self.catch_up(topic).await?;
+                CommandResult::CatchUpResult(res)
+            }
+            Command::GetRecord { key, quorum } => {
                 // Initiate kademlia query for a record.
-                let query_id = self.swarm.behaviour_mut().get_record(key);
+                let query_id = self.swarm.behaviour_mut().get_record(key, quorum);
 
                 // determine what query result matches the issued kademlia query
                 let is_match = |event: &QueryResult| match event {
                     QueryResult::GetRecord(Ok(GetRecordOk { records, .. })) => {
-                        let records = records
-                            .iter()
-                            .map(|peer_rec| peer_rec.record.clone())
-                            .collect();
-                        Some(Ok(records))
+                        let records = Self::distinct_records(records);
+                        match records.is_empty() {
+                            true => Some(Err("No record with a valid signature was found".into())),
+                            false => Some(Ok(records)),
+                        }
                     }
                     QueryResult::GetRecord(Err(GetRecordError::NotFound { key, .. })) => {
                         Some(Err(format!("Record for {:?} was not found", key)))
                     }
-                    QueryResult::GetRecord(Err(e)) => Some(Err(format!("{:?}", e))),
+                    QueryResult::GetRecord(Err(GetRecordError::QuorumFailed {
+                        records,
+                        quorum,
+                        ..
+                    }))
+                    | QueryResult::GetRecord(Err(GetRecordError::Timeout {
+                        records,
+                        quorum,
+                        ..
+                    })) => Some(Err(format!(
+                        "Only {} of {} required peers returned the record",
+                        records.len(),
+                        quorum
+                    ))),
                     _ => None,
                 };
 
                 // Poll swarm until a matching query result is returned.
-                let res = self.await_query_result(query_id, &is_match)?;
+                let res = self.await_query_result(query_id, &is_match).await?;
                 CommandResult::GetRecordResult(res)
             }
-            Command::PutRecord { key, value } => {
+            Command::PutRecord {
+                key,
+                value,
+                quorum,
+                ttl,
+                signed,
+            } => {
                 // Initiate kademlia query to publish a record.
-                // This queries the peer who's id is closest to the hash of the record key to store
-                // the record. Fails if that peer fails to store it.
-                let put_result = self.swarm.behaviour_mut().put_record(key, value);
+                // This queries the peers who's ids are closest to the hash of the record key to store
+                // the record. Fails if less peers than required by the quorum store it.
+                let put_result = self
+                    .swarm
+                    .behaviour_mut()
+                    .put_record(key, value, quorum, ttl, signed);
                 let res = match put_result {
                     Ok(query_id) => {
                         // Determine what query result matches the issued kademlia query
                         let is_match = |event: &QueryResult| match event {
-                            QueryResult::PutRecord(Ok(PutRecordOk { .. })) => Some(Ok(())),
+                            QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
+                                Some(Ok(String::from_utf8_lossy(key.as_ref()).into_owned()))
+                            }
                             QueryResult::PutRecord(Err(e)) => Some(Err(format!("{:?}", e))),
                             _ => None,
                         };
 
                         // Poll swarm until a matching query result is returned.
-                        self.await_query_result(query_id, &is_match)?
+                        self.await_query_result(query_id, &is_match).await?
                     }
-                    Err(err) => Err(format!("{:?}", err)),
+                    Err(err) => Err(err),
                 };
                 CommandResult::PutRecordResult(res)
             }
+            Command::StartProviding(key) => {
+                // Initiate kademlia query to store a provider record at the peers closest to the key.
+                let res = match self.swarm.behaviour_mut().start_providing(key) {
+                    Ok(query_id) => {
+                        let is_match = |event: &QueryResult| match event {
+                            QueryResult::StartProviding(Ok(AddProviderOk { .. })) => Some(Ok(())),
+                            QueryResult::StartProviding(Err(e)) => Some(Err(format!("{:?}", e))),
+                            _ => None,
+                        };
+                        self.await_query_result(query_id, &is_match).await?
+                    }
+                    Err(err) => Err(format!("{:?}", err)),
+                };
+                CommandResult::StartProvidingResult(res)
+            }
+            Command::StopProviding(key) => {
+                self.swarm.behaviour_mut().stop_providing(key);
+                CommandResult::StopProvidingAck
+            }
+            Command::GetProviders(key) => {
+                let res = self.get_providers(key).await?;
+                CommandResult::GetProvidersResult(res)
+            }
             Command::Connect(addr) => {
                 let res = self.dial_addr(addr).await;
-                CommandResult::ConnectResult(res)
+                // Add the peer as if it was discovered, which is required if mDNS is disabled.
+                if let Ok((peer_id, addr)) = &res {
+                    self.swarm.behaviour_mut().add_peer(*peer_id, addr.clone());
+                }
+                CommandResult::ConnectResult(res.map(|(peer_id, _)| peer_id))
+            }
+            Command::ConnectPeer(peer_id) => {
+                let res = self.dial_peer(peer_id).await;
+                if let Ok(Some((peer_id, addr))) = &res {
+                    self.swarm.behaviour_mut().add_peer(*peer_id, addr.clone());
+                }
+                CommandResult::ConnectResult(res.map(|_| peer_id))
+            }
+            Command::Ban(peer_id) => {
+                let res = self.swarm.behaviour_mut().ban_peer(peer_id);
+                // Banning the peer in the swarm closes all connections to it, and rejects new ones.
+                // The ban is only applied once it was persisted, so that it survives a restart.
+                if res.is_ok() {
+                    self.swarm.ban_peer_id(peer_id);
+                }
+                CommandResult::BanResult(res)
+            }
+            Command::Unban(peer_id) => {
+                let res = self.swarm.behaviour_mut().unban_peer(&peer_id);
+                if res.is_ok() {
+                    self.swarm.unban_peer_id(peer_id);
+                }
+                CommandResult::UnbanResult(res)
+            }
+            Command::Disconnect(peer_id) => {
+                let res = if self.swarm.is_connected(&peer_id) {
+                    // Remove the peer so that it is not dialed again right away.
+                    self.swarm.behaviour_mut().remove_peer(&peer_id);
+                    // The swarm only closes the connections to a peer when it is banned.
+                    self.swarm.ban_peer_id(peer_id);
+                    self.swarm.unban_peer_id(peer_id);
+                    Ok(())
+                } else {
+                    Err(format!("Not connected to peer {}", peer_id))
+                };
+                CommandResult::DisconnectResult(res)
+            }
+            Command::Pin(addr) => {
+                CommandResult::PinResult(self.swarm.behaviour_mut().pin_peer(addr))
+            }
+            Command::Unpin(addr) => {
+                CommandResult::UnpinResult(self.swarm.behaviour_mut().unpin_peer(&addr))
+            }
+            Command::GetPeers => {
+                let mut info = self.swarm.behaviour().peers_info();
+                info.listen_addrs = self.swarm.listeners().cloned().collect();
+                CommandResult::PeersResult(info)
+            }
+            Command::Register {
+                namespace,
+                ttl,
+                point,
+            } => {
+                let res = match self.connect_rendezvous_point(point).await {
+                    Ok(point) => {
+                        let ttl = ttl.unwrap_or(rendezvous::DEFAULT_TTL);
+                        self.swarm
+                            .behaviour_mut()
+                            .register(point, namespace.clone(), ttl);
+                        let is_match = |event: &BehaviourEvent| match event {
+                            BehaviourEvent::Rendezvous(RendezvousEvent::Registered {
+                                peer_id,
+                                namespace: ns,
+                                result,
+                            }) if peer_id == &point && ns == &namespace => Some(result.clone()),
+                            _ => None,
+                        };
+                        self.await_event(&is_match).await?
+                    }
+                    Err(err) => Err(err),
+                };
+                CommandResult::RegisterResult(res)
+            }
+            Command::Unregister { namespace, point } => {
+                let res = self.connect_rendezvous_point(point).await;
+                if let Ok(point) = res {
+                    self.swarm.behaviour_mut().unregister(point, namespace);
+                }
+                CommandResult::UnregisterResult(res.map(|_| ()))
+            }
+            Command::Discover { namespace, point } => {
+                let res = match self.connect_rendezvous_point(point).await {
+                    Ok(point) => {
+                        self.swarm
+                            .behaviour_mut()
+                            .discover(point, namespace.clone());
+                        let is_match = |event: &BehaviourEvent| match event {
+                            BehaviourEvent::Rendezvous(RendezvousEvent::Discovered {
+                                peer_id,
+                                namespace: ns,
+                                result,
+                            }) if peer_id == &point && ns == &namespace => Some(result.clone()),
+                            _ => None,
+                        };
+                        self.await_event(&is_match).await?
+                    }
+                    Err(err) => Err(err),
+                };
+                // Add the discovered peers, so that they are dialed by gossipsub and kademlia.
+                if let Ok(registrations) = &res {
+                    for registration in registrations {
+                        for addr in registration.addrs.iter() {
+                            self.swarm
+                                .behaviour_mut()
+                                .add_peer(registration.peer_id, addr.clone());
+                        }
+                    }
+                }
+                CommandResult::DiscoverResult(res)
+            }
+            Command::Share(path) => {
+                let res = self.share_file(&path).await?;
+                CommandResult::ShareResult(res)
+            }
+            Command::Fetch { root, path } => {
+                let res = self.fetch_file(&root, &path).await?;
+                CommandResult::FetchResult(res)
+            }
+            Command::Release {
+                path,
+                version,
+                target,
+            } => {
+                let res = self.release(&path, version, target).await?;
+                CommandResult::ReleaseResult(res)
+            }
+            Command::GetUpdates => CommandResult::UpdatesResult(self.updates.updates()),
+            Command::GetStats => {
+                let (rate_limited_publishes, rate_limited_messages) =
+                    self.swarm.behaviour().rate_limited();
+                let stats = Stats {
+                    dropped_messages: self.message_tx.dropped(),
+                    rate_limited_publishes,
+                    rate_limited_messages,
+                };
+                CommandResult::StatsResult(stats)
             }
             Command::Shutdown => CommandResult::ShutdownAck,
         };
-        Self::send_channel(&mut self.cmd_res_tx, res).await
+        Ok(res)
     }
 
-    // Forward a gossipsub message via the channel to the user task.
-    async fn send_gossip_msg(
+    // Query the DHT for the providers of a key.
+    // On timeout, the providers that were found until then are still returned.
+    async fn get_providers(&mut self, key: String) -> Result<Result<Vec<PeerId>, String>, String> {
+        let query_id = self.swarm.behaviour_mut().get_providers(key);
+        let is_match =
+            |event: &QueryResult| match event {
+                QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. }))
+                | QueryResult::GetProviders(Err(GetProvidersError::Timeout {
+                    providers, ..
+                })) if !providers.is_empty() => Some(Ok(providers.iter().cloned().collect())),
+                QueryResult::GetProviders(Ok(GetProvidersOk { key, .. })) => {
+                    Some(Err(format!("No providers for {:?} were found", key)))
+                }
+                QueryResult::GetProviders(Err(e)) => Some(Err(format!("{:?}", e))),
+                _ => None,
+            };
+        self.await_query_result(query_id, &is_match).await
+    }
+
+    // Share a file, and announce the local peer as provider of its root hash in the DHT, so that
+    // other peers can find it.
+    async fn share_file(&mut self, path: &str) -> Result<Result<String, String>, String> {
+        let root = match self.swarm.behaviour_mut().share_file(path) {
+            Ok(root) => root,
+            Err(err) => return Ok(Err(err)),
+        };
+        let key = file_exchange::provider_key(&root);
+        let query_id = match self.swarm.behaviour_mut().start_providing(key) {
+            Ok(query_id) => query_id,
+            Err(err) => return Ok(Err(format!("{:?}", err))),
+        };
+        let is_match = |event: &QueryResult| match event {
+            QueryResult::StartProviding(Ok(AddProviderOk { .. })) => Some(Ok(root.clone())),
+            QueryResult::StartProviding(Err(e)) => {
+                Some(Err(format!("Announcing {} failed: {:?}", root, e)))
+            }
+            _ => None,
+        };
+        self.await_query_result(query_id, &is_match).await
+    }
+
+    // Share the payload of a release, and announce its signed manifest on the updates topic.
+    async fn release(
         &mut self,
-        topic: String,
-        message: GossipMessage,
-    ) -> Result<(), String> {
-        let send = (topic, message);
-        Self::send_channel(&mut self.message_tx, send).await
+        path: &str,
+        version: String,
+        target: Option<String>,
+    ) -> Result<Result<(UpdateManifest, PeerId), String>, String> {
+        let publisher = match self.updates.publisher() {
+            Some(publisher) => publisher,
+            None => return Ok(Err("No signing key is configured".into())),
+        };
+        let size = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Ok(Err(format!("Failed to read {}: {}", path, e))),
+        };
+        let root = match self.share_file(path).await? {
+            Ok(root) => root,
+            Err(err) => return Ok(Err(err)),
+        };
+        let manifest = UpdateManifest {
+            version,
+            target: target.unwrap_or_else(|| self.updates.target().into()),
+            root,
+            size,
+        };
+        let res = self.updates.sign(manifest.clone()).and_then(|update| {
+            self.swarm
+                .behaviour_mut()
+                .publish_data(UPDATES_TOPIC.into(), &GossipMessage::Update(update))
+                .map_err(|e| format!("{:?}", e))
+        });
+        Ok(res.map(|_| (manifest, publisher)))
     }
 
-    // Poll the channel until it is ready to send at least one message, then send the message.
-    // Failes if the channel is closed.
-    async fn send_channel<T>(channel: &mut UnboundedSender<T>, message: T) -> Result<(), String> {
-        future::poll_fn(|tcx: &mut Context<'_>| match channel.poll_ready(tcx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(err)) => err
-                .is_full()
-                .then(|| Poll::Pending)
-                .unwrap_or(Poll::Ready(Err(err))),
-            _ => Poll::Pending,
-        })
-        .await
-        .and_then(|()| channel.start_send(message))
-        .map_err(|err| {
-            channel.close_channel();
-            format!("Forwarding gossip message to channel failed: {:?}", err)
-        })
+    // Start fetching the payload of the pending update with the highest version, unless an update
+    // is being fetched or applied. The payload is fetched in the background, see `UpdateFetch`.
+    fn install_updates(&mut self) {
+        if self.updates.is_installing() {
+            return;
+        }
+        while let Some((manifest, path)) = self.updates.next_pending() {
+            match self.updates.prepare_staging() {
+                Ok(()) => {
+                    self.updates.set_state(&manifest, UpdateState::Fetching);
+                    let timeout = self.update_fetch_timeout;
+                    let fetch = UpdateFetch::start(&mut self.swarm, manifest, path, timeout);
+                    self.update_fetch = Some(fetch);
+                    return;
+                }
+                Err(err) => self.updates.set_state(&manifest, UpdateState::Failed(err)),
+            }
+        }
+    }
+
+    // Pass a behaviour event to the update fetch, and apply the update once it completed.
+    fn inject_fetch_event(&mut self, event: &BehaviourEvent) {
+        let res = match self.update_fetch.as_mut() {
+            Some(fetch) => fetch.inject_event(&mut self.swarm, event),
+            None => return,
+        };
+        if let Some(res) = res {
+            self.fetched_update(res);
+        }
+    }
+
+    // Resolves once the update fetch timed out, never if no update is fetched.
+    async fn fetch_deadline(fetch: &Option<UpdateFetch>) {
+        match fetch {
+            Some(fetch) => task::sleep(fetch.remaining()).await,
+            None => future::pending().await,
+        }
+    }
+
+    // Apply the update whose payload was fetched, or record why the fetch failed. Then install
+    // the next update. The apply hook runs in the background, the next update is installed once
+    // it exited.
+    fn fetched_update(&mut self, res: Result<FetchInfo, String>) {
+        let UpdateFetch { manifest, path, .. } = match self.update_fetch.take() {
+            Some(fetch) => fetch,
+            None => return,
+        };
+        let err = match res {
+            Ok(info) if info.size == manifest.size => {
+                if let Some(hook) = self.updates.apply(&manifest, &path) {
+                    let applied_tx = self.applied_tx.clone();
+                    task::spawn(async move {
+                        let state = hook.await;
+                        let _ = applied_tx.unbounded_send((manifest, state));
+                    });
+                }
+                return self.install_updates();
+            }
+            Ok(_) => "The size of the payload does not match".into(),
+            Err(err) => err,
+        };
+        self.updates.set_state(&manifest, UpdateState::Failed(err));
+        self.install_updates();
+    }
+
+    // Publish a device command, and collect the acknowledgments of the receivers until the ack
+    // window elapsed. Gossip messages that are received meanwhile are forwarded to the user task.
+    // The peers that are known to subscribe to the topic, but did not acknowledge the command,
+    // are returned without status.
+    async fn publish_command(
+        &mut self,
+        topic: Topic,
+        data: GossipMessage,
+    ) -> Result<Result<Vec<PeerAck>, String>, String> {
+        let id = match data.command_id() {
+            Some(id) => id,
+            None => return Ok(Err("The message is no device command".into())),
+        };
+        let behaviour = self.swarm.behaviour_mut();
+        if let Err(err) = behaviour.check_publish_rate(&topic) {
+            return Ok(Err(err));
+        }
+        // The acknowledgments are published to the topic of the command.
+        let is_subscribed = match behaviour.subscribe(topic.clone()) {
+            Ok(is_new) => is_new,
+            Err(e) => return Ok(Err(format!("{:?}", e))),
+        };
+        let res = behaviour.publish_data(topic.clone(), &data);
+        let mut acks: Vec<PeerAck> = behaviour
+            .topic_peers(&topic)
+            .into_iter()
+            .map(|peer_id| PeerAck {
+                peer_id,
+                status: None,
+            })
+            .collect();
+
+        let deadline = Instant::now() + self.ack_window;
+        while res.is_ok() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let event = match future::timeout(remaining, self.swarm.next()).await {
+                Ok(event) => event,
+                Err(_) => break,
+            };
+            self.inject_fetch_event(&event);
+            let message = match event {
+                BehaviourEvent::Gossipsub(GossipsubEvent::Message { message, .. }) => message,
+                _ => continue,
+            };
+            match self.swarm.behaviour().decode_message(&message) {
+                Some(GossipMessage::Ack { id: ack_id, status })
+                    if ack_id == id && message.topic.as_str() == topic =>
+                {
+                    // Acknowledgments are signed, hence the source is set.
+                    let peer_id = match message.source {
+                        Some(peer_id) => peer_id,
+                        None => continue,
+                    };
+                    match acks.iter_mut().find(|ack| ack.peer_id == peer_id) {
+                        Some(ack) => ack.status = Some(status),
+                        None => acks.push(PeerAck {
+                            peer_id,
+                            status: Some(status),
+                        }),
+                    }
+                }
+                Some(msg) => self.send_gossip_msg(message.topic.into_string(), msg)?,
+                None => {}
+            }
+        }
+        if is_subscribed {
+            let _ = self.swarm.behaviour_mut().unsubscribe(topic);
+        }
+        Ok(res.map(|_| acks).map_err(Self::publish_error))
+    }
+
+    // Request the retained messages of the topic from all peers that subscribe to it, and forward
+    // the messages that were not received before to the user task.
+    // Only fails if no peer subscribes to the topic, or all requests failed.
+    async fn catch_up(&mut self, topic: Topic) -> Result<Result<usize, String>, String> {
+        let peers = self.swarm.behaviour().topic_peers(&topic);
+        if peers.is_empty() {
+            return Ok(Err("No known peers are subscribing to that topic.".into()));
+        }
+        let mut pending: HashSet<_> = peers
+            .iter()
+            .map(|peer_id| {
+                self.swarm
+                    .behaviour_mut()
+                    .request_catch_up(peer_id, topic.clone())
+            })
+            .collect();
+        let mut error = None;
+        let mut succeeded = false;
+        let mut count = 0;
+        while !pending.is_empty() {
+            let is_match = |event: &BehaviourEvent| match event {
+                BehaviourEvent::CatchUp(CatchUpEvent { request_id, result })
+                    if pending.contains(request_id) =>
+                {
+                    Some((*request_id, result.clone()))
+                }
+                _ => None,
+            };
+            let (request_id, result) = self.await_event(&is_match).await?;
+            pending.remove(&request_id);
+            let messages = match result {
+                Ok(messages) => messages,
+                Err(err) => {
+                    error = Some(err);
+                    continue;
+                }
+            };
+            succeeded = true;
+            for message in messages {
+                let msg = self.swarm.behaviour_mut().catch_up_message(&topic, message);
+                if let Some(msg) = msg {
+                    self.send_gossip_msg(topic.clone(), msg)?;
+                    count += 1;
+                }
+            }
+        }
+        match error {
+            Some(err) if !succeeded => Ok(Err(err)),
+            _ => Ok(Ok(count)),
+        }
+    }
+
+    // Connect a peer and send it a request for a shared file, then wait for the response.
+    async fn request_file(
+        &mut self,
+        peer_id: PeerId,
+        request: FileRequest,
+    ) -> Result<Result<FileResponse, String>, String> {
+        // The dial is not covered by the command timeout, because fetching a file is not.
+        match future::timeout(self.command_timeout, self.dial_peer(peer_id)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => return Ok(Err(err)),
+            Err(_) => return Ok(Err(format!("Dialing peer {} timed out", peer_id))),
+        }
+        let request_id = self.swarm.behaviour_mut().request_file(&peer_id, request);
+        let is_match = |event: &BehaviourEvent| match event {
+            BehaviourEvent::FileExchange(FileExchangeEvent {
+                request_id: id,
+                result,
+            }) if *id == request_id => Some(result.clone()),
+            _ => None,
+        };
+        self.await_event(&is_match).await
+    }
+
+    // Download a shared file from the peers that provide it, and write it to the path.
+    // The manifest is fetched first and verified against the root hash, then each chunk that is
+    // not yet in the partial file from a previous fetch is requested and verified against the
+    // manifest. If a provider fails, the download continues with the next one.
+    async fn fetch_file(
+        &mut self,
+        root: &str,
+        path: &str,
+    ) -> Result<Result<FetchInfo, String>, String> {
+        let timeout = self.command_timeout;
+        let lookup = self.get_providers(file_exchange::provider_key(root));
+        let providers = match future::timeout(timeout, lookup).await {
+            Ok(res) => match res? {
+                Ok(providers) => providers,
+                Err(err) => return Ok(Err(err)),
+            },
+            Err(_) => {
+                return Ok(Err(format!(
+                    "Looking up the providers of {} timed out",
+                    root
+                )))
+            }
+        };
+        let local_peer_id = *self.swarm.local_peer_id();
+        let mut providers = providers.into_iter().filter(|peer| peer != &local_peer_id);
+        let mut last_err = format!("No provider of {} could be reached", root);
+
+        // Fetch the manifest from the first provider that shares the file.
+        let (mut provider, manifest) = loop {
+            let peer_id = match providers.next() {
+                Some(peer_id) => peer_id,
+                None => return Ok(Err(last_err)),
+            };
+            let request = FileRequest::Manifest { root: root.into() };
+            match self.request_file(peer_id, request).await? {
+                Ok(FileResponse::Manifest(bytes)) => match Manifest::decode(root, &bytes) {
+                    Ok(manifest) => break (peer_id, manifest),
+                    Err(err) => last_err = err,
+                },
+                Ok(_) => last_err = format!("Peer {} does not share {}", peer_id, root),
+                Err(err) => last_err = err,
+            }
+        };
+
+        let mut download = match Download::open(path, manifest.clone()) {
+            Ok(download) => download,
+            Err(err) => return Ok(Err(err)),
+        };
+        let missing = download.missing_chunks();
+        let info = FetchInfo {
+            size: manifest.size,
+            chunks: manifest.chunks.len(),
+            resumed: manifest.chunks.len() - missing.len(),
+        };
+
+        let mut missing = missing.into_iter().peekable();
+        while let Some(&index) = missing.peek() {
+            let request = FileRequest::Chunk {
+                root: root.into(),
+                index,
+            };
+            let res = match self.request_file(provider, request).await? {
+                Ok(FileResponse::Chunk(chunk)) => download.write_chunk(index, &chunk),
+                Ok(_) => Err(format!("Peer {} does not share {}", provider, root)),
+                Err(err) => Err(err),
+            };
+            match res {
+                Ok(()) => {
+                    missing.next();
+                }
+                // The chunks that were received so far are kept in the partial file.
+                Err(err) => match providers.next() {
+                    Some(peer_id) => provider = peer_id,
+                    None => {
+                        return Ok(Err(format!(
+                            "{}. Run fetch again to resume the download",
+                            err
+                        )))
+                    }
+                },
+            }
+        }
+        Ok(download.finish().map(|_| info))
+    }
+
+    // Group the records that were returned by different peers by their value.
+    // Signed records are unwrapped, records with an invalid signature are dropped.
+    fn distinct_records(records: &[PeerRecord]) -> Vec<FoundRecord> {
+        let mut found: Vec<FoundRecord> = Vec::new();
+        for PeerRecord { peer, record } in records {
+            let (record, is_signed) = match signed_record::verify(record) {
+                Ok(Some(value)) => {
+                    let mut record = record.clone();
+                    record.value = value;
+                    (record, true)
+                }
+                Ok(None) => (record.clone(), false),
+                Err(_) => continue,
+            };
+            match found.iter_mut().find(|f| f.record.value == record.value) {
+                Some(found_record) => found_record.peers.push(*peer),
+                None => found.push(FoundRecord {
+                    record,
+                    peers: vec![*peer],
+                    is_signed,
+                }),
+            }
+        }
+        found
+    }
+
+    // Describe why publishing to a topic failed.
+    fn publish_error(error: PublishError) -> String {
+        match error {
+            PublishError::InsufficientPeers => {
+                "No known peers are subscribing to that topic.".into()
+            }
+            e => format!("{:?}", e),
+        }
+    }
+
+    // Forward a gossipsub message via the channel to the user task.
+    // Announced updates are only forwarded if they are queued for installation. Acknowledgments
+    // are only handled while the published command awaits them.
+    fn send_gossip_msg(&mut self, topic: String, message: GossipMessage) -> Result<(), String> {
+        if let GossipMessage::Ack { .. } = &message {
+            return Ok(());
+        }
+        if let GossipMessage::Update(update) = &message {
+            if topic != UPDATES_TOPIC || self.updates.announce(update.clone()).is_err() {
+                return Ok(());
+            }
+            let _ = self.install_tx.unbounded_send(());
+        }
+        self.message_tx
+            .send((topic, message))
+            .map_err(|err| format!("Forwarding gossip message to channel failed: {}", err))
     }
 }
@@ -0,0 +1,843 @@
+// Detection of the reachability of the local node via AutoNAT.
+// The local node asks a connected peer to dial it back at the addresses on which it listens and
+// under which remote peers observed it. If the dial-back succeeds the node is public, if it fails
+// the node is private, e.g. behind a NAT. The other way around, the local node dials back peers
+// that ask it.
+// The libp2p AutoNAT behaviour is not available for the libp2p version that is used, hence the
+// protocol is implemented here on top of the request-response protocol, with the same protocol
+// name and messages.
+
+use super::{
+    responder::{RequestResponder, Responder},
+    transport,
+};
+use crate::{config::AutoNatConfig, types::Reachability};
+use async_std::task;
+use async_trait::async_trait;
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{
+    core::{
+        connection::{ConnectionId, ListenerId},
+        upgrade::{read_one, write_with_len_prefix},
+        ConnectedPoint,
+    },
+    multiaddr::Protocol,
+    request_response::{
+        handler::RequestResponseHandler, ProtocolName, RequestId, RequestResponseCodec,
+    },
+    swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters, ProtocolsHandler},
+    Multiaddr, PeerId,
+};
+use prost::Message;
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    error, io,
+    net::{Ipv4Addr, Ipv6Addr},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+// Number of addresses of the local peer as observed by remote peers that are kept.
+const MAX_OBSERVED_ADDRS: usize = 8;
+
+// Number of addresses of a request that are dialed back at most.
+const MAX_DIAL_BACK_ADDRS: usize = 4;
+
+// Number of dial-backs that run at the same time at most, further requests are refused.
+const MAX_PENDING_DIAL_BACKS: usize = 8;
+
+// A dial-back that did not succeed within the timeout failed. Dials that are rejected right away,
+// e.g. due to the connection limits, are not reported by the swarm.
+const DIAL_BACK_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Requests of the local peer fail if they are not answered within the timeout. Remote peers answer
+// once their dial-back succeeded or failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Delay before the next probe if the remote peer did not answer or refused to dial back.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Messages of the protocol, encoded with protobuf:
+// https://github.com/libp2p/specs/blob/master/autonat/README.md
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Message {
+        #[prost(enumeration = "MessageType", optional, tag = "1")]
+        pub r#type: Option<i32>,
+        #[prost(message, optional, tag = "2")]
+        pub dial: Option<Dial>,
+        #[prost(message, optional, tag = "3")]
+        pub dial_response: Option<DialResponse>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MessageType {
+        Dial = 0,
+        DialResponse = 1,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PeerInfo {
+        #[prost(bytes, optional, tag = "1")]
+        pub id: Option<Vec<u8>>,
+        #[prost(bytes, repeated, tag = "2")]
+        pub addrs: Vec<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Dial {
+        #[prost(message, optional, tag = "1")]
+        pub peer: Option<PeerInfo>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DialResponse {
+        #[prost(enumeration = "super::ResponseStatus", optional, tag = "1")]
+        pub status: Option<i32>,
+        #[prost(string, optional, tag = "2")]
+        pub status_text: Option<String>,
+        #[prost(bytes, optional, tag = "3")]
+        pub addr: Option<Vec<u8>>,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AutoNatProtocol;
+
+impl ProtocolName for AutoNatProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/libp2p/autonat/1.0.0"
+    }
+}
+
+// Request to dial the peer back at one of the addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialRequest {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ResponseStatus {
+    Ok = 0,
+    // None of the addresses could be dialed.
+    DialError = 100,
+    // The remote peer did not dial back, e.g. because it had no address to dial.
+    DialRefused = 101,
+    BadRequest = 200,
+    InternalError = 300,
+}
+
+// Address at which the remote peer dialed back, or the status with the reason why it did not.
+pub type DialResponse = Result<Multiaddr, (ResponseStatus, String)>;
+
+// Codec for the AutoNAT protocol, messages are protobuf encoded with a length prefix.
+#[derive(Debug, Clone, Default)]
+pub struct AutoNatCodec;
+
+fn invalid_data(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+async fn read_message<T>(io: &mut T) -> io::Result<proto::Message>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let bytes = read_one(io, MAX_MESSAGE_SIZE).await.map_err(invalid_data)?;
+    proto::Message::decode(bytes.as_slice()).map_err(invalid_data)
+}
+
+async fn write_message<T>(io: &mut T, message: proto::Message) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let mut bytes = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut bytes).map_err(invalid_data)?;
+    write_with_len_prefix(io, bytes).await
+}
+
+#[async_trait]
+impl RequestResponseCodec for AutoNatCodec {
+    type Protocol = AutoNatProtocol;
+    type Request = DialRequest;
+    type Response = DialResponse;
+
+    // Addresses that can't be decoded are skipped.
+    async fn read_request<T>(&mut self, _: &AutoNatProtocol, io: &mut T) -> io::Result<DialRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = read_message(io).await?;
+        let peer = match (message.r#type, message.dial) {
+            (Some(0), Some(proto::Dial { peer: Some(peer) })) => peer,
+            _ => return Err(invalid_data("Expected a dial request")),
+        };
+        let peer_id = peer
+            .id
+            .and_then(|id| PeerId::from_bytes(&id).ok())
+            .ok_or_else(|| invalid_data("Invalid peer id"))?;
+        let addrs = peer
+            .addrs
+            .into_iter()
+            .filter_map(|addr| Multiaddr::try_from(addr).ok())
+            .collect();
+        Ok(DialRequest { peer_id, addrs })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &AutoNatProtocol,
+        io: &mut T,
+    ) -> io::Result<DialResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = read_message(io).await?;
+        let response = match (message.r#type, message.dial_response) {
+            (Some(1), Some(response)) => response,
+            _ => return Err(invalid_data("Expected a dial response")),
+        };
+        let status = response
+            .status
+            .and_then(ResponseStatus::from_i32)
+            .ok_or_else(|| invalid_data("Invalid response status"))?;
+        match status {
+            ResponseStatus::Ok => response
+                .addr
+                .and_then(|addr| Multiaddr::try_from(addr).ok())
+                .map(Ok)
+                .ok_or_else(|| invalid_data("Invalid address")),
+            status => Ok(Err((status, response.status_text.unwrap_or_default()))),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &AutoNatProtocol,
+        io: &mut T,
+        request: DialRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let peer = proto::PeerInfo {
+            id: Some(request.peer_id.to_bytes()),
+            addrs: request
+                .addrs
+                .into_iter()
+                .map(|addr| addr.to_vec())
+                .collect(),
+        };
+        let message = proto::Message {
+            r#type: Some(proto::MessageType::Dial as i32),
+            dial: Some(proto::Dial { peer: Some(peer) }),
+            dial_response: None,
+        };
+        write_message(io, message).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &AutoNatProtocol,
+        io: &mut T,
+        response: DialResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let response = match response {
+            Ok(addr) => proto::DialResponse {
+                status: Some(ResponseStatus::Ok as i32),
+                status_text: None,
+                addr: Some(addr.to_vec()),
+            },
+            Err((status, text)) => proto::DialResponse {
+                status: Some(status as i32),
+                status_text: Some(text),
+                addr: None,
+            },
+        };
+        let message = proto::Message {
+            r#type: Some(proto::MessageType::DialResponse as i32),
+            dial: None,
+            dial_response: Some(response),
+        };
+        write_message(io, message).await
+    }
+}
+
+// Dial-back for a request of a remote peer.
+struct PendingDialBack {
+    request_id: RequestId,
+    peer_id: PeerId,
+    // Addresses that were dialed and did not fail yet.
+    addrs: Vec<Multiaddr>,
+    // Address at which the peer was dialed back.
+    dialed: Option<Multiaddr>,
+    deadline: Instant,
+}
+
+// Outcome of a probe of the local peer.
+#[derive(Debug)]
+pub struct ProbeResult {
+    request_id: RequestId,
+    result: Result<DialResponse, String>,
+}
+
+// Server side of the protocol, that dials back the peers that ask for it.
+pub struct DialBack {
+    enabled: bool,
+    only_global_ips: bool,
+    // Remote addresses of the connections to each peer that were not relayed.
+    direct: HashMap<PeerId, Vec<(ConnectionId, Multiaddr)>>,
+    pending: Vec<PendingDialBack>,
+    // Addresses that are dialed on the next poll.
+    dials: VecDeque<Multiaddr>,
+    responses: VecDeque<(RequestId, DialResponse)>,
+    // Timer that wakes up the task at the earliest deadline of the dial-backs.
+    timer: Option<(Instant, BoxFuture<'static, ()>)>,
+}
+
+impl DialBack {
+    fn new(config: &AutoNatConfig) -> Self {
+        DialBack {
+            enabled: config.server,
+            only_global_ips: config.only_global_ips,
+            direct: HashMap::new(),
+            pending: Vec::new(),
+            dials: VecDeque::new(),
+            responses: VecDeque::new(),
+            timer: None,
+        }
+    }
+
+    // Peers that are connected directly, in the order of their peer ids.
+    fn servers(&self) -> Vec<PeerId> {
+        let mut servers: Vec<PeerId> = self.direct.keys().cloned().collect();
+        servers.sort();
+        servers
+    }
+
+    fn connection_established(&mut self, peer_id: &PeerId, id: &ConnectionId, remote: &Multiaddr) {
+        self.direct
+            .entry(*peer_id)
+            .or_default()
+            .push((*id, remote.clone()));
+    }
+
+    fn connection_closed(&mut self, peer_id: &PeerId, id: &ConnectionId) {
+        if let Some(connections) = self.direct.get_mut(peer_id) {
+            connections.retain(|(connection, _)| connection != id);
+            if connections.is_empty() {
+                self.direct.remove(peer_id);
+            }
+        }
+    }
+
+    // The dial-back to the address succeeded if the connection was established to the peer that
+    // requested it.
+    fn dialed(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        for pending in self.pending.iter_mut() {
+            if !pending.addrs.contains(addr) {
+                continue;
+            }
+            match &pending.peer_id == peer_id {
+                true => pending.dialed = Some(addr.clone()),
+                false => pending.addrs.retain(|a| a != addr),
+            }
+        }
+    }
+
+    fn reach_failure(&mut self, addr: &Multiaddr) {
+        for pending in self.pending.iter_mut() {
+            pending.addrs.retain(|a| a != addr);
+        }
+    }
+
+    // Answer the dial-backs that succeeded, failed for all addresses or timed out, and register the
+    // timer for the next deadline.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.pending.len() {
+            let pending = &self.pending[i];
+            let error = |text: &str| Err((ResponseStatus::DialError, text.into()));
+            let response = match &pending.dialed {
+                Some(addr) => Ok(addr.clone()),
+                None if pending.addrs.is_empty() => error("Dial-back failed"),
+                None if pending.deadline <= now => error("Dial-back timed out"),
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            self.responses.push_back((pending.request_id, response));
+            self.pending.remove(i);
+        }
+        let next = match self.pending.iter().map(|pending| pending.deadline).min() {
+            Some(next) => next,
+            None => {
+                self.timer = None;
+                return;
+            }
+        };
+        if !matches!(&self.timer, Some((deadline, _)) if *deadline == next) {
+            let delay = next.saturating_duration_since(now);
+            self.timer = Some((next, task::sleep(delay).boxed()));
+        }
+        if let Some((_, timer)) = &mut self.timer {
+            if timer.poll_unpin(cx).is_ready() {
+                self.timer = None;
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+}
+
+impl Responder for DialBack {
+    type Codec = AutoNatCodec;
+    type OutEvent = ProbeResult;
+
+    fn protocol() -> AutoNatProtocol {
+        AutoNatProtocol
+    }
+
+    // Dial back the peer at the addresses of the request, the response is returned once a dial
+    // succeeded or all of them failed.
+    fn respond(
+        &mut self,
+        peer_id: &PeerId,
+        request_id: RequestId,
+        request: DialRequest,
+    ) -> Option<DialResponse> {
+        let refuse = |text: &str| Some(Err((ResponseStatus::DialRefused, text.into())));
+        if !self.enabled {
+            return refuse("Dial-backs are disabled");
+        }
+        if &request.peer_id != peer_id {
+            let text = "Peer id does not match the connection".into();
+            return Some(Err((ResponseStatus::BadRequest, text)));
+        }
+        let observed = match self.direct.get(peer_id).and_then(|c| c.first()) {
+            Some((_, observed)) => observed,
+            None => return refuse("Only relayed connections to the peer"),
+        };
+        let addrs = dial_back_addrs(request.addrs, observed, self.only_global_ips);
+        if addrs.is_empty() {
+            return refuse("No address to dial back");
+        }
+        if self.pending.len() >= MAX_PENDING_DIAL_BACKS {
+            return refuse("Too many dial-backs");
+        }
+        self.dials.extend(addrs.iter().cloned());
+        self.pending.push(PendingDialBack {
+            request_id,
+            peer_id: *peer_id,
+            addrs,
+            dialed: None,
+            deadline: Instant::now() + DIAL_BACK_TIMEOUT,
+        });
+        None
+    }
+
+    fn poll_response(&mut self, cx: &mut Context<'_>) -> Poll<(RequestId, DialResponse)> {
+        self.poll_pending(cx);
+        match self.responses.pop_front() {
+            Some(response) => Poll::Ready(response),
+            None => Poll::Pending,
+        }
+    }
+
+    fn event(request_id: RequestId, result: Result<DialResponse, String>) -> ProbeResult {
+        ProbeResult { request_id, result }
+    }
+}
+
+// Addresses of a request that are dialed back. Relayed addresses are skipped, and the IP of the
+// other addresses is replaced by the IP from which the peer connected, so that peers can't use the
+// local node to dial other hosts.
+// Without IP, e.g. for the memory transport in tests, the addresses are dialed as they are.
+fn dial_back_addrs(
+    addrs: Vec<Multiaddr>,
+    observed: &Multiaddr,
+    only_global_ips: bool,
+) -> Vec<Multiaddr> {
+    let observed_ip = observed
+        .iter()
+        .next()
+        .filter(|p| matches!(p, Protocol::Ip4(_) | Protocol::Ip6(_)));
+    if only_global_ips && matches!(&observed_ip, Some(ip) if !is_global(ip)) {
+        return Vec::new();
+    }
+    let mut dial_back: Vec<Multiaddr> = Vec::new();
+    for addr in addrs.into_iter().filter(|a| !transport::is_relayed(a)) {
+        let mut protocols = addr.iter();
+        let first = protocols.next();
+        let is_ip = matches!(first, Some(Protocol::Ip4(_)) | Some(Protocol::Ip6(_)));
+        let addr = match &observed_ip {
+            Some(ip) if is_ip => std::iter::once(ip.clone()).chain(protocols).collect(),
+            None if !is_ip => addr.clone(),
+            _ => continue,
+        };
+        if !dial_back.contains(&addr) && dial_back.len() < MAX_DIAL_BACK_ADDRS {
+            dial_back.push(addr);
+        }
+    }
+    dial_back
+}
+
+// Whether the IP is routable in the internet.
+fn is_global(ip: &Protocol) -> bool {
+    match ip {
+        Protocol::Ip4(ip) => !is_local_ipv4(ip),
+        Protocol::Ip6(ip) => !is_local_ipv6(ip),
+        _ => false,
+    }
+}
+
+fn is_local_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // Shared address space of carrier-grade NATs.
+    let is_shared = a == 100 && (b & 0xc0) == 64;
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || is_shared
+}
+
+fn is_local_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // Unique local and link-local addresses.
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+// Network behaviour that probes the reachability of the local peer, and dials back remote peers
+// that probe theirs.
+// One connected peer at a time is asked to dial back, in turns. The reachability is that of the
+// last answered probe: public if the dial-back succeeded, private if it failed. Probes that were
+// refused or not answered don't change it.
+pub struct AutoNat {
+    inner: RequestResponder<DialBack>,
+    local_peer_id: PeerId,
+    // Time between two probes, probing is disabled if None.
+    probe_interval: Option<Duration>,
+    // Time at which the next probe is sent, once a peer and an address to probe are known.
+    next_probe: Instant,
+    // Probe that waits for its response.
+    probe: Option<RequestId>,
+    // Number of sent probes, to ask the connected peers in turns.
+    probes: usize,
+    // Addresses of the local peer as observed by remote peers, most recent last.
+    observed_addrs: Vec<Multiaddr>,
+    reachability: Reachability,
+    // Timer that wakes up the task once the next probe is due.
+    timer: Option<(Instant, BoxFuture<'static, ()>)>,
+}
+
+impl AutoNat {
+    pub fn new(local_peer_id: PeerId, config: &AutoNatConfig) -> Self {
+        AutoNat {
+            inner: RequestResponder::new(DialBack::new(config), REQUEST_TIMEOUT),
+            local_peer_id,
+            probe_interval: config.probe_interval(),
+            next_probe: Instant::now(),
+            probe: None,
+            probes: 0,
+            observed_addrs: Vec::new(),
+            reachability: Reachability::Unknown,
+            timer: None,
+        }
+    }
+
+    pub fn reachability(&self) -> Reachability {
+        self.reachability.clone()
+    }
+
+    pub fn observed_addrs(&self) -> Vec<Multiaddr> {
+        self.observed_addrs.clone()
+    }
+
+    // Add an address under which a remote peer observed the local peer. It is probed with the next
+    // probe, right away while the reachability is unknown.
+    pub fn add_observed_addr(&mut self, addr: Multiaddr) {
+        self.probe_unknown();
+        self.observed_addrs.retain(|a| a != &addr);
+        self.observed_addrs.push(addr);
+        if self.observed_addrs.len() > MAX_OBSERVED_ADDRS {
+            self.observed_addrs.remove(0);
+        }
+    }
+
+    // Probe right away if the reachability is unknown, e.g. because no address to probe was known
+    // when the last probe was refused.
+    fn probe_unknown(&mut self) {
+        if self.reachability == Reachability::Unknown && self.probe.is_none() {
+            self.next_probe = Instant::now();
+        }
+    }
+
+    // Update the reachability with the response to a probe, and schedule the next one.
+    fn probe_finished(&mut self, result: ProbeResult) {
+        if self.probe != Some(result.request_id) {
+            return;
+        }
+        self.probe = None;
+        let interval = self.probe_interval.unwrap_or(RETRY_INTERVAL);
+        let delay = match result.result {
+            Ok(Ok(addr)) => {
+                self.reachability = Reachability::Public(addr);
+                interval
+            }
+            Ok(Err((ResponseStatus::DialError, _))) => {
+                self.reachability = Reachability::Private;
+                interval
+            }
+            Ok(Err(_)) | Err(_) => RETRY_INTERVAL.min(interval),
+        };
+        self.next_probe = Instant::now() + delay;
+    }
+
+    // Send a probe to the next connected peer once it is due.
+    // Returns true if a probe was sent.
+    fn poll_probe(&mut self, cx: &mut Context<'_>, params: &mut impl PollParameters) -> bool {
+        if self.probe_interval.is_none() || self.probe.is_some() {
+            return false;
+        }
+        let now = Instant::now();
+        if self.next_probe > now {
+            if !matches!(&self.timer, Some((deadline, _)) if *deadline == self.next_probe) {
+                let delay = self.next_probe.saturating_duration_since(now);
+                self.timer = Some((self.next_probe, task::sleep(delay).boxed()));
+            }
+            if let Some((_, timer)) = &mut self.timer {
+                if timer.poll_unpin(cx).is_ready() {
+                    self.timer = None;
+                    cx.waker().wake_by_ref();
+                }
+            }
+            return false;
+        }
+        let mut addrs: Vec<Multiaddr> = Vec::new();
+        let candidates = params
+            .listened_addresses()
+            .chain(self.observed_addrs.iter().rev().cloned());
+        for addr in candidates.filter(|a| !transport::is_relayed(a)) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        let servers = self.inner.responder().servers();
+        if addrs.is_empty() || servers.is_empty() {
+            return false;
+        }
+        let server = servers[self.probes % servers.len()];
+        self.probes += 1;
+        let request = DialRequest {
+            peer_id: self.local_peer_id,
+            addrs,
+        };
+        self.probe = Some(self.inner.request(&server, request));
+        true
+    }
+}
+
+impl NetworkBehaviour for AutoNat {
+    type ProtocolsHandler = RequestResponseHandler<AutoNatCodec>;
+    type OutEvent = ();
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let (remote, is_relayed) = match endpoint {
+            ConnectedPoint::Dialer { address } => {
+                self.inner.responder_mut().dialed(peer_id, address);
+                (address, transport::is_relayed(address))
+            }
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => (send_back_addr, transport::is_relayed(local_addr)),
+        };
+        // Peers that are connected directly can be asked to dial back.
+        if !is_relayed {
+            self.inner
+                .responder_mut()
+                .connection_established(peer_id, id, remote);
+            self.probe_unknown();
+        }
+        self.inner
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner.responder_mut().connection_closed(peer_id, id);
+        self.inner.inject_connection_closed(peer_id, id, endpoint)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        id: ConnectionId,
+        event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.inner.inject_event(peer_id, id, event)
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        if peer_id.is_none() {
+            self.inner.responder_mut().reach_failure(addr);
+        }
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+        self.probe_unknown();
+        self.inner.inject_new_listen_addr(id, addr)
+    }
+
+    // Dial the addresses of dial-backs, and send probes.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<<Self::ProtocolsHandler as ProtocolsHandler>::InEvent, ()>>
+    {
+        loop {
+            if let Some(address) = self.inner.responder_mut().dials.pop_front() {
+                return Poll::Ready(NetworkBehaviourAction::DialAddress { address });
+            }
+            match self.inner.poll(cx, params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(result)) => {
+                    self.probe_finished(result);
+                    continue;
+                }
+                Poll::Ready(action) => {
+                    return Poll::Ready(action.map_out(|_| unreachable!("Event was matched")))
+                }
+                Poll::Pending => {}
+            }
+            if !self.poll_probe(cx, params) {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[test]
+    fn dial_back_addrs_use_the_observed_ip() {
+        let addr = |s: &str| s.parse::<Multiaddr>().unwrap();
+        let addrs = vec![
+            addr("/ip4/192.168.1.2/tcp/4001"),
+            addr("/ip6/::1/tcp/4002"),
+            addr("/ip4/203.0.113.7/tcp/4001/p2p/12D3KooWBJTyq5sNop5PSkw5Yvc56t3HGgVQ27SYrwUvYLY42EW4/p2p-circuit"),
+            addr("/dns4/example.com/tcp/4001"),
+        ];
+        let observed = addr("/ip4/198.51.100.3/tcp/53012");
+        assert_eq!(
+            dial_back_addrs(addrs.clone(), &observed, false),
+            vec![
+                addr("/ip4/198.51.100.3/tcp/4001"),
+                addr("/ip4/198.51.100.3/tcp/4002")
+            ]
+        );
+
+        // Peers in the same LAN are not dialed back.
+        let observed = addr("/ip4/192.168.1.2/tcp/53012");
+        assert!(dial_back_addrs(addrs.clone(), &observed, true).is_empty());
+        assert_eq!(dial_back_addrs(addrs, &observed, false).len(), 2);
+
+        let addrs = vec![addr("/memory/1234"), addr("/ip4/192.168.1.2/tcp/4001")];
+        assert_eq!(
+            dial_back_addrs(addrs, &addr("/memory/5678"), true),
+            vec![addr("/memory/1234")]
+        );
+    }
+
+    #[test]
+    fn messages_are_decoded_as_encoded() {
+        task::block_on(async {
+            let request = DialRequest {
+                peer_id: PeerId::random(),
+                addrs: vec!["/ip4/203.0.113.7/tcp/4001".parse().unwrap()],
+            };
+            let mut io = Cursor::new(Vec::new());
+            AutoNatCodec
+                .write_request(&AutoNatProtocol, &mut io, request.clone())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let decoded = AutoNatCodec
+                .read_request(&AutoNatProtocol, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(decoded, request);
+
+            let responses = vec![
+                Ok(request.addrs[0].clone()),
+                Err((ResponseStatus::DialError, "Dial-back failed".into())),
+            ];
+            for response in responses {
+                let mut io = Cursor::new(Vec::new());
+                AutoNatCodec
+                    .write_response(&AutoNatProtocol, &mut io, response.clone())
+                    .await
+                    .unwrap();
+                io.set_position(0);
+                let decoded = AutoNatCodec
+                    .read_response(&AutoNatProtocol, &mut io)
+                    .await
+                    .unwrap();
+                assert_eq!(decoded, response);
+            }
+        })
+    }
+}
@@ -0,0 +1,72 @@
+use libp2p::PeerId;
+use std::{collections::BTreeSet, fs, io::ErrorKind, str::FromStr};
+
+// List of banned peers, that is persisted to a file if a path is configured.
+// The file contains one peer id per line.
+pub struct BanList {
+    path: Option<String>,
+    peers: BTreeSet<PeerId>,
+}
+
+impl BanList {
+    // Load the ban list from the file, start with an empty list if the file does not exist yet.
+    pub fn load(path: Option<String>) -> Result<Self, String> {
+        let mut peers = BTreeSet::new();
+        if let Some(path) = &path {
+            let content = match fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(format!("Failed to read ban list {}: {}", path, e)),
+            };
+            for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                let peer = PeerId::from_str(line)
+                    .map_err(|_| format!("Invalid peer id {} in ban list {}", line, path))?;
+                peers.insert(peer);
+            }
+        }
+        Ok(BanList { path, peers })
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+
+    // Add a peer to the list and persist it.
+    // Returns false if the peer was already banned. The list is unchanged if persisting it failed.
+    pub fn insert(&mut self, peer: PeerId) -> Result<bool, String> {
+        if !self.peers.insert(peer) {
+            return Ok(false);
+        }
+        if let Err(err) = self.save() {
+            self.peers.remove(&peer);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    // Remove a peer from the list and persist it.
+    // Returns false if the peer was not banned. The list is unchanged if persisting it failed.
+    pub fn remove(&mut self, peer: &PeerId) -> Result<bool, String> {
+        if !self.peers.remove(peer) {
+            return Ok(false);
+        }
+        if let Err(err) = self.save() {
+            self.peers.insert(*peer);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let content: String = self.peers.iter().map(|p| format!("{}\n", p)).collect();
+        fs::write(path, content).map_err(|e| format!("Failed to write ban list {}: {}", path, e))
+    }
+}
@@ -1,21 +1,52 @@
-use super::transport::TransportLayer;
-use crate::types::*;
+use super::{
+    autonat::AutoNat,
+    ban_list::BanList,
+    catch_up::{
+        self, CatchUp, CatchUpEvent, CatchUpRequest, RetainedMessage, Retention, SignatureCapture,
+    },
+    encryption::TopicCiphers,
+    file_exchange::{FileExchange, FileExchangeEvent, FileRequest, SharedFiles},
+    peers::{Peers, PeersEvent},
+    rate_limit::RateLimiter,
+    rendezvous::{Rendezvous, RendezvousEvent},
+    signed_record::{self, ValidatingKademlia},
+    transport::{self, TransportLayer},
+};
+use crate::{config::Config, types::*};
 use async_std::task::{Context, Poll};
 use libp2p::{
+    core::{connection::ListenerId, network::ConnectionLimits},
     gossipsub::{
         error::{PublishError, SubscriptionError},
-        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic, MessageAuthenticity,
-        MessageId,
+        subscription_filter::AllowAllSubscriptionFilter,
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic,
+        MessageAcceptance, MessageAuthenticity, MessageId,
     },
+    identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo},
+    identity::Keypair,
     kad::{
-        record::{store::MemoryStore, Key as RecordKey},
-        store::Error as StoreError,
-        Kademlia, KademliaEvent, QueryId, Quorum, Record,
+        record::Key as RecordKey,
+        store::{Error as StoreError, MemoryStore},
+        Kademlia, KademliaConfig, KademliaEvent, QueryId, Quorum, Record,
     },
     mdns::{Mdns, MdnsConfig, MdnsEvent},
-    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters, Swarm},
-    NetworkBehaviour,
+    multiaddr::Protocol,
+    relay::Relay,
+    request_response::RequestId,
+    swarm::{
+        toggle::Toggle, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters,
+        Swarm, SwarmBuilder,
+    },
+    Multiaddr, NetworkBehaviour, PeerId,
 };
+use std::time::{Duration, Instant};
+
+// Protocol version that is announced to other peers via the identify protocol.
+const PROTOCOL_VERSION: &str = "/p2p-network/1.0.0";
+
+// Maximum size of a Kademlia message, including the record key (twice), the publisher and the
+// protobuf encoding. Larger records would be rejected by the remote peers.
+const MAX_KADEMLIA_MESSAGE_SIZE: usize = 16 * 1024;
 
 // Out-event that may be returned when polling the Behaviour.
 // Created from Kademlia or Gossibsub event that emerged in the Swarm.
@@ -23,20 +54,57 @@ use libp2p::{
 pub enum BehaviourEvent {
     Kademlia(KademliaEvent),
     Gossipsub(GossipsubEvent),
+    Rendezvous(RendezvousEvent),
+    FileExchange(FileExchangeEvent),
+    CatchUp(CatchUpEvent),
 }
 
-// Create a Network Behaviou structure that combines the protocols mdns, kademlia and gossibsub.
+// Create a Network Behaviou structure that combines the protocols mdns, kademlia, gossibsub,
+// identify, circuit relay, AutoNAT, rendezvous, file exchange and catch-up.
 // Based on the Behaviour a swarm is created, as entrypoint for all network interaction.
 // Polling the Swarm for events returns a libp2p::swarm::SwarmEvent, in case of a Gossipsub or
 // Kademlia event, the respective `BehaviourEvent` for it is issued.
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "BehaviourEvent", poll_method = "poll")]
 pub struct Behaviour {
-    mdns: Mdns,
-    kademlia: Kademlia<MemoryStore>,
-    gossipsub: Gossipsub,
+    // Disabled if mDNS is turned off in the config.
+    mdns: Toggle<Mdns>,
+    kademlia: ValidatingKademlia,
+    gossipsub: Gossipsub<SignatureCapture, AllowAllSubscriptionFilter>,
+    // Connected peers, and pinned peers that are redialed.
+    peers: Peers,
+    // Exchange of listening and observed addresses with connected peers.
+    identify: Identify,
+    // Disabled if the relay protocol is turned off in the config.
+    relay: Toggle<Relay>,
+    // Detection of the reachability of the local peer via dial-backs of connected peers.
+    autonat: AutoNat,
+    // Registration and discovery of peers at a rendezvous point.
+    rendezvous: Rendezvous,
+    // Transfer of shared files in chunks.
+    file_exchange: FileExchange,
+    // Retained gossip messages for peers that subscribed late.
+    catch_up: CatchUp,
     #[behaviour(ignore)]
     is_bootstrapped: bool,
+    // Default time-to-live of records that are published by the local peer.
+    #[behaviour(ignore)]
+    record_ttl: Option<Duration>,
+    // Keypair of the local peer to sign records.
+    #[behaviour(ignore)]
+    keypair: Keypair,
+    // Signatures of the received gossipsub messages, with which they are retained.
+    #[behaviour(ignore)]
+    signatures: SignatureCapture,
+    // Peers that are banned, they are neither added as gossipsub nor as kademlia peers.
+    #[behaviour(ignore)]
+    ban_list: BanList,
+    // Limits on published and received gossip messages.
+    #[behaviour(ignore)]
+    rate_limiter: RateLimiter,
+    // Keys of the topics whose messages are encrypted.
+    #[behaviour(ignore)]
+    ciphers: TopicCiphers,
     #[behaviour(ignore)]
     events: Vec<BehaviourEvent>,
 }
@@ -45,9 +113,20 @@ impl Behaviour {
     // Create a new Behaviour use it together with the provided transport to build a Swarm.
     // The behaviour methods can be accessed from a swarm struct via Swarm.behaviour,
     // additionally the methods from libp2p::Swarm for dialing, listening, etc. can be used.
-    pub async fn build_swarm(transport: TransportLayer) -> Swarm<Behaviour> {
+    pub async fn build_swarm(
+        transport: TransportLayer,
+        config: &Config,
+    ) -> Result<Swarm<Behaviour>, String> {
+        // Load the peers that were banned in previous runs.
+        let ban_list = BanList::load(config.peers.ban_list_file.clone())?;
+        let rate_limiter = RateLimiter::new(config.rate_limits.clone())?;
+        let ciphers = TopicCiphers::new(&config.encryption)?;
+
+        // Build the transport, and the relay behaviour if the relay protocol is enabled.
+        let (boxed_transport, relay) = transport.build().await;
+
         // Create the network behaviour
-        let behaviour = Behaviour::new(&transport)
+        let behaviour = Behaviour::new(&transport, config, ban_list, rate_limiter, ciphers, relay)
             .await
             .expect("Failed to create Network Behaviour.");
 
@@ -55,8 +134,186 @@ impl Behaviour {
         // for authentication on the transport.
         let peer_id = transport.local_peer_id();
 
+        // Limit the number of connections that the swarm accepts or establishes.
+        let limits = &config.limits;
+        let connection_limits = ConnectionLimits::default()
+            .with_max_established_incoming(limits.max_established_incoming)
+            .with_max_established_outgoing(limits.max_established_outgoing)
+            .with_max_established_per_peer(limits.max_established_per_peer)
+            .with_max_pending_incoming(limits.max_pending_incoming)
+            .with_max_pending_outgoing(limits.max_pending_outgoing);
+
         // Create a swarm.
-        Swarm::new(transport.build().await, behaviour, peer_id)
+        let mut swarm = SwarmBuilder::new(boxed_transport, behaviour, peer_id)
+            .connection_limits(connection_limits)
+            .build();
+
+        // Reject connections from and to banned peers.
+        let banned: Vec<PeerId> = swarm.behaviour().ban_list.iter().cloned().collect();
+        for peer_id in banned {
+            swarm.ban_peer_id(peer_id);
+        }
+
+        // Dial the pinned peers once the swarm is polled.
+        for addr in config.peers.pinned.iter() {
+            swarm.behaviour_mut().peers.pin(addr.clone());
+        }
+        Ok(swarm)
+    }
+
+    // Add a known peer to the kademlia routing table and as gossipsub peer.
+    // Bootstrap kademlia if this is the first peer.
+    // The bootrapping process introduces the local peer to the kademlia DHT by adding
+    // the local peers information to the routing table of the closest peers, and adding
+    // their information to the local routing table.
+    // Banned peers are ignored.
+    pub fn add_peer(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        if self.ban_list.contains(&peer_id) {
+            return;
+        }
+        self.kademlia.add_address(&peer_id, addr);
+        self.gossipsub.add_explicit_peer(&peer_id);
+        if !self.is_bootstrapped {
+            self.is_bootstrapped = self.kademlia.bootstrap().is_ok();
+        }
+    }
+
+    // Remove a peer from the kademlia routing table and the gossipsub peers, so that
+    // neither protocol dials it again.
+    // This does not close existing connections.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.kademlia.remove_peer(peer_id);
+        self.gossipsub.remove_explicit_peer(peer_id);
+    }
+
+    // Add a peer to the persisted ban list, remove it as known peer and reject its gossipsub
+    // messages.
+    // Returns false if the peer was already banned. Nothing changes if the ban list could not be
+    // persisted.
+    pub fn ban_peer(&mut self, peer_id: PeerId) -> Result<bool, String> {
+        let is_new = self.ban_list.insert(peer_id)?;
+        self.remove_peer(&peer_id);
+        self.gossipsub.blacklist_peer(&peer_id);
+        Ok(is_new)
+    }
+
+    // Remove a peer from the ban list.
+    // Returns false if the peer was not banned. Nothing changes if the ban list could not be
+    // persisted.
+    pub fn unban_peer(&mut self, peer_id: &PeerId) -> Result<bool, String> {
+        let was_banned = self.ban_list.remove(peer_id)?;
+        self.gossipsub.remove_blacklisted_peer(peer_id);
+        Ok(was_banned)
+    }
+
+    // Pin a peer so that it is redialed whenever its connection closes.
+    // Returns false if the address was already pinned.
+    pub fn pin_peer(&mut self, addr: Multiaddr) -> bool {
+        self.peers.pin(addr)
+    }
+
+    // Stop redialing a pinned peer.
+    // Returns false if the address was not pinned.
+    pub fn unpin_peer(&mut self, addr: &Multiaddr) -> bool {
+        self.peers.unpin(addr)
+    }
+
+    // Overview of the connected, pinned and banned peers, and the reachability of the local peer.
+    // The listening addresses are only known to the swarm and have to be set by the caller.
+    pub fn peers_info(&self) -> PeersInfo {
+        PeersInfo {
+            connected: self.peers.connected(),
+            pinned: self.peers.pinned(),
+            banned: self.ban_list.iter().cloned().collect(),
+            listen_addrs: Vec::new(),
+            observed_addrs: self.autonat.observed_addrs(),
+            reachability: self.autonat.reachability(),
+        }
+    }
+
+    // Listeners that closed since the last call.
+    pub fn take_closed_listeners(&mut self) -> Vec<ListenerId> {
+        self.peers.take_closed_listeners()
+    }
+
+    // Register the local peer under a namespace at a connected rendezvous point.
+    pub fn register(&mut self, point: PeerId, namespace: String, ttl: Duration) {
+        self.rendezvous.register(point, namespace, ttl)
+    }
+
+    pub fn unregister(&mut self, point: PeerId, namespace: String) {
+        self.rendezvous.unregister(point, namespace)
+    }
+
+    // Query a connected rendezvous point for the peers that registered under a namespace.
+    pub fn discover(&mut self, point: PeerId, namespace: String) {
+        self.rendezvous.discover(point, namespace)
+    }
+
+    // Share a file via the file exchange protocol.
+    // Returns the root hash of the file, under which the local peer has to be announced as
+    // provider.
+    pub fn share_file(&mut self, path: &str) -> Result<String, String> {
+        self.file_exchange.responder_mut().share(path)
+    }
+
+    // Request the manifest or a chunk of a shared file from a remote peer.
+    // The response is returned as BehaviourEvent::FileExchange.
+    pub fn request_file(&mut self, peer_id: &PeerId, request: FileRequest) -> RequestId {
+        self.file_exchange.request(peer_id, request)
+    }
+
+    // Peers that are known to subscribe to the topic.
+    pub fn topic_peers(&self, topic: &str) -> Vec<PeerId> {
+        let topic_hash = IdentTopic::new(topic).hash();
+        self.gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic_hash))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    // Request the retained messages of a topic from a remote peer.
+    // The response is returned as BehaviourEvent::CatchUp.
+    pub fn request_catch_up(&mut self, peer_id: &PeerId, topic: String) -> RequestId {
+        self.catch_up.request(peer_id, CatchUpRequest { topic })
+    }
+
+    // Verify and decode a message that a remote peer retained, and retain it locally if the topic
+    // has a retention policy.
+    // Returns None if the message was not signed by its source, was already received, or is no
+    // GossipMessage.
+    pub fn catch_up_message(
+        &mut self,
+        topic: &str,
+        message: RetainedMessage,
+    ) -> Option<GossipMessage> {
+        let (source, data) = match message.verify(topic) {
+            Ok(verified) => verified,
+            Err(err) => {
+                println!("Dropped retained message from {}: {}.", message.source, err);
+                return None;
+            }
+        };
+        let msg = self.decode_data(topic, Some(source), data)?;
+        match self
+            .catch_up
+            .responder_mut()
+            .record(topic, msg.kind(), message)
+        {
+            true => Some(msg),
+            false => None,
+        }
+    }
+
+    // Check if publishing to the topic is within the rate limit.
+    pub fn check_publish_rate(&mut self, topic: &str) -> Result<(), String> {
+        self.rate_limiter.check_publish(topic, Instant::now())
+    }
+
+    // Number of publish attempts and of received messages that exceeded the rate limits.
+    pub fn rate_limited(&self) -> (u64, u64) {
+        self.rate_limiter.rejected()
     }
 
     // Subscribe to a gossipsub topic
@@ -77,55 +334,218 @@ impl Behaviour {
         topic: String,
         data: &GossipMessage,
     ) -> Result<MessageId, PublishError> {
-        let topic = IdentTopic::new(topic);
-        // Serialize the the GossipMessage struct into a byte vector.
+        // Serialize the the GossipMessage struct into a byte vector, and encrypt it if the topic
+        // has a key.
         let data_vec = serde_json::to_vec(data).expect("Could not serialize data.");
-        self.gossipsub.publish(topic, data_vec)
+        let data_vec = self.ciphers.encrypt(&topic, data_vec);
+        let message_id = self
+            .gossipsub
+            .publish(IdentTopic::new(topic.clone()), data_vec.clone())?;
+        // Acknowledgments are only of interest while the command awaits them.
+        if !matches!(data, GossipMessage::Ack { .. }) {
+            let source = self.keypair.public().into_peer_id();
+            let message = catch_up::sequence_number(&source, &message_id).and_then(|seqno| {
+                RetainedMessage::sign(&self.keypair, &topic, seqno, &data_vec).ok()
+            });
+            if let Some(message) = message {
+                self.catch_up
+                    .responder_mut()
+                    .record(&topic, data.kind(), message);
+            }
+        }
+        Ok(message_id)
+    }
+
+    // Decrypt the data of a received message if the topic has a key, and deserialize it back into
+    // the GossipMessage that it was serialized from.
+    // A message that fails to decrypt is returned as GossipMessage::Undecryptable, so that it can
+    // be reported. Returns None if the data is no GossipMessage.
+    pub fn decode_message(&self, message: &GossipsubMessage) -> Option<GossipMessage> {
+        self.decode_data(message.topic.as_str(), message.source, message.data.clone())
+    }
+
+    fn decode_data(
+        &self,
+        topic: &str,
+        source: Option<PeerId>,
+        data: Vec<u8>,
+    ) -> Option<GossipMessage> {
+        match self.ciphers.decrypt(topic, data) {
+            Ok(data) => serde_json::from_slice(&data).ok(),
+            Err(error) => Some(GossipMessage::Undecryptable { source, error }),
+        }
     }
 
     // Initiate a kademlia query for a record.
-    pub fn get_record(&mut self, key: String) -> QueryId {
+    pub fn get_record(&mut self, key: String, quorum: Quorum) -> QueryId {
         let key = RecordKey::new(&key);
-        self.kademlia.get_record(&key, Quorum::One)
+        self.kademlia.get_record(&key, quorum)
     }
 
     // Initiate a kademlia query to publish a record in the DHT.
-    pub fn put_record(&mut self, key: String, value: Vec<u8>) -> Result<QueryId, StoreError> {
+    // A signed record is stored in the namespace of the local peer and signed with its keypair.
+    // The expiry is set before the record is stored locally, so that the local copy expires
+    // at the same time as the copies on remote peers.
+    pub fn put_record(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        quorum: Quorum,
+        ttl: Option<Duration>,
+        signed: bool,
+    ) -> Result<QueryId, String> {
+        let mut record = if signed {
+            let key = signed_record::signed_key(&self.keypair.public().into_peer_id(), &key);
+            let key = RecordKey::new(&key);
+            let value = signed_record::sign(&self.keypair, &key, value)?;
+            Record::new(key, value)
+        } else if signed_record::is_signed_key(&key) {
+            return Err("Keys starting with /pk/ are reserved for signed records".into());
+        } else {
+            Record::new(RecordKey::new(&key), value)
+        };
+        let ttl = match ttl {
+            // Like the configured default TTL, a TTL of 0 disables expiry.
+            Some(ttl) if ttl.as_secs() == 0 => None,
+            Some(ttl) => Some(ttl),
+            None => self.record_ttl,
+        };
+        record.expires = ttl.map(|ttl| Instant::now() + ttl);
+        let size = put_message_len(&record, &self.keypair.public().into_peer_id());
+        if size > MAX_KADEMLIA_MESSAGE_SIZE {
+            return Err(format!(
+                "The encoded record has {} bytes, but Kademlia messages are limited to {} bytes",
+                size, MAX_KADEMLIA_MESSAGE_SIZE
+            ));
+        }
+        self.kademlia
+            .put_record(record, quorum)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    // Initiate a kademlia query for the peers closest to a peer id.
+    // Peers that are found during the query are added to the routing table.
+    pub fn get_closest_peers(&mut self, peer_id: PeerId) -> QueryId {
+        self.kademlia.get_closest_peers(peer_id)
+    }
+
+    // Initiate a kademlia query to announce the local peer as provider for a key.
+    pub fn start_providing(&mut self, key: String) -> Result<QueryId, StoreError> {
+        let key = RecordKey::new(&key);
+        self.kademlia.start_providing(key)
+    }
+
+    // Stop announcing the local peer as provider for a key.
+    // The provider record is removed from the local store and will not be republished, remote
+    // peers drop it once it expired.
+    pub fn stop_providing(&mut self, key: String) {
         let key = RecordKey::new(&key);
-        let record = Record::new(key, value);
-        self.kademlia.put_record(record, Quorum::One)
+        self.kademlia.stop_providing(&key)
     }
 
-    // Create a new Behaviour with mdns, kademlia and gossibsub protocols.
+    // Initiate a kademlia query for the providers of a key.
+    pub fn get_providers(&mut self, key: String) -> QueryId {
+        let key = RecordKey::new(&key);
+        self.kademlia.get_providers(key)
+    }
+
+    // Create a new Behaviour with mdns, kademlia, gossibsub, identify, relay, rendezvous and file
+    // exchange protocols.
     // The Behaviour itself is only used in the context of a swarm, that is created with the
     // build_swarm method.
-    async fn new(transport: &TransportLayer) -> Result<Behaviour, ()> {
-        // Create mDNS protocol
-        let mdns = Mdns::new(MdnsConfig::default()).await.map_err(|_| ())?;
+    async fn new(
+        transport: &TransportLayer,
+        config: &Config,
+        ban_list: BanList,
+        rate_limiter: RateLimiter,
+        ciphers: TopicCiphers,
+        relay: Option<Relay>,
+    ) -> Result<Behaviour, ()> {
+        let dht_config = &config.dht;
+        let command_timeout = config.commands.timeout().map_err(|_| ())?;
 
-        // Create kademlia protocol with an in-memory storing of records.
+        // Create mDNS protocol, if enabled
+        let mdns = if config.discovery.mdns {
+            Some(Mdns::new(MdnsConfig::default()).await.map_err(|_| ())?)
+        } else {
+            None
+        };
+
+        // Create kademlia protocol with an in-memory storing of records, that rejects signed records
+        // with invalid signature.
+        // Expired records are removed from the store when they are accessed or republished.
         let kademlia = {
             let store = MemoryStore::new(transport.local_peer_id());
-            Kademlia::new(transport.local_peer_id(), store)
+            let mut config = KademliaConfig::default();
+            config
+                .set_record_ttl(dht_config.record_ttl())
+                .set_replication_interval(dht_config.replication_interval())
+                .set_publication_interval(dht_config.publication_interval())
+                .set_query_timeout(command_timeout);
+            let kademlia = Kademlia::with_config(transport.local_peer_id(), store, config);
+            ValidatingKademlia::new(kademlia)
         };
 
         // Create gossipsub protocol with default config, sign messages with the same keypair
-        // that is used to build the transport layer
-        let gossipsub = {
-            let gossipsub_config = GossipsubConfigBuilder::default().build().unwrap();
-            Gossipsub::new(
+        // that is used to build the transport layer.
+        // Received messages are only forwarded to other peers once they passed the rate limits.
+        // The signatures of received messages are kept, so that they can be retained for peers
+        // that catch up.
+        let signatures = SignatureCapture::default();
+        let mut gossipsub = {
+            let gossipsub_config = GossipsubConfigBuilder::default()
+                .validate_messages()
+                .build()
+                .unwrap();
+            Gossipsub::new_with_transform(
                 MessageAuthenticity::Signed(transport.keypair().clone()),
                 gossipsub_config,
+                signatures.clone(),
             )
             .unwrap()
         };
+        for peer_id in ban_list.iter() {
+            gossipsub.blacklist_peer(peer_id);
+        }
+
+        // Dials of pinned peers time out the same as the connect command.
+        let peers = Peers::new(
+            Duration::from_secs(config.peers.reconnect_backoff_secs),
+            Duration::from_secs(config.peers.max_reconnect_backoff_secs),
+            command_timeout,
+            config.limits.max_established,
+        );
+
+        // Announce listening addresses to connected peers, and again whenever they change, e.g.
+        // once the node listens via a relay.
+        let identify = {
+            let public_key = transport.keypair().public();
+            let config = IdentifyConfig::new(PROTOCOL_VERSION.into(), public_key)
+                .with_push_listen_addr_updates(true);
+            Identify::new(config)
+        };
 
         // Create and return new behaviour with the protocols.
         Ok(Behaviour {
-            mdns,
+            mdns: mdns.into(),
             kademlia,
             gossipsub,
+            peers,
+            identify,
+            relay: relay.into(),
+            autonat: AutoNat::new(transport.local_peer_id(), &config.transport.autonat),
+            rendezvous: Rendezvous::new(config.discovery.rendezvous_server),
+            // Requests for files fail after the command timeout, so that a fetch can continue
+            // with another provider.
+            file_exchange: FileExchange::new(SharedFiles::default(), command_timeout),
+            catch_up: CatchUp::new(Retention::new(&config.retention), command_timeout),
             is_bootstrapped: false,
+            record_ttl: dht_config.record_ttl(),
+            keypair: transport.keypair().clone(),
+            signatures,
+            ban_list,
+            rate_limiter,
+            ciphers,
             events: Vec::new(),
         })
     }
@@ -144,6 +564,30 @@ impl Behaviour {
     }
 }
 
+// Upper bound of the size of the Kademlia message that stores the record on a remote peer.
+// The message contains the key and the record with key, value, publisher and TTL. Fields with a
+// default value are omitted by protobuf, but are counted with their maximal size here.
+fn put_message_len(record: &Record, publisher: &PeerId) -> usize {
+    // A field with a varint length prefix, tags of fields with a number below 16 take one byte.
+    // Lengths beyond 2 MiB take more than 3 bytes, but exceed the limit anyway.
+    let field_len = |tag_len: usize, len: usize| {
+        let prefix_len = match len {
+            0..=0x7f => 1,
+            0x80..=0x3fff => 2,
+            _ => 3,
+        };
+        tag_len + prefix_len + len
+    };
+    let record_len = field_len(1, record.key.as_ref().len())
+        + field_len(1, record.value.len())
+        + field_len(2, publisher.to_bytes().len())
+        // Varint of the TTL as u32.
+        + 2
+        + 5;
+    // Message type and cluster level.
+    2 + 2 + field_len(1, record.key.as_ref().len()) + field_len(1, record_len)
+}
+
 // Handle event from the mDNS protocol.
 // The mDNS event is freqently issued by the mDNS protocol, and return the list of peers and have
 // been discovered or expired within the last period.
@@ -152,15 +596,7 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for Behaviour {
         if let MdnsEvent::Discovered(list) = event {
             // Add discovered peers and addresses to kademlia routing table
             for (peer_id, multiaddr) in list {
-                self.kademlia.add_address(&peer_id, multiaddr);
-                self.gossipsub.add_explicit_peer(&peer_id);
-            }
-            // Bootstrap kademlia if the first peer is discovered.
-            // The bootrapping process introduces the local peer to the kademlia DHT by adding
-            // the local peers information to the routing table of the closest peers, and adding
-            // their information to the local routing table.
-            if !self.is_bootstrapped {
-                self.is_bootstrapped = self.kademlia.bootstrap().is_ok();
+                self.add_peer(peer_id, multiaddr);
             }
         }
     }
@@ -176,8 +612,125 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for Behaviour {
 
 // Handle Gossipsub event by adding it to the local events, which results in it being returned
 // when the Behaviour.poll method is called.
+// Received messages are validated against the rate limit of their source, and messages of
+// encrypted topics against the topic key: valid messages are accepted and forwarded to other
+// peers, the others are rejected and dropped. Messages that fail to decrypt are still reported.
 impl NetworkBehaviourEventProcess<GossipsubEvent> for Behaviour {
     fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = &event
+        {
+            let source = message.source.unwrap_or(*propagation_source);
+            let is_allowed =
+                self.rate_limiter
+                    .check_incoming(source, message.topic.as_str(), Instant::now());
+            // Only messages within the limit are decoded.
+            let msg = match is_allowed {
+                true => self.decode_message(message),
+                false => None,
+            };
+            let is_decrypted = !matches!(msg, Some(GossipMessage::Undecryptable { .. }));
+            let acceptance = match is_allowed && is_decrypted {
+                true => MessageAcceptance::Accept,
+                false => MessageAcceptance::Reject,
+            };
+            // Fails if the message is no longer in the cache.
+            let _ = self.gossipsub.report_message_validation_result(
+                message_id,
+                propagation_source,
+                acceptance,
+            );
+            if !is_allowed {
+                return;
+            }
+            // Retain the message as it was received with its signature, messages that can not be
+            // deserialized are retained under their own type. Acknowledgments and undecryptable
+            // messages are not retained.
+            if !matches!(
+                msg,
+                Some(GossipMessage::Ack { .. }) | Some(GossipMessage::Undecryptable { .. })
+            ) {
+                let kind = msg.as_ref().map_or("unknown", |msg| msg.kind());
+                let signature = self.signatures.take(message_id);
+                if let (Some(source), Some(seqno), Some(signature)) =
+                    (message.source, message.sequence_number, signature)
+                {
+                    let retained = RetainedMessage::new(&source, seqno, &message.data, signature);
+                    self.catch_up
+                        .responder_mut()
+                        .record(message.topic.as_str(), kind, retained);
+                }
+            }
+        }
         self.events.push(BehaviourEvent::Gossipsub(event));
     }
 }
+
+// Handle responses of rendezvous points by adding them to the local events.
+impl NetworkBehaviourEventProcess<RendezvousEvent> for Behaviour {
+    fn inject_event(&mut self, event: RendezvousEvent) {
+        self.events.push(BehaviourEvent::Rendezvous(event));
+    }
+}
+
+// Handle responses to file requests by adding them to the local events.
+impl NetworkBehaviourEventProcess<FileExchangeEvent> for Behaviour {
+    fn inject_event(&mut self, event: FileExchangeEvent) {
+        self.events.push(BehaviourEvent::FileExchange(event));
+    }
+}
+
+// Handle responses to catch-up requests by adding them to the local events.
+impl NetworkBehaviourEventProcess<CatchUpEvent> for Behaviour {
+    fn inject_event(&mut self, event: CatchUpEvent) {
+        self.events.push(BehaviourEvent::CatchUp(event));
+    }
+}
+
+// Handle event from the peers behaviour.
+// Pinned peers are added as known peers once they are connected, the same as for the connect
+// command.
+impl NetworkBehaviourEventProcess<PeersEvent> for Behaviour {
+    fn inject_event(&mut self, event: PeersEvent) {
+        let PeersEvent::PinnedConnected { peer_id, addr } = event;
+        self.add_peer(peer_id, addr);
+    }
+}
+
+// Handle event from the identify protocol.
+// The addresses on which an identified peer listens are added to the routing table, so that other
+// peers can find them via the DHT. /p2p-circuit addresses are only dialable with the id of the
+// destination peer.
+// The address under which the remote peer observed the local peer is kept for the peers view, and
+// probed by AutoNAT.
+impl NetworkBehaviourEventProcess<IdentifyEvent> for Behaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received {
+            peer_id,
+            info:
+                IdentifyInfo {
+                    listen_addrs,
+                    observed_addr,
+                    ..
+                },
+        } = event
+        {
+            for addr in listen_addrs {
+                let addr = match transport::is_relayed(&addr) {
+                    true => addr.with(Protocol::P2p(peer_id.into())),
+                    false => addr,
+                };
+                self.add_peer(peer_id, addr);
+            }
+            self.autonat.add_observed_addr(observed_addr);
+        }
+    }
+}
+
+// The relay and AutoNAT protocols do not issue any events.
+impl NetworkBehaviourEventProcess<()> for Behaviour {
+    fn inject_event(&mut self, _: ()) {}
+}
@@ -0,0 +1,561 @@
+use super::responder::{RequestResponder, Responder};
+use crate::config::{RetentionConfig, RetentionPolicy};
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    core::upgrade::{read_one, write_with_len_prefix},
+    gossipsub::{DataTransform, GossipsubMessage, MessageId, RawGossipsubMessage, TopicHash},
+    identity::{Keypair, PublicKey},
+    request_response::{ProtocolName, RequestId, RequestResponseCodec},
+    PeerId,
+};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error, io,
+    sync::{Arc, Mutex},
+};
+
+// Maximum size of a response, which limits the size of the retained messages of a topic.
+const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+const MAX_REQUEST_SIZE: usize = 1024;
+
+// Number of message types of which the latest message is retained per topic, above it the
+// oldest message is dropped.
+const MAX_RETAINED_TYPES: usize = 32;
+
+// Number of message ids that are remembered to deduplicate the messages of a catch-up.
+const MAX_SEEN_MESSAGES: usize = 1024;
+
+// Prefix of the data that gossipsub signs.
+const SIGNING_PREFIX: &[u8] = b"libp2p-pubsub:";
+
+#[derive(Debug, Clone)]
+pub struct CatchUpProtocol;
+
+impl ProtocolName for CatchUpProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-network/catch-up/2.0.0"
+    }
+}
+
+// Request the retained messages of a topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatchUpRequest {
+    pub topic: String,
+}
+
+// Signature of a gossipsub message, and the public key of the source if it is not inlined in its
+// peer id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub signature: Vec<u8>,
+    pub key: Option<Vec<u8>>,
+}
+
+// Gossip message as it was received, i.g. still encrypted if the topic has a key, together with
+// the gossipsub signature of its source. A peer that catches up verifies the signature, so that
+// the peer that retained the message can not forge or alter it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetainedMessage {
+    pub source: String,
+    pub sequence_number: u64,
+    // Base64 encoded data of the message.
+    pub data: String,
+    // Base64 encoded signature and protobuf encoded public key.
+    pub signature: String,
+    pub key: Option<String>,
+}
+
+impl RetainedMessage {
+    pub fn new(source: &PeerId, sequence_number: u64, data: &[u8], signature: Signature) -> Self {
+        RetainedMessage {
+            source: source.to_base58(),
+            sequence_number,
+            data: base64::encode(data),
+            signature: base64::encode(&signature.signature),
+            key: signature.key.map(base64::encode),
+        }
+    }
+
+    // Sign a message that the local peer published, the same as gossipsub signed it.
+    pub fn sign(
+        keypair: &Keypair,
+        topic: &str,
+        sequence_number: u64,
+        data: &[u8],
+    ) -> Result<Self, String> {
+        let source = keypair.public().into_peer_id();
+        let signature = keypair
+            .sign(&signed_data(&source, sequence_number, topic, data))
+            .map_err(|e| format!("Failed to sign message: {:?}", e))?;
+        let signature = Signature {
+            signature,
+            key: Some(keypair.public().into_protobuf_encoding()),
+        };
+        Ok(RetainedMessage::new(
+            &source,
+            sequence_number,
+            data,
+            signature,
+        ))
+    }
+
+    // Gossipsub message id, under which messages are deduplicated.
+    pub fn id(&self) -> String {
+        format!("{}{}", self.source, self.sequence_number)
+    }
+
+    // Verify that the message was signed by its source for the topic, and return the source and
+    // the data.
+    pub fn verify(&self, topic: &str) -> Result<(PeerId, Vec<u8>), String> {
+        let source: PeerId = self
+            .source
+            .parse()
+            .map_err(|_| format!("Invalid source {}", self.source))?;
+        let data = base64::decode(&self.data).map_err(|e| format!("Invalid data: {}", e))?;
+        let signature =
+            base64::decode(&self.signature).map_err(|e| format!("Invalid signature: {}", e))?;
+        let public_key = match &self.key {
+            Some(key) => base64::decode(key).map_err(|e| format!("Invalid key: {}", e))?,
+            // The public key is inlined in the peer id, after the multihash code and length.
+            None => source.to_bytes().get(2..).unwrap_or_default().to_vec(),
+        };
+        let public_key = PublicKey::from_protobuf_encoding(&public_key)
+            .map_err(|_| "No valid public key of the source".to_string())?;
+        if public_key.clone().into_peer_id() != source {
+            return Err("Public key does not match the source".into());
+        }
+        let signed_data = signed_data(&source, self.sequence_number, topic, &data);
+        if !public_key.verify(&signed_data, &signature) {
+            return Err("Invalid signature".into());
+        }
+        Ok((source, data))
+    }
+}
+
+// Sequence number of a message that the local peer published, from its message id.
+// Gossipsub uses the source followed by the sequence number as message id.
+pub fn sequence_number(source: &PeerId, id: &MessageId) -> Option<u64> {
+    let id = String::from_utf8(id.0.clone()).ok()?;
+    id.strip_prefix(&source.to_base58())?.parse().ok()
+}
+
+// Data that gossipsub signs, i.g. the protobuf encoded message without signature and key.
+fn signed_data(source: &PeerId, sequence_number: u64, topic: &str, data: &[u8]) -> Vec<u8> {
+    let fields = [
+        (1, source.to_bytes()),
+        (2, data.to_vec()),
+        (3, sequence_number.to_be_bytes().to_vec()),
+        (4, topic.as_bytes().to_vec()),
+    ];
+    let mut signed_data = SIGNING_PREFIX.to_vec();
+    for (number, value) in fields.iter() {
+        // Length delimited field, followed by the varint encoded length.
+        signed_data.push(number << 3 | 2);
+        let mut len = value.len();
+        while len >= 0x80 {
+            signed_data.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        signed_data.push(len as u8);
+        signed_data.extend(value);
+    }
+    signed_data
+}
+
+// Gossipsub data transform that keeps the signatures of received messages, which the message
+// events do not contain, until the messages are retained.
+#[derive(Clone)]
+pub struct SignatureCapture {
+    signatures: Arc<Mutex<LruCache<String, Signature>>>,
+}
+
+impl Default for SignatureCapture {
+    fn default() -> Self {
+        SignatureCapture {
+            signatures: Arc::new(Mutex::new(LruCache::new(MAX_SEEN_MESSAGES))),
+        }
+    }
+}
+
+impl SignatureCapture {
+    // Take the signature of a received message.
+    pub fn take(&self, id: &MessageId) -> Option<Signature> {
+        let id = String::from_utf8_lossy(&id.0).into_owned();
+        self.signatures
+            .lock()
+            .expect("Could not lock signatures.")
+            .pop(&id)
+    }
+}
+
+impl DataTransform for SignatureCapture {
+    fn inbound_transform(&self, raw: RawGossipsubMessage) -> Result<GossipsubMessage, io::Error> {
+        if let (Some(source), Some(sequence_number), Some(signature)) =
+            (&raw.source, raw.sequence_number, raw.signature)
+        {
+            let id = format!("{}{}", source.to_base58(), sequence_number);
+            let signature = Signature {
+                signature,
+                key: raw.key,
+            };
+            self.signatures
+                .lock()
+                .expect("Could not lock signatures.")
+                .put(id, signature);
+        }
+        Ok(GossipsubMessage {
+            source: raw.source,
+            data: raw.data,
+            sequence_number: raw.sequence_number,
+            topic: raw.topic,
+        })
+    }
+
+    fn outbound_transform(&self, _: &TopicHash, data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        Ok(data)
+    }
+}
+
+// Retained message with its message type, and its size in a response.
+struct Retained {
+    kind: String,
+    message: RetainedMessage,
+    size: usize,
+}
+
+// Messages of the topics that have a retention policy, oldest first.
+// The messages of a topic are sent in one response, hence the oldest ones are dropped once they
+// would not fit into a response, independent of the policy.
+#[derive(Default)]
+pub struct RetainedMessages {
+    policies: HashMap<String, RetentionPolicy>,
+    topics: HashMap<String, VecDeque<Retained>>,
+}
+
+impl RetainedMessages {
+    pub fn new(config: &RetentionConfig) -> Self {
+        RetainedMessages {
+            policies: config.topics.clone(),
+            topics: HashMap::new(),
+        }
+    }
+
+    // Retain a message according to the policy of the topic, if it has one.
+    pub fn retain(&mut self, topic: &str, kind: &str, message: RetainedMessage) {
+        let policy = match self.policies.get(topic) {
+            Some(policy) => *policy,
+            None => return,
+        };
+        let messages = self.topics.entry(topic.to_string()).or_default();
+        if messages.iter().any(|m| m.message.id() == message.id()) {
+            return;
+        }
+        let retained = Retained {
+            kind: kind.to_string(),
+            size: serde_json::to_vec(&message)
+                .expect("Could not serialize message.")
+                .len(),
+            message,
+        };
+        match policy {
+            RetentionPolicy::Last(limit) => {
+                messages.push_back(retained);
+                while messages.len() > limit {
+                    messages.pop_front();
+                }
+            }
+            RetentionPolicy::LatestPerType => {
+                messages.retain(|m| m.kind != kind);
+                messages.push_back(retained);
+                // The type of custom messages is chosen by the sender.
+                while messages.len() > MAX_RETAINED_TYPES {
+                    messages.pop_front();
+                }
+            }
+        }
+        // A message that does not fit into a response on its own is not retained.
+        while response_size(messages) > MAX_RESPONSE_SIZE {
+            messages.pop_front();
+        }
+    }
+
+    pub fn messages(&self, topic: &str) -> Vec<RetainedMessage> {
+        self.topics
+            .get(topic)
+            .map(|messages| messages.iter().map(|m| m.message.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+// Size of the JSON encoded response with the messages, i.g. the messages separated by commas
+// within brackets.
+fn response_size(messages: &VecDeque<Retained>) -> usize {
+    messages.iter().map(|m| m.size + 1).sum::<usize>() + 1
+}
+
+// Ids of the most recent messages that the local peer received.
+#[derive(Default)]
+struct SeenMessages {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenMessages {
+    // Remember the message id, returns false if it was seen before.
+    fn insert(&mut self, id: String) -> bool {
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > MAX_SEEN_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+// Retained messages that are served to remote peers, and the messages that the local peer
+// received, so that the messages of a catch-up are only forwarded once.
+pub struct Retention {
+    retained: RetainedMessages,
+    seen: SeenMessages,
+}
+
+impl Retention {
+    pub fn new(config: &RetentionConfig) -> Self {
+        Retention {
+            retained: RetainedMessages::new(config),
+            seen: SeenMessages::default(),
+        }
+    }
+
+    // Record a message that the local peer received or published.
+    // Returns false if the message was seen before, i.g. is a duplicate.
+    pub fn record(&mut self, topic: &str, kind: &str, message: RetainedMessage) -> bool {
+        if !self.seen.insert(message.id()) {
+            return false;
+        }
+        self.retained.retain(topic, kind, message);
+        true
+    }
+}
+
+impl Responder for Retention {
+    type Codec = CatchUpCodec;
+    type OutEvent = CatchUpEvent;
+
+    fn protocol() -> CatchUpProtocol {
+        CatchUpProtocol
+    }
+
+    fn respond(
+        &mut self,
+        _: &PeerId,
+        _: RequestId,
+        request: CatchUpRequest,
+    ) -> Option<Vec<RetainedMessage>> {
+        Some(self.retained.messages(&request.topic))
+    }
+
+    fn event(request_id: RequestId, result: Result<Vec<RetainedMessage>, String>) -> CatchUpEvent {
+        CatchUpEvent { request_id, result }
+    }
+}
+
+// Retained messages of a remote peer, or the reason why the request failed.
+#[derive(Debug)]
+pub struct CatchUpEvent {
+    pub request_id: RequestId,
+    pub result: Result<Vec<RetainedMessage>, String>,
+}
+
+// Behaviour that serves the retained messages to remote peers, and requests them from remote peers
+// after the local peer subscribed to a topic.
+pub type CatchUp = RequestResponder<Retention>;
+
+// Codec for the catch-up protocol, requests and responses are JSON encoded.
+#[derive(Debug, Clone, Default)]
+pub struct CatchUpCodec;
+
+fn invalid_data(e: impl error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[async_trait]
+impl RequestResponseCodec for CatchUpCodec {
+    type Protocol = CatchUpProtocol;
+    type Request = CatchUpRequest;
+    type Response = Vec<RetainedMessage>;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &CatchUpProtocol,
+        io: &mut T,
+    ) -> io::Result<CatchUpRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_one(io, MAX_REQUEST_SIZE).await.map_err(invalid_data)?;
+        serde_json::from_slice(&bytes).map_err(invalid_data)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &CatchUpProtocol,
+        io: &mut T,
+    ) -> io::Result<Vec<RetainedMessage>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_one(io, MAX_RESPONSE_SIZE)
+            .await
+            .map_err(invalid_data)?;
+        serde_json::from_slice(&bytes).map_err(invalid_data)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &CatchUpProtocol,
+        io: &mut T,
+        request: CatchUpRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&request).map_err(invalid_data)?;
+        write_with_len_prefix(io, bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &CatchUpProtocol,
+        io: &mut T,
+        response: Vec<RetainedMessage>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&response).map_err(invalid_data)?;
+        write_with_len_prefix(io, bytes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8) -> RetainedMessage {
+        let signature = Signature {
+            signature: Vec::new(),
+            key: None,
+        };
+        let source = PeerId::from_bytes(&[0, 1, 0]).unwrap();
+        RetainedMessage::new(&source, id.into(), &[id], signature)
+    }
+
+    #[test]
+    fn messages_are_retained_per_policy() {
+        let mut config = RetentionConfig::default();
+        config
+            .topics
+            .insert("chat".into(), RetentionPolicy::Last(2));
+        config
+            .topics
+            .insert("led".into(), RetentionPolicy::LatestPerType);
+        let mut retained = RetainedMessages::new(&config);
+
+        for id in 0..3 {
+            retained.retain("chat", "message", message(id));
+        }
+        assert_eq!(retained.messages("chat"), vec![message(1), message(2)]);
+
+        retained.retain("led", "set-led", message(0));
+        retained.retain("led", "message", message(1));
+        retained.retain("led", "set-led", message(2));
+        retained.retain("led", "set-led", message(2));
+        assert_eq!(retained.messages("led"), vec![message(1), message(2)]);
+
+        // The oldest message is dropped once there are too many types.
+        for id in 0..MAX_RETAINED_TYPES as u8 - 1 {
+            retained.retain("led", &format!("custom-{}", id), message(id + 3));
+        }
+        let messages = retained.messages("led");
+        assert_eq!(messages.len(), MAX_RETAINED_TYPES);
+        assert_eq!(messages[0], message(2));
+
+        retained.retain("other", "message", message(0));
+        assert!(retained.messages("other").is_empty());
+    }
+
+    #[test]
+    fn retained_messages_fit_into_a_response() {
+        let mut config = RetentionConfig::default();
+        config
+            .topics
+            .insert("chat".into(), RetentionPolicy::Last(100));
+        let mut retained = RetainedMessages::new(&config);
+        let signature = Signature {
+            signature: Vec::new(),
+            key: None,
+        };
+        let source = PeerId::from_bytes(&[0, 1, 0]).unwrap();
+        let large = |id: u64, len: usize| {
+            RetainedMessage::new(&source, id, &vec![id as u8; len], signature.clone())
+        };
+
+        // Base64 encoded, each message takes more than 1 MiB.
+        for id in 0..5 {
+            retained.retain("chat", "message", large(id, 768 * 1024));
+        }
+        let messages = retained.messages("chat");
+        let response = serde_json::to_vec(&messages).unwrap();
+        assert!(response.len() <= MAX_RESPONSE_SIZE);
+        // The oldest messages were dropped.
+        assert_eq!(
+            messages,
+            vec![
+                large(2, 768 * 1024),
+                large(3, 768 * 1024),
+                large(4, 768 * 1024)
+            ]
+        );
+
+        // A message that exceeds a response on its own is not retained.
+        retained.retain("chat", "message", large(5, MAX_RESPONSE_SIZE));
+        assert!(retained.messages("chat").is_empty());
+    }
+
+    #[test]
+    fn only_messages_signed_by_source_are_verified() {
+        let keypair = Keypair::generate_ed25519();
+        let source = keypair.public().into_peer_id();
+        let message = RetainedMessage::sign(&keypair, "led", 7, b"data").unwrap();
+        assert_eq!(message.verify("led"), Ok((source, b"data".to_vec())));
+        // The signature covers the topic and the data.
+        assert!(message.verify("other").is_err());
+        let altered = RetainedMessage {
+            data: base64::encode(b"altered"),
+            ..message.clone()
+        };
+        assert!(altered.verify("led").is_err());
+        // The public key is inlined in ed25519 peer ids.
+        let inlined = RetainedMessage {
+            key: None,
+            ..message.clone()
+        };
+        assert!(inlined.verify("led").is_ok());
+        let forged = RetainedMessage {
+            source: PeerId::random().to_base58(),
+            ..message
+        };
+        assert!(forged.verify("led").is_err());
+
+        let id = MessageId::from(format!("{}{}", source.to_base58(), 7));
+        assert_eq!(sequence_number(&source, &id), Some(7));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::config::EncryptionConfig;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::RngCore;
+use std::{collections::HashMap, convert::TryFrom};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+// ChaCha20-Poly1305 ciphers of the topics whose messages are encrypted end-to-end, so that only
+// peers with the topic key can read them, and not the peers that relay them.
+// An encrypted message consists of a random nonce followed by the ciphertext. The topic is
+// authenticated as associated data, so that a message can not be replayed on another topic that
+// uses the same key.
+pub struct TopicCiphers {
+    ciphers: HashMap<String, ChaCha20Poly1305>,
+}
+
+impl TopicCiphers {
+    pub fn new(config: &EncryptionConfig) -> Result<Self, String> {
+        let mut ciphers = HashMap::new();
+        for (topic, key) in &config.topic_keys {
+            let key = hex::decode(key.trim())
+                .ok()
+                .and_then(|key| <[u8; KEY_SIZE]>::try_from(key.as_slice()).ok())
+                .ok_or_else(|| format!("Key of topic {} is not 32 hex encoded bytes", topic))?;
+            ciphers.insert(topic.clone(), ChaCha20Poly1305::new(&Key::from(key)));
+        }
+        Ok(TopicCiphers { ciphers })
+    }
+
+    // Encrypt the data if the topic has a key, else return it unchanged.
+    pub fn encrypt(&self, topic: &str, data: Vec<u8>) -> Vec<u8> {
+        let cipher = match self.ciphers.get(topic) {
+            Some(cipher) => cipher,
+            None => return data,
+        };
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: &data,
+            aad: topic.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(&Nonce::from(nonce), payload)
+            .expect("Could not encrypt data.");
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(ciphertext);
+        encrypted
+    }
+
+    // Decrypt the data if the topic has a key, else return it unchanged.
+    // Fails if the data was not encrypted with the key of the topic, or was modified.
+    pub fn decrypt(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let cipher = match self.ciphers.get(topic) {
+            Some(cipher) => cipher,
+            None => return Ok(data),
+        };
+        if data.len() < NONCE_SIZE {
+            return Err("Message is too short to be encrypted".into());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let nonce = <[u8; NONCE_SIZE]>::try_from(nonce).expect("Nonce has the checked size.");
+        let payload = Payload {
+            msg: ciphertext,
+            aad: topic.as_bytes(),
+        };
+        cipher
+            .decrypt(&Nonce::from(nonce), payload)
+            .map_err(|_| "Message is not encrypted with the key of the topic".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ciphers(key: &str) -> TopicCiphers {
+        let mut config = EncryptionConfig::default();
+        config.topic_keys.insert("led".into(), key.repeat(32));
+        TopicCiphers::new(&config).unwrap()
+    }
+
+    #[test]
+    fn only_peers_with_topic_key_decrypt() {
+        let ciphers = ciphers("01");
+        let encrypted = ciphers.encrypt("led", b"on".to_vec());
+        assert_ne!(encrypted, b"on".to_vec());
+        assert_eq!(ciphers.decrypt("led", encrypted.clone()).unwrap(), b"on");
+
+        // Other topics are not encrypted.
+        assert_eq!(ciphers.encrypt("chat", b"hi".to_vec()), b"hi".to_vec());
+        assert_eq!(
+            ciphers.decrypt("chat", encrypted.clone()),
+            Ok(encrypted.clone())
+        );
+
+        assert!(self::ciphers("02")
+            .decrypt("led", encrypted.clone())
+            .is_err());
+        let mut tampered = encrypted;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ciphers.decrypt("led", tampered).is_err());
+        assert!(ciphers.decrypt("led", b"on".to_vec()).is_err());
+
+        let mut config = EncryptionConfig::default();
+        config.topic_keys.insert("led".into(), "01".repeat(16));
+        assert!(TopicCiphers::new(&config).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use super::responder::{RequestResponder, Responder};
+use async_std::task;
+use async_trait::async_trait;
+use futures::{future::BoxFuture, prelude::*, stream::FuturesUnordered};
+use libp2p::{
+    core::upgrade::{read_one, write_with_len_prefix},
+    request_response::{ProtocolName, RequestId, RequestResponseCodec},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+// Size of the chunks into which shared files are split.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// Maximum size of a response, which limits the size of a manifest and hence of a shared file.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+const MAX_REQUEST_SIZE: usize = 1024;
+
+// Prefix of the kademlia key under which the providers of a file are announced.
+const PROVIDER_KEY_PREFIX: &str = "/files/";
+
+// Kademlia key under which the peers that share a file are announced.
+pub fn provider_key(root: &str) -> String {
+    format!("{}{}", PROVIDER_KEY_PREFIX, root)
+}
+
+// Hex encoded SHA-256 hash, that is used to address chunks and manifests.
+pub fn hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+#[derive(Debug, Clone)]
+pub struct FileExchangeProtocol;
+
+impl ProtocolName for FileExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-network/file-exchange/1.0.0"
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileRequest {
+    // Request the manifest of the file with that root hash.
+    Manifest { root: String },
+    // Request a chunk of the file with that root hash.
+    Chunk { root: String, index: usize },
+}
+
+#[derive(Debug, Clone)]
+pub enum FileResponse {
+    // The remote peer does not share the file, or failed to read it.
+    NotFound,
+    // Encoded manifest, that hashes to the root hash of the file.
+    Manifest(Vec<u8>),
+    Chunk(Vec<u8>),
+}
+
+// Response to a request of the local peer, or the reason why the request failed.
+#[derive(Debug)]
+pub struct FileExchangeEvent {
+    pub request_id: RequestId,
+    pub result: Result<FileResponse, String>,
+}
+
+// List of the chunks of a file. The hash of the encoded manifest is the root hash of the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunk_size: usize,
+    // Hashes of the chunks, in the order in which they appear in the file.
+    pub chunks: Vec<String>,
+}
+
+impl Manifest {
+    // Split a file into chunks and hash each of them.
+    pub fn from_file(file: &mut File) -> io::Result<Manifest> {
+        let size = file.metadata()?.len();
+        let mut chunks = Vec::new();
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let len = read_full(file, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            chunks.push(hash(&buf[..len]));
+        }
+        Ok(Manifest {
+            size,
+            chunk_size: CHUNK_SIZE,
+            chunks,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Could not serialize manifest.")
+    }
+
+    // Decode a received manifest, and check that it matches the requested root hash.
+    pub fn decode(root: &str, bytes: &[u8]) -> Result<Manifest, String> {
+        if hash(bytes) != root {
+            return Err("The manifest does not match the root hash".into());
+        }
+        let manifest: Manifest =
+            serde_json::from_slice(bytes).map_err(|e| format!("Invalid manifest: {}", e))?;
+        // Chunks are read into memory as a whole, hence only the chunk size that is used by this
+        // node is accepted.
+        if manifest.chunk_size != CHUNK_SIZE {
+            return Err(format!(
+                "Invalid manifest: chunk size of {} bytes instead of {}",
+                manifest.chunk_size, CHUNK_SIZE
+            ));
+        }
+        // All chunks except the last one are full.
+        let chunk_size = manifest.chunk_size as u64;
+        let chunks_size = (manifest.chunks.len() as u64).saturating_mul(chunk_size);
+        if manifest.size > chunks_size || manifest.size.saturating_add(chunk_size) <= chunks_size {
+            return Err("Invalid manifest: the chunks do not match the file size".into());
+        }
+        Ok(manifest)
+    }
+
+    // Offset and length of a chunk within the file.
+    fn chunk_range(&self, index: usize) -> (u64, usize) {
+        let offset = index as u64 * self.chunk_size as u64;
+        let len = (self.size - offset).min(self.chunk_size as u64);
+        (offset, len as usize)
+    }
+
+    fn read_chunk(&self, file: &mut File, index: usize) -> io::Result<Vec<u8>> {
+        let (offset, len) = self.chunk_range(index);
+        let mut buf = vec![0; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+// Read until the buffer is full or the end of the file is reached.
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+struct SharedFile {
+    path: PathBuf,
+    manifest: Arc<Manifest>,
+    encoded_manifest: Vec<u8>,
+}
+
+// Files that the local peer shares, by root hash.
+// Chunks are read from disk when they are requested, so the files must not change while they
+// are shared. The reads run on blocking tasks, so that they don't stall the swarm.
+#[derive(Default)]
+pub struct SharedFiles {
+    files: HashMap<String, SharedFile>,
+    // Chunks that are read for requests of remote peers.
+    reads: FuturesUnordered<BoxFuture<'static, (RequestId, FileResponse)>>,
+}
+
+impl SharedFiles {
+    // Split the file into chunks, and return the root hash under which it is shared.
+    pub fn share(&mut self, path: &str) -> Result<String, String> {
+        let mut file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let manifest = Manifest::from_file(&mut file)
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let encoded_manifest = manifest.encode();
+        if encoded_manifest.len() >= MAX_RESPONSE_SIZE {
+            return Err(format!("{} is too large to be shared", path));
+        }
+        let root = hash(&encoded_manifest);
+        self.files.insert(
+            root.clone(),
+            SharedFile {
+                path: path.into(),
+                manifest: Arc::new(manifest),
+                encoded_manifest,
+            },
+        );
+        Ok(root)
+    }
+
+    fn manifest(&self, root: &str) -> FileResponse {
+        match self.files.get(root) {
+            Some(shared) => FileResponse::Manifest(shared.encoded_manifest.clone()),
+            None => FileResponse::NotFound,
+        }
+    }
+
+    // Read a chunk on a blocking task.
+    fn read_chunk(&self, root: &str, index: usize) -> BoxFuture<'static, FileResponse> {
+        let shared = match self.files.get(root) {
+            Some(shared) if index < shared.manifest.chunks.len() => shared,
+            _ => return future::ready(FileResponse::NotFound).boxed(),
+        };
+        let path = shared.path.clone();
+        let manifest = shared.manifest.clone();
+        task::spawn_blocking(move || {
+            let chunk =
+                File::open(&path).and_then(|mut file| manifest.read_chunk(&mut file, index));
+            match chunk {
+                Ok(chunk) => FileResponse::Chunk(chunk),
+                Err(_) => FileResponse::NotFound,
+            }
+        })
+        .boxed()
+    }
+}
+
+impl Responder for SharedFiles {
+    type Codec = FileExchangeCodec;
+    type OutEvent = FileExchangeEvent;
+
+    fn protocol() -> FileExchangeProtocol {
+        FileExchangeProtocol
+    }
+
+    // Answer a request of a remote peer. Chunks are returned by `poll_response` once they were
+    // read.
+    fn respond(
+        &mut self,
+        _: &PeerId,
+        request_id: RequestId,
+        request: FileRequest,
+    ) -> Option<FileResponse> {
+        match request {
+            FileRequest::Manifest { root } => Some(self.manifest(&root)),
+            FileRequest::Chunk { root, index } => {
+                let read = self.read_chunk(&root, index);
+                self.reads
+                    .push(read.map(move |response| (request_id, response)).boxed());
+                None
+            }
+        }
+    }
+
+    fn poll_response(&mut self, cx: &mut Context<'_>) -> Poll<(RequestId, FileResponse)> {
+        match self.reads.poll_next_unpin(cx) {
+            Poll::Ready(Some(read)) => Poll::Ready(read),
+            // The responder polls again after a read was added.
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn event(request_id: RequestId, result: Result<FileResponse, String>) -> FileExchangeEvent {
+        FileExchangeEvent { request_id, result }
+    }
+}
+
+// File that is being downloaded.
+// Chunks are written to `<path>.part`, which is renamed to the path once all chunks were received.
+// If the download is interrupted, the chunks in the partial file that match their hash are kept,
+// so that a new download of the same file resumes.
+pub struct Download {
+    file: File,
+    part_path: String,
+    path: String,
+    manifest: Manifest,
+}
+
+impl Download {
+    pub fn open(path: &str, manifest: Manifest) -> Result<Self, String> {
+        let part_path = format!("{}.part", path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Keep the chunks of a previous download.
+            .truncate(false)
+            .open(&part_path)
+            .and_then(|file| file.set_len(manifest.size).map(|_| file))
+            .map_err(|e| format!("Failed to open {}: {}", part_path, e))?;
+        Ok(Download {
+            file,
+            part_path,
+            path: path.into(),
+            manifest,
+        })
+    }
+
+    // Indices of the chunks that are not yet downloaded, or are corrupted.
+    pub fn missing_chunks(&mut self) -> Vec<usize> {
+        let manifest = &self.manifest;
+        let file = &mut self.file;
+        (0..manifest.chunks.len())
+            .filter(|index| match manifest.read_chunk(file, *index) {
+                Ok(chunk) => hash(&chunk) != manifest.chunks[*index],
+                Err(_) => true,
+            })
+            .collect()
+    }
+
+    // Verify the hash of a received chunk, and write it to the partial file.
+    pub fn write_chunk(&mut self, index: usize, chunk: &[u8]) -> Result<(), String> {
+        if self.manifest.chunks.get(index) != Some(&hash(chunk)) {
+            return Err(format!("Chunk {} does not match its hash", index));
+        }
+        let (offset, _) = self.manifest.chunk_range(index);
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(chunk))
+            .map_err(|e| format!("Failed to write {}: {}", self.part_path, e))
+    }
+
+    // Move the complete file to its path.
+    pub fn finish(self) -> Result<(), String> {
+        self.file
+            .sync_all()
+            .and_then(|_| fs::rename(&self.part_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path, e))
+    }
+}
+
+// Behaviour that serves the shared files to remote peers, and requests files from them.
+pub type FileExchange = RequestResponder<SharedFiles>;
+
+// Codec for the file exchange protocol.
+// Requests are JSON encoded. Responses start with a tag, followed by the manifest or chunk bytes,
+// so that chunks are transferred without encoding overhead.
+#[derive(Debug, Clone, Default)]
+pub struct FileExchangeCodec;
+
+const TAG_NOT_FOUND: u8 = 0;
+const TAG_MANIFEST: u8 = 1;
+const TAG_CHUNK: u8 = 2;
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[async_trait]
+impl RequestResponseCodec for FileExchangeCodec {
+    type Protocol = FileExchangeProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_one(io, MAX_REQUEST_SIZE).await.map_err(invalid_data)?;
+        serde_json::from_slice(&bytes).map_err(invalid_data)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_one(io, MAX_RESPONSE_SIZE)
+            .await
+            .map_err(invalid_data)?;
+        match bytes.split_first() {
+            Some((&TAG_NOT_FOUND, _)) => Ok(FileResponse::NotFound),
+            Some((&TAG_MANIFEST, data)) => Ok(FileResponse::Manifest(data.to_vec())),
+            Some((&TAG_CHUNK, data)) => Ok(FileResponse::Chunk(data.to_vec())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown response",
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        request: FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&request).map_err(invalid_data)?;
+        write_with_len_prefix(io, bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        response: FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = match response {
+            FileResponse::NotFound => vec![TAG_NOT_FOUND],
+            FileResponse::Manifest(data) => [&[TAG_MANIFEST], data.as_slice()].concat(),
+            FileResponse::Chunk(data) => [&[TAG_CHUNK], data.as_slice()].concat(),
+        };
+        write_with_len_prefix(io, bytes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::env;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("p2p-network-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn download_verifies_and_resumes() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let shared_path = temp_path("shared");
+        fs::write(&shared_path, &data).unwrap();
+        let mut shared = SharedFiles::default();
+        let root = shared.share(&shared_path).unwrap();
+
+        let manifest = match shared.manifest(&root) {
+            FileResponse::Manifest(bytes) => Manifest::decode(&root, &bytes).unwrap(),
+            res => panic!("Unexpected response: {:?}", res),
+        };
+        assert_eq!(manifest.chunks.len(), 3);
+        assert!(Manifest::decode(&hash(b"other"), &manifest.encode()).is_err());
+
+        let out_path = temp_path("out");
+        let mut download = Download::open(&out_path, manifest.clone()).unwrap();
+        assert_eq!(download.missing_chunks(), vec![0, 1, 2]);
+        let chunk = |index| match block_on(shared.read_chunk(&root, index)) {
+            FileResponse::Chunk(chunk) => chunk,
+            res => panic!("Unexpected response: {:?}", res),
+        };
+        assert!(matches!(
+            block_on(shared.read_chunk(&root, 3)),
+            FileResponse::NotFound
+        ));
+        assert!(download.write_chunk(0, &chunk(1)).is_err());
+        download.write_chunk(1, &chunk(1)).unwrap();
+        drop(download);
+
+        // Only the missing chunks have to be downloaded after an interruption.
+        let mut download = Download::open(&out_path, manifest).unwrap();
+        assert_eq!(download.missing_chunks(), vec![0, 2]);
+        download.write_chunk(0, &chunk(0)).unwrap();
+        download.write_chunk(2, &chunk(2)).unwrap();
+        assert!(download.missing_chunks().is_empty());
+        download.finish().unwrap();
+        assert_eq!(fs::read(&out_path).unwrap(), data);
+
+        let _ = fs::remove_file(shared_path);
+        let _ = fs::remove_file(out_path);
+    }
+
+    #[test]
+    fn manifest_with_other_chunk_size_is_rejected() {
+        let manifest = Manifest {
+            size: 1 << 40,
+            chunk_size: 1 << 40,
+            chunks: vec![hash(b"chunk")],
+        };
+        let bytes = manifest.encode();
+        assert!(Manifest::decode(&hash(&bytes), &bytes).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use crate::types::{PinnedPeer, PinnedState};
+use async_std::task;
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{
+    core::{
+        connection::{ConnectionId, ConnectionLimit, ListenerId},
+        upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade},
+        ConnectedPoint,
+    },
+    multiaddr::Protocol,
+    swarm::{
+        DialPeerCondition, KeepAlive, NegotiatedSubstream, NetworkBehaviour,
+        NetworkBehaviourAction, NotifyHandler, PollParameters, ProtocolsHandler,
+        ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, SubstreamProtocol,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    error,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// Event that is issued when a pinned peer was connected.
+#[derive(Debug)]
+pub enum PeersEvent {
+    PinnedConnected { peer_id: PeerId, addr: Multiaddr },
+}
+
+enum State {
+    Connected,
+    // Dial was issued. Failed dials are reported by the swarm, except if the dial of an address
+    // failed right away, e.g. because it was rejected due to the connection limits. Hence the
+    // dial is also considered failed after the deadline.
+    Dialing { deadline: Instant },
+    // Waiting until the peer is dialed again.
+    Backoff { until: Instant },
+}
+
+// Peer that should always be connected.
+struct Pinned {
+    addr: Multiaddr,
+    // Known once the peer was connected, or if the address ends with /p2p/<peer-id>.
+    peer_id: Option<PeerId>,
+    state: State,
+    // Number of failed dials since the peer was last connected.
+    failures: u32,
+}
+
+// Network behaviour that keeps track of the connected peers, and keeps the pinned peers connected.
+// Pinned peers are redialed with exponential backoff whenever their connection closes or a dial
+// fails.
+// The swarm only limits the established connections per direction, hence the total number of
+// connections is limited here: connections beyond the limit are closed right after they were
+// established.
+pub struct Peers {
+    connected: BTreeSet<PeerId>,
+    // Number of established connections, and the maximum number of them.
+    established: u32,
+    max_established: Option<u32>,
+    // Connections that exceed the limit and are closed by their handler.
+    exceeded: VecDeque<(PeerId, ConnectionId, ConnectionLimit)>,
+    pinned: Vec<Pinned>,
+    // Listeners that were closed since they were last taken, e.g. because the connection to the
+    // relay of a /p2p-circuit listener closed.
+    closed_listeners: Vec<ListenerId>,
+    // Delay before the first redial, it doubles with each failed dial up to the max backoff.
+    backoff: Duration,
+    max_backoff: Duration,
+    // Time after which a dial is considered failed.
+    dial_timeout: Duration,
+    // Timer that wakes up the task at the next backoff or dial deadline.
+    timer: Option<(Instant, BoxFuture<'static, ()>)>,
+    events: VecDeque<PeersEvent>,
+}
+
+impl Peers {
+    pub fn new(
+        backoff: Duration,
+        max_backoff: Duration,
+        dial_timeout: Duration,
+        max_established: Option<u32>,
+    ) -> Self {
+        Peers {
+            connected: BTreeSet::new(),
+            established: 0,
+            max_established,
+            exceeded: VecDeque::new(),
+            pinned: Vec::new(),
+            closed_listeners: Vec::new(),
+            backoff,
+            max_backoff,
+            dial_timeout,
+            timer: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    // Pin a peer by address, it is dialed on the next poll of the swarm.
+    // Returns false if the address is already pinned.
+    pub fn pin(&mut self, addr: Multiaddr) -> bool {
+        if self.pinned.iter().any(|p| p.addr == addr) {
+            return false;
+        }
+        let peer_id = match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+            _ => None,
+        };
+        let state = match peer_id {
+            Some(peer_id) if self.connected.contains(&peer_id) => State::Connected,
+            _ => State::Backoff {
+                until: Instant::now(),
+            },
+        };
+        self.pinned.push(Pinned {
+            addr,
+            peer_id,
+            state,
+            failures: 0,
+        });
+        true
+    }
+
+    // Stop redialing the peer at that address. An existing connection is kept.
+    // Returns false if the address was not pinned.
+    pub fn unpin(&mut self, addr: &Multiaddr) -> bool {
+        let len = self.pinned.len();
+        self.pinned.retain(|p| &p.addr != addr);
+        self.pinned.len() != len
+    }
+
+    pub fn connected(&self) -> Vec<PeerId> {
+        self.connected.iter().cloned().collect()
+    }
+
+    pub fn take_closed_listeners(&mut self) -> Vec<ListenerId> {
+        std::mem::take(&mut self.closed_listeners)
+    }
+
+    // Current state of the pinned peers.
+    pub fn pinned(&self) -> Vec<PinnedPeer> {
+        let now = Instant::now();
+        self.pinned
+            .iter()
+            .map(|p| PinnedPeer {
+                addr: p.addr.clone(),
+                peer_id: p.peer_id,
+                state: match p.state {
+                    State::Connected => PinnedState::Connected,
+                    State::Dialing { .. } => PinnedState::Dialing,
+                    State::Backoff { until } => PinnedState::Backoff {
+                        retry_in: until.saturating_duration_since(now),
+                        failures: p.failures,
+                    },
+                },
+            })
+            .collect()
+    }
+
+    // Schedule the next dial of a pinned peer after the backoff for its number of failures.
+    fn schedule_redial(pinned: &mut Pinned, backoff: Duration, max_backoff: Duration) {
+        let delay = backoff_delay(backoff, max_backoff, pinned.failures);
+        pinned.state = State::Backoff {
+            until: Instant::now() + delay,
+        };
+    }
+
+    // Register the timer for the earliest deadline of all pinned peers.
+    // Returns true if the deadline already passed.
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> bool {
+        let next = self
+            .pinned
+            .iter()
+            .filter_map(|p| match p.state {
+                State::Connected => None,
+                State::Dialing { deadline } => Some(deadline),
+                State::Backoff { until } => Some(until),
+            })
+            .min();
+        let next = match next {
+            Some(next) => next,
+            None => {
+                self.timer = None;
+                return false;
+            }
+        };
+        if !matches!(&self.timer, Some((deadline, _)) if *deadline == next) {
+            let delay = next.saturating_duration_since(Instant::now());
+            self.timer = Some((next, task::sleep(delay).boxed()));
+        }
+        let is_expired = match &mut self.timer {
+            Some((_, timer)) => timer.poll_unpin(cx).is_ready(),
+            None => false,
+        };
+        if is_expired {
+            self.timer = None;
+        }
+        is_expired
+    }
+}
+
+// Backoff before the next dial: doubles with each failure, limited to the max backoff.
+fn backoff_delay(backoff: Duration, max_backoff: Duration, failures: u32) -> Duration {
+    2u32.checked_pow(failures)
+        .and_then(|factor| backoff.checked_mul(factor))
+        .map_or(max_backoff, |delay| delay.min(max_backoff))
+}
+
+impl NetworkBehaviour for Peers {
+    type ProtocolsHandler = LimitHandler;
+    type OutEvent = PeersEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        LimitHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.pinned
+            .iter()
+            .filter(|p| p.peer_id.as_ref() == Some(peer_id))
+            .map(|p| p.addr.clone())
+            .collect()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.connected.insert(*peer_id);
+    }
+
+    // Mark pinned peers as connected, if the connection was dialed to their address or if it is
+    // with their peer id.
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.established += 1;
+        match self.max_established {
+            Some(limit) if self.established > limit => {
+                let current = self.established - 1;
+                self.exceeded
+                    .push_back((*peer_id, *id, ConnectionLimit { limit, current }));
+            }
+            _ => {}
+        }
+        for pinned in self.pinned.iter_mut() {
+            let is_dialed = match endpoint {
+                ConnectedPoint::Dialer { address } => address == &pinned.addr,
+                ConnectedPoint::Listener { .. } => false,
+            };
+            let is_pinned = is_dialed || pinned.peer_id.as_ref() == Some(peer_id);
+            if is_pinned && !matches!(pinned.state, State::Connected) {
+                pinned.peer_id = Some(*peer_id);
+                pinned.state = State::Connected;
+                pinned.failures = 0;
+                self.events.push_back(PeersEvent::PinnedConnected {
+                    peer_id: *peer_id,
+                    addr: pinned.addr.clone(),
+                });
+            }
+        }
+    }
+
+    fn inject_connection_closed(&mut self, _: &PeerId, _: &ConnectionId, _: &ConnectedPoint) {
+        self.established = self.established.saturating_sub(1);
+    }
+
+    // Redial pinned peers once their last connection closed.
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.connected.remove(peer_id);
+        for pinned in self.pinned.iter_mut() {
+            let is_connected = matches!(pinned.state, State::Connected);
+            if is_connected && pinned.peer_id.as_ref() == Some(peer_id) {
+                Self::schedule_redial(pinned, self.backoff, self.max_backoff);
+            }
+        }
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, _: ()) {}
+
+    fn inject_listener_closed(&mut self, id: ListenerId, _: Result<(), &std::io::Error>) {
+        self.closed_listeners.push(id);
+    }
+
+    // Back off further if the dial of a pinned peer by its address failed.
+    // Dials by peer id are reported as dial failure once all addresses of the peer failed.
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        _: &dyn error::Error,
+    ) {
+        if peer_id.is_some() {
+            return;
+        }
+        for pinned in self.pinned.iter_mut() {
+            let is_dialing = matches!(pinned.state, State::Dialing { .. });
+            if pinned.peer_id.is_none() && &pinned.addr == addr && is_dialing {
+                pinned.failures += 1;
+                Self::schedule_redial(pinned, self.backoff, self.max_backoff);
+            }
+        }
+    }
+
+    // Back off further if the dial of a pinned peer by its peer id failed. The swarm reports this
+    // for all failures, including dials that are rejected right away, e.g. due to a ban.
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        for pinned in self.pinned.iter_mut() {
+            let is_dialing = matches!(pinned.state, State::Dialing { .. });
+            if pinned.peer_id.as_ref() == Some(peer_id) && is_dialing {
+                pinned.failures += 1;
+                Self::schedule_redial(pinned, self.backoff, self.max_backoff);
+            }
+        }
+    }
+
+    // Dial pinned peers whose backoff expired, and back off further for timed out dials.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<Self::ProtocolsHandler as ProtocolsHandler>::InEvent, PeersEvent>,
+    > {
+        loop {
+            if let Some((peer_id, connection, limit)) = self.exceeded.pop_front() {
+                return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::One(connection),
+                    event: limit,
+                });
+            }
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+            }
+            let now = Instant::now();
+            for pinned in self.pinned.iter_mut() {
+                match pinned.state {
+                    State::Backoff { until } if until <= now => {
+                        pinned.state = State::Dialing {
+                            deadline: now + self.dial_timeout,
+                        };
+                        // Dial by peer id once it is known, so that every failure is reported.
+                        let action = match pinned.peer_id {
+                            Some(peer_id) => NetworkBehaviourAction::DialPeer {
+                                peer_id,
+                                condition: DialPeerCondition::Disconnected,
+                            },
+                            None => NetworkBehaviourAction::DialAddress {
+                                address: pinned.addr.clone(),
+                            },
+                        };
+                        return Poll::Ready(action);
+                    }
+                    State::Dialing { deadline } if deadline <= now => {
+                        pinned.failures += 1;
+                        Self::schedule_redial(pinned, self.backoff, self.max_backoff);
+                    }
+                    _ => {}
+                }
+            }
+            if !self.poll_timer(cx) {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+// Connection handler that does not support any protocol, but closes the connection once it is
+// told that the connection exceeds the limit.
+#[derive(Default)]
+pub struct LimitHandler {
+    exceeded: Option<ConnectionLimit>,
+}
+
+impl ProtocolsHandler for LimitHandler {
+    type InEvent = ConnectionLimit;
+    type OutEvent = ();
+    type Error = ConnectionLimit;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<DeniedUpgrade, ()> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        _: <DeniedUpgrade as InboundUpgrade<NegotiatedSubstream>>::Output,
+        _: (),
+    ) {
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        _: <DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        _: (),
+    ) {
+    }
+
+    fn inject_event(&mut self, limit: ConnectionLimit) {
+        self.exceeded = Some(limit);
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        _: (),
+        _: ProtocolsHandlerUpgrErr<<DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Error>,
+    ) {
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ProtocolsHandlerEvent<DeniedUpgrade, (), (), ConnectionLimit>> {
+        match self.exceeded.take() {
+            Some(limit) => Poll::Ready(ProtocolsHandlerEvent::Close(limit)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let backoff = Duration::from_secs(1);
+        let max = Duration::from_secs(60);
+        assert_eq!(backoff_delay(backoff, max, 0), Duration::from_secs(1));
+        assert_eq!(backoff_delay(backoff, max, 1), Duration::from_secs(2));
+        assert_eq!(backoff_delay(backoff, max, 5), Duration::from_secs(32));
+        assert_eq!(backoff_delay(backoff, max, 6), max);
+        assert_eq!(backoff_delay(backoff, max, 100), max);
+    }
+
+    #[test]
+    fn pin_and_unpin() {
+        let secs = Duration::from_secs;
+        let mut peers = Peers::new(secs(1), secs(60), secs(60), None);
+        let addr: Multiaddr = "/memory/1".parse().unwrap();
+        assert!(peers.pin(addr.clone()));
+        assert!(!peers.pin(addr.clone()));
+        assert_eq!(peers.pinned().len(), 1);
+        assert!(peers.unpin(&addr));
+        assert!(!peers.unpin(&addr));
+        assert!(peers.pinned().is_empty());
+    }
+}
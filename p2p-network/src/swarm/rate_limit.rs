@@ -0,0 +1,229 @@
+use crate::config::{RateLimit, RateLimitsConfig};
+use libp2p::PeerId;
+use lru::LruCache;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// Number of buckets for incoming messages. Above it, the bucket of the source peer and topic
+// that sent the least recently is dropped, so that the tracked peers do not grow memory.
+const MAX_INCOMING_BUCKETS: usize = 1024;
+
+// Lowest rate of a limit, i.g. one message per day, so that the time until the next token is
+// available can always be represented.
+const MIN_PER_SEC: f64 = 1.0 / 86400.0;
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_sec).min(self.limit.burst as f64);
+        self.updated = now;
+    }
+
+    // Take a token, or return the time until the next token is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_sec,
+            ))
+        }
+    }
+}
+
+// Rate limits on the messages that the local node publishes per topic, and that it receives per
+// source peer and topic.
+pub struct RateLimiter {
+    config: RateLimitsConfig,
+    publish: HashMap<String, TokenBucket>,
+    incoming: LruCache<(PeerId, String), TokenBucket>,
+    // Number of publish attempts that exceeded the limit.
+    rejected_publishes: u64,
+    // Number of received messages that exceeded the limit.
+    rejected_messages: u64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitsConfig) -> Result<Self, String> {
+        let limits = config
+            .topics
+            .values()
+            .flat_map(|topic| vec![topic.publish, topic.incoming])
+            .chain(vec![config.publish, config.incoming]);
+        for limit in limits.flatten() {
+            if !limit.per_sec.is_finite() || limit.per_sec < MIN_PER_SEC || limit.burst == 0 {
+                return Err(format!(
+                    "A rate limit requires per_sec >= {} and burst > 0",
+                    MIN_PER_SEC
+                ));
+            }
+        }
+        Ok(RateLimiter {
+            config,
+            publish: HashMap::new(),
+            incoming: LruCache::new(MAX_INCOMING_BUCKETS),
+            rejected_publishes: 0,
+            rejected_messages: 0,
+        })
+    }
+
+    // Check if the local node may publish to the topic.
+    pub fn check_publish(&mut self, topic: &str, now: Instant) -> Result<(), String> {
+        let limit = match self.config.topics.get(topic) {
+            Some(limits) => limits.publish,
+            None => self.config.publish,
+        };
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let bucket = self
+            .publish
+            .entry(topic.to_string())
+            .or_insert_with(|| TokenBucket::new(limit, now));
+        bucket.take(now).map_err(|retry_in| {
+            self.rejected_publishes += 1;
+            format!(
+                "Rate limit of topic {} exceeded, retry in {}ms",
+                topic,
+                retry_in.as_millis() + 1
+            )
+        })
+    }
+
+    // Check if a message that was received on the topic from the source peer is within the limit.
+    pub fn check_incoming(&mut self, source: PeerId, topic: &str, now: Instant) -> bool {
+        let limit = match self.config.topics.get(topic) {
+            Some(limits) => limits.incoming,
+            None => self.config.incoming,
+        };
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let key = (source, topic.to_string());
+        if !self.incoming.contains(&key) {
+            self.incoming.put(key.clone(), TokenBucket::new(limit, now));
+        }
+        let bucket = self.incoming.get_mut(&key).expect("Bucket was inserted.");
+        let is_allowed = bucket.take(now).is_ok();
+        if !is_allowed {
+            self.rejected_messages += 1;
+        }
+        is_allowed
+    }
+
+    // Number of rejected publish attempts, and of rejected received messages.
+    pub fn rejected(&self) -> (u64, u64) {
+        (self.rejected_publishes, self.rejected_messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopicRateLimits;
+
+    #[test]
+    fn limits_per_topic_and_source() {
+        let limit = RateLimit {
+            per_sec: 1.0,
+            burst: 2,
+        };
+        let mut config = RateLimitsConfig {
+            publish: Some(limit),
+            incoming: Some(limit),
+            ..Default::default()
+        };
+        config
+            .topics
+            .insert("unlimited".into(), TopicRateLimits::default());
+        let mut limiter = RateLimiter::new(config).unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_publish("led", now).is_ok());
+        assert!(limiter.check_publish("led", now).is_ok());
+        assert!(limiter.check_publish("led", now).is_err());
+        // Each topic has its own bucket.
+        assert!(limiter.check_publish("other", now).is_ok());
+        for _ in 0..10 {
+            assert!(limiter.check_publish("unlimited", now).is_ok());
+        }
+        // The bucket refills over time.
+        assert!(limiter
+            .check_publish("led", now + Duration::from_secs(1))
+            .is_ok());
+
+        let a = PeerId::random();
+        let b = PeerId::random();
+        assert!(limiter.check_incoming(a, "led", now));
+        assert!(limiter.check_incoming(a, "led", now));
+        assert!(!limiter.check_incoming(a, "led", now));
+        assert!(limiter.check_incoming(b, "led", now));
+        assert_eq!(limiter.rejected(), (1, 1));
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let config = RateLimitsConfig {
+            incoming: Some(RateLimit {
+                per_sec: 0.0,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        assert!(RateLimiter::new(config).is_err());
+
+        let config = RateLimitsConfig {
+            publish: Some(RateLimit {
+                per_sec: 1e-300,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        assert!(RateLimiter::new(config).is_err());
+    }
+
+    #[test]
+    fn incoming_buckets_are_bounded() {
+        let limit = RateLimit {
+            per_sec: MIN_PER_SEC,
+            burst: 1,
+        };
+        let config = RateLimitsConfig {
+            incoming: Some(limit),
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(config).unwrap();
+        let now = Instant::now();
+        let first = PeerId::random();
+        let recent = PeerId::random();
+        assert!(limiter.check_incoming(first, "led", now));
+        assert!(limiter.check_incoming(recent, "led", now));
+        for _ in 0..MAX_INCOMING_BUCKETS - 1 {
+            assert!(limiter.check_incoming(PeerId::random(), "led", now));
+        }
+        assert_eq!(limiter.incoming.len(), MAX_INCOMING_BUCKETS);
+        // The least recently seen source lost its bucket, the others are still limited.
+        assert!(!limiter.check_incoming(recent, "led", now));
+        assert!(limiter.check_incoming(first, "led", now));
+    }
+}
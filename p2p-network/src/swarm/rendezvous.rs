@@ -0,0 +1,537 @@
+use super::transport;
+use crate::types::Registration;
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{
+    core::{
+        connection::ConnectionId,
+        upgrade::{self, InboundUpgrade, OutboundUpgrade, UpgradeInfo},
+    },
+    multiaddr::Protocol,
+    swarm::{
+        protocols_handler::{OneShotHandler, OneShotHandlerConfig, SubstreamProtocol},
+        NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io, iter,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+const PROTOCOL_NAME: &[u8] = b"/p2p-network/rendezvous/1.0.0";
+
+// Maximum size of a message, which limits the number of registrations in a discover response.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// Bytes of a discover response that are available for the registrations. The remainder is left
+// for the namespace and the encoding of the response.
+const DISCOVER_BUDGET: usize = MAX_MESSAGE_SIZE - 4 * 1024;
+
+// Limits of a rendezvous point, so that registrations do not exhaust its memory.
+const MAX_NAMESPACE_LEN: usize = 255;
+const MAX_ADDRS: usize = 16;
+const MAX_REGISTRATIONS: usize = 1000;
+const MAX_DISCOVERED: usize = 100;
+const MAX_TTL: Duration = Duration::from_secs(72 * 60 * 60);
+
+const NOT_A_POINT: &str = "Peer is not a rendezvous point";
+
+// Ttl of a registration if none is requested.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Message that is exchanged between a rendezvous point and its clients.
+// Each message is sent on a new substream, responses are matched to requests by peer and namespace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    Register {
+        namespace: String,
+        addrs: Vec<Multiaddr>,
+        ttl_secs: u64,
+    },
+    RegisterResponse {
+        namespace: String,
+        // Ttl in seconds that was granted by the rendezvous point.
+        result: Result<u64, String>,
+    },
+    Unregister {
+        namespace: String,
+    },
+    Discover {
+        namespace: String,
+    },
+    DiscoverResponse {
+        namespace: String,
+        result: Result<Vec<WireRegistration>, String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireRegistration {
+    peer_id: String,
+    addrs: Vec<Multiaddr>,
+    ttl_secs: u64,
+}
+
+// Responses of a rendezvous point to requests of the local peer.
+#[derive(Debug)]
+pub enum RendezvousEvent {
+    Registered {
+        peer_id: PeerId,
+        namespace: String,
+        result: Result<Duration, String>,
+    },
+    Discovered {
+        peer_id: PeerId,
+        namespace: String,
+        result: Result<Vec<Registration>, String>,
+    },
+}
+
+// Message that was received from, or sent to, a remote peer.
+#[derive(Debug)]
+pub enum HandlerEvent {
+    Received(Message),
+    Sent,
+}
+
+impl From<Message> for HandlerEvent {
+    fn from(message: Message) -> Self {
+        HandlerEvent::Received(message)
+    }
+}
+
+impl From<()> for HandlerEvent {
+    fn from(_: ()) -> Self {
+        HandlerEvent::Sent
+    }
+}
+
+// Upgrade for inbound substreams that reads one message.
+#[derive(Debug, Clone, Default)]
+pub struct RendezvousProtocol;
+
+impl UpgradeInfo for RendezvousProtocol {
+    type Info = &'static [u8];
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(PROTOCOL_NAME)
+    }
+}
+
+impl<C> InboundUpgrade<C> for RendezvousProtocol
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = Message;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Message, io::Error>>;
+
+    fn upgrade_inbound(self, mut socket: C, _: Self::Info) -> Self::Future {
+        async move {
+            let bytes = upgrade::read_one(&mut socket, MAX_MESSAGE_SIZE)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        .boxed()
+    }
+}
+
+// A message is sent by writing it to a new outbound substream.
+impl UpgradeInfo for Message {
+    type Info = &'static [u8];
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(PROTOCOL_NAME)
+    }
+}
+
+impl<C> OutboundUpgrade<C> for Message
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = ();
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<(), io::Error>>;
+
+    fn upgrade_outbound(self, mut socket: C, _: Self::Info) -> Self::Future {
+        async move {
+            let bytes = serde_json::to_vec(&self).expect("Could not serialize message.");
+            upgrade::write_one(&mut socket, bytes).await
+        }
+        .boxed()
+    }
+}
+
+struct Stored {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    expires: Instant,
+}
+
+// Registrations that are stored by a rendezvous point, per namespace.
+#[derive(Default)]
+struct Registrations {
+    namespaces: HashMap<String, Vec<Stored>>,
+}
+
+impl Registrations {
+    // Store or refresh the registration of a peer. Returns the granted ttl.
+    fn add(
+        &mut self,
+        peer_id: PeerId,
+        namespace: String,
+        addrs: Vec<Multiaddr>,
+        ttl: Duration,
+    ) -> Result<Duration, String> {
+        if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+            return Err(format!(
+                "Namespace has to have 1 to {} bytes",
+                MAX_NAMESPACE_LEN
+            ));
+        }
+        if addrs.is_empty() {
+            return Err("Registration without addresses".into());
+        }
+        if addrs.len() > MAX_ADDRS {
+            return Err(format!(
+                "Registration with more than {} addresses",
+                MAX_ADDRS
+            ));
+        }
+        if ttl == Duration::from_secs(0) {
+            return Err("Ttl can not be 0".into());
+        }
+        self.remove_expired();
+        self.remove(&peer_id, &namespace);
+        if self.len() >= MAX_REGISTRATIONS {
+            return Err("Too many registrations".into());
+        }
+        let ttl = ttl.min(MAX_TTL);
+        self.namespaces.entry(namespace).or_default().push(Stored {
+            peer_id,
+            addrs,
+            expires: Instant::now() + ttl,
+        });
+        Ok(ttl)
+    }
+
+    fn remove(&mut self, peer_id: &PeerId, namespace: &str) {
+        if let Some(stored) = self.namespaces.get_mut(namespace) {
+            stored.retain(|s| &s.peer_id != peer_id);
+        }
+    }
+
+    // Registrations within a namespace, except the ones of the requesting peer.
+    // Registrations that do not fit into the response anymore are skipped, so that the response
+    // does not exceed the maximum message size.
+    fn discover(&mut self, namespace: &str, requester: &PeerId) -> Vec<WireRegistration> {
+        self.remove_expired();
+        let now = Instant::now();
+        let mut size = 0usize;
+        let mut discovered = Vec::new();
+        let stored = self.namespaces.get(namespace).into_iter().flatten();
+        for s in stored.filter(|s| &s.peer_id != requester) {
+            if discovered.len() >= MAX_DISCOVERED {
+                break;
+            }
+            let registration = WireRegistration {
+                peer_id: s.peer_id.to_base58(),
+                addrs: s.addrs.clone(),
+                ttl_secs: s.expires.saturating_duration_since(now).as_secs(),
+            };
+            // Encoded size of the registration, including the separator in the list.
+            let len = serde_json::to_vec(&registration).map_or(usize::MAX, |bytes| bytes.len() + 1);
+            if size.saturating_add(len) > DISCOVER_BUDGET {
+                continue;
+            }
+            size += len;
+            discovered.push(registration);
+        }
+        discovered
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        for stored in self.namespaces.values_mut() {
+            stored.retain(|s| s.expires > now);
+        }
+        self.namespaces.retain(|_, stored| !stored.is_empty());
+    }
+
+    fn len(&self) -> usize {
+        self.namespaces.values().map(Vec::len).sum()
+    }
+}
+
+// Network behaviour for the discovery of peers via a rendezvous point.
+// Peers register themselves under a namespace at a known rendezvous point, and discover the other
+// peers that registered under the same namespace.
+// Only nodes that act as rendezvous point accept registrations.
+pub struct Rendezvous {
+    is_point: bool,
+    registrations: Registrations,
+    // Messages that will be sent. The addresses of register requests are filled in on send.
+    outbound: VecDeque<(PeerId, Message)>,
+    events: VecDeque<RendezvousEvent>,
+}
+
+impl Rendezvous {
+    pub fn new(is_point: bool) -> Self {
+        Rendezvous {
+            is_point,
+            registrations: Registrations::default(),
+            outbound: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    // Register the local peer with its listening addresses at a connected rendezvous point.
+    pub fn register(&mut self, point: PeerId, namespace: String, ttl: Duration) {
+        let message = Message::Register {
+            namespace,
+            addrs: Vec::new(),
+            ttl_secs: ttl.as_secs(),
+        };
+        self.outbound.push_back((point, message));
+    }
+
+    pub fn unregister(&mut self, point: PeerId, namespace: String) {
+        self.outbound
+            .push_back((point, Message::Unregister { namespace }));
+    }
+
+    // Query a connected rendezvous point for the peers that registered under a namespace.
+    pub fn discover(&mut self, point: PeerId, namespace: String) {
+        self.outbound
+            .push_back((point, Message::Discover { namespace }));
+    }
+
+    // Handle a request of a remote peer, returns the response.
+    fn handle_request(&mut self, peer_id: PeerId, message: Message) -> Option<Message> {
+        match message {
+            Message::Register {
+                namespace,
+                addrs,
+                ttl_secs,
+            } => {
+                let result = match self.is_point {
+                    true => self
+                        .registrations
+                        .add(
+                            peer_id,
+                            namespace.clone(),
+                            addrs,
+                            Duration::from_secs(ttl_secs),
+                        )
+                        .map(|ttl| ttl.as_secs()),
+                    false => Err(NOT_A_POINT.into()),
+                };
+                Some(Message::RegisterResponse { namespace, result })
+            }
+            Message::Unregister { namespace } => {
+                self.registrations.remove(&peer_id, &namespace);
+                None
+            }
+            Message::Discover { namespace } => {
+                let result = match self.is_point {
+                    true => Ok(self.registrations.discover(&namespace, &peer_id)),
+                    false => Err(NOT_A_POINT.into()),
+                };
+                Some(Message::DiscoverResponse { namespace, result })
+            }
+            Message::RegisterResponse { namespace, result } => {
+                self.events.push_back(RendezvousEvent::Registered {
+                    peer_id,
+                    namespace,
+                    result: result.map(Duration::from_secs),
+                });
+                None
+            }
+            Message::DiscoverResponse { namespace, result } => {
+                self.events.push_back(RendezvousEvent::Discovered {
+                    peer_id,
+                    namespace,
+                    result: result.map(|registrations| {
+                        registrations
+                            .into_iter()
+                            .filter_map(into_registration)
+                            .collect()
+                    }),
+                });
+                None
+            }
+        }
+    }
+}
+
+// Convert a received registration, registrations with an invalid peer id are dropped.
+fn into_registration(registration: WireRegistration) -> Option<Registration> {
+    let peer_id = registration.peer_id.parse().ok()?;
+    Some(Registration {
+        peer_id,
+        addrs: registration.addrs,
+        ttl: Duration::from_secs(registration.ttl_secs),
+    })
+}
+
+// Addresses under which the local peer can be reached.
+// /p2p-circuit addresses are only dialable with the id of the local peer.
+fn local_addrs(params: &impl PollParameters) -> Vec<Multiaddr> {
+    let local_peer_id = *params.local_peer_id();
+    params
+        .external_addresses()
+        .map(|record| record.addr)
+        .chain(params.listened_addresses())
+        .map(|addr| match transport::is_relayed(&addr) {
+            true => addr.with(Protocol::P2p(local_peer_id.into())),
+            false => addr,
+        })
+        .collect()
+}
+
+impl NetworkBehaviour for Rendezvous {
+    type ProtocolsHandler = OneShotHandler<RendezvousProtocol, Message, HandlerEvent>;
+    type OutEvent = RendezvousEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        OneShotHandler::new(
+            SubstreamProtocol::new(RendezvousProtocol, ()),
+            OneShotHandlerConfig::default(),
+        )
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_event(&mut self, peer_id: PeerId, _: ConnectionId, event: HandlerEvent) {
+        if let HandlerEvent::Received(message) = event {
+            if let Some(response) = self.handle_request(peer_id, message) {
+                self.outbound.push_back((peer_id, response));
+            }
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Message, RendezvousEvent>> {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        if let Some((peer_id, mut message)) = self.outbound.pop_front() {
+            if let Message::Register {
+                namespace, addrs, ..
+            } = &mut message
+            {
+                *addrs = local_addrs(params);
+                addrs.truncate(MAX_ADDRS);
+                if addrs.is_empty() {
+                    let event = RendezvousEvent::Registered {
+                        peer_id,
+                        namespace: namespace.clone(),
+                        result: Err("The local peer is not listening on any address".into()),
+                    };
+                    return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                }
+            }
+            return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::Any,
+                event: message,
+            });
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs() -> Vec<Multiaddr> {
+        vec!["/memory/1".parse().unwrap()]
+    }
+
+    #[test]
+    fn discover_registered_peers() {
+        let mut registrations = Registrations::default();
+        let peer_a = PeerId::random();
+        let peer_b = PeerId::random();
+        let ttl = Duration::from_secs(60);
+        assert!(registrations.add(peer_a, "ns".into(), addrs(), ttl).is_ok());
+        assert!(registrations.add(peer_b, "ns".into(), addrs(), ttl).is_ok());
+        assert!(registrations
+            .add(peer_b, "other".into(), addrs(), ttl)
+            .is_ok());
+
+        // The requesting peer does not discover itself.
+        let discovered = registrations.discover("ns", &peer_a);
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered[0].peer_id, peer_b.to_base58());
+
+        // Registering again refreshes the registration instead of adding a second one.
+        assert!(registrations.add(peer_b, "ns".into(), addrs(), ttl).is_ok());
+        assert_eq!(registrations.len(), 3);
+
+        registrations.remove(&peer_b, "ns");
+        assert!(registrations.discover("ns", &peer_a).is_empty());
+    }
+
+    #[test]
+    fn invalid_registrations_are_rejected() {
+        let mut registrations = Registrations::default();
+        let peer_id = PeerId::random();
+        let ttl = Duration::from_secs(60);
+        assert!(registrations.add(peer_id, "".into(), addrs(), ttl).is_err());
+        assert!(registrations
+            .add(peer_id, "ns".into(), Vec::new(), ttl)
+            .is_err());
+        assert!(registrations
+            .add(peer_id, "ns".into(), addrs(), Duration::from_secs(0))
+            .is_err());
+        let too_many_addrs = vec![addrs()[0].clone(); MAX_ADDRS + 1];
+        assert!(registrations
+            .add(peer_id, "ns".into(), too_many_addrs, ttl)
+            .is_err());
+        let long_ttl = MAX_TTL * 2;
+        assert_eq!(
+            registrations.add(peer_id, "ns".into(), addrs(), long_ttl),
+            Ok(MAX_TTL)
+        );
+    }
+
+    #[test]
+    fn discover_response_fits_into_a_message() {
+        let mut registrations = Registrations::default();
+        let ttl = Duration::from_secs(60);
+        let addr: Multiaddr = format!("/dns4/{}/tcp/4001", "a".repeat(250))
+            .parse()
+            .unwrap();
+        for _ in 0..50 {
+            let addrs = vec![addr.clone(); MAX_ADDRS];
+            assert!(registrations
+                .add(PeerId::random(), "ns".into(), addrs, ttl)
+                .is_ok());
+        }
+
+        let discovered = registrations.discover("ns", &PeerId::random());
+        assert!(!discovered.is_empty() && discovered.len() < 50);
+        let response = Message::DiscoverResponse {
+            namespace: "n".repeat(MAX_NAMESPACE_LEN),
+            result: Ok(discovered),
+        };
+        assert!(serde_json::to_vec(&response).unwrap().len() <= MAX_MESSAGE_SIZE);
+    }
+}
@@ -0,0 +1,223 @@
+use libp2p::{
+    core::{connection::ConnectionId, ConnectedPoint},
+    request_response::{
+        handler::RequestResponseHandler, ProtocolSupport, RequestId, RequestResponse,
+        RequestResponseCodec, RequestResponseConfig, RequestResponseEvent, RequestResponseMessage,
+        ResponseChannel,
+    },
+    swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters, ProtocolsHandler},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    error, iter,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+type Request<R> = <<R as Responder>::Codec as RequestResponseCodec>::Request;
+type Response<R> = <<R as Responder>::Codec as RequestResponseCodec>::Response;
+
+// State of a request-response protocol, with which the local peer answers the requests of remote
+// peers.
+pub trait Responder {
+    type Codec: RequestResponseCodec + Default + Clone + Send + 'static;
+    type OutEvent: Send + 'static;
+
+    fn protocol() -> <Self::Codec as RequestResponseCodec>::Protocol;
+
+    // Answer a request of a remote peer. Returns None if the response is not known yet, it is then
+    // returned by `poll_response`.
+    fn respond(
+        &mut self,
+        peer_id: &PeerId,
+        request_id: RequestId,
+        request: Request<Self>,
+    ) -> Option<Response<Self>>;
+
+    // Responses to requests of remote peers that were not answered right away.
+    fn poll_response(&mut self, _cx: &mut Context<'_>) -> Poll<(RequestId, Response<Self>)> {
+        Poll::Pending
+    }
+
+    // Event for the response to a request of the local peer, or the reason why the request failed.
+    fn event(request_id: RequestId, result: Result<Response<Self>, String>) -> Self::OutEvent;
+}
+
+// Behaviour that answers the requests of remote peers with the responder, and returns the
+// responses to requests of the local peer as events.
+// Wraps the request-response protocol, which expects that it was informed about every connection
+// that closes. The swarm closes connections to banned peers without informing the behaviours that
+// they were established, so closed connections that are unknown are filtered.
+pub struct RequestResponder<R: Responder> {
+    inner: RequestResponse<R::Codec>,
+    // Connections that the inner behaviour was informed about.
+    connections: HashSet<ConnectionId>,
+    // Requests of remote peers that are answered later.
+    pending: HashMap<RequestId, ResponseChannel<Response<R>>>,
+    responder: R,
+}
+
+impl<R: Responder> RequestResponder<R> {
+    // Requests that are not answered within the timeout fail.
+    pub fn new(responder: R, request_timeout: Duration) -> Self {
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(request_timeout);
+        let protocols = iter::once((R::protocol(), ProtocolSupport::Full));
+        RequestResponder {
+            inner: RequestResponse::new(R::Codec::default(), protocols, config),
+            connections: HashSet::new(),
+            pending: HashMap::new(),
+            responder,
+        }
+    }
+
+    pub fn responder(&self) -> &R {
+        &self.responder
+    }
+
+    pub fn responder_mut(&mut self) -> &mut R {
+        &mut self.responder
+    }
+
+    // Send a request to a remote peer, the response is returned as event.
+    pub fn request(&mut self, peer_id: &PeerId, request: Request<R>) -> RequestId {
+        self.inner.send_request(peer_id, request)
+    }
+}
+
+impl<R: Responder + Send + 'static> NetworkBehaviour for RequestResponder<R> {
+    type ProtocolsHandler = RequestResponseHandler<R::Codec>;
+    type OutEvent = R::OutEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.connections.insert(*id);
+        self.inner
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        if self.connections.remove(id) {
+            self.inner.inject_connection_closed(peer_id, id, endpoint)
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        id: ConnectionId,
+        event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.inner.inject_event(peer_id, id, event)
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    // Requests of remote peers are answered once the responder knows the response, responses to
+    // requests of the local peer and failed requests are returned as events.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<Self::ProtocolsHandler as ProtocolsHandler>::InEvent, R::OutEvent>,
+    > {
+        loop {
+            // Fails if the remote peer closed the connection meanwhile.
+            while let Poll::Ready((request_id, response)) = self.responder.poll_response(cx) {
+                if let Some(channel) = self.pending.remove(&request_id) {
+                    let _ = self.inner.send_response(channel, response);
+                }
+            }
+            let event = match self.inner.poll(cx, params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)) => event,
+                Poll::Ready(action) => {
+                    return Poll::Ready(action.map_out(|_| unreachable!("Event was matched")))
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            let (request_id, result) = match event {
+                RequestResponseEvent::Message {
+                    peer,
+                    message:
+                        RequestResponseMessage::Request {
+                            request_id,
+                            request,
+                            channel,
+                        },
+                } => {
+                    match self.responder.respond(&peer, request_id, request) {
+                        // Fails if the remote peer closed the connection meanwhile.
+                        Some(response) => {
+                            let _ = self.inner.send_response(channel, response);
+                        }
+                        None => {
+                            self.pending.insert(request_id, channel);
+                        }
+                    }
+                    continue;
+                }
+                RequestResponseEvent::Message {
+                    message:
+                        RequestResponseMessage::Response {
+                            request_id,
+                            response,
+                        },
+                    ..
+                } => (request_id, Ok(response)),
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => (
+                    request_id,
+                    Err(format!("Request to peer {} failed: {:?}", peer, error)),
+                ),
+                RequestResponseEvent::InboundFailure { request_id, .. } => {
+                    self.pending.remove(&request_id);
+                    continue;
+                }
+                RequestResponseEvent::ResponseSent { .. } => continue,
+            };
+            let event = R::event(request_id, result);
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+    }
+}
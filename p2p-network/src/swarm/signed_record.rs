@@ -0,0 +1,256 @@
+use libp2p::{
+    core::{connection::ConnectionId, connection::ListenerId, ConnectedPoint},
+    identity::{Keypair, PublicKey},
+    kad::{
+        handler::{
+            KademliaHandlerEvent, KademliaHandlerIn, KademliaHandlerProto, KademliaRequestId,
+        },
+        record::{store::MemoryStore, Key as RecordKey},
+        Kademlia, KademliaEvent, QueryId, Record,
+    },
+    swarm::{NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    error, io,
+    ops::{Deref, DerefMut},
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+// Namespace for signed records.
+// The key of a signed record is `/pk/<peer-id>/<name>`, and its value has to be signed by the
+// peer with that id, so that only the owner of the namespace can write to it.
+const SIGNED_KEY_PREFIX: &str = "/pk/";
+
+// Key for a record with the given name in the namespace of a peer.
+pub fn signed_key(owner: &PeerId, name: &str) -> String {
+    format!("{}{}/{}", SIGNED_KEY_PREFIX, owner, name)
+}
+
+// Whether the key is in the namespace of signed records.
+pub fn is_signed_key(key: &str) -> bool {
+    key.starts_with(SIGNED_KEY_PREFIX)
+}
+
+// Return the owner of the namespace if the key is the key of a signed record.
+fn key_owner(key: &RecordKey) -> Option<Result<PeerId, String>> {
+    let key = std::str::from_utf8(key.as_ref()).ok()?;
+    let owner = key.strip_prefix(SIGNED_KEY_PREFIX)?.split('/').next()?;
+    let owner = PeerId::from_str(owner).map_err(|_| format!("Invalid owner {} in key", owner));
+    Some(owner)
+}
+
+// Wrap the value together with the public key and the signature over key and value.
+// Encoded as `[public key length: u16][public key][signature length: u16][signature][value]`.
+pub fn sign(keypair: &Keypair, key: &RecordKey, value: Vec<u8>) -> Result<Vec<u8>, String> {
+    let signature = keypair
+        .sign(&signed_payload(key, &value))
+        .map_err(|e| format!("Failed to sign record: {:?}", e))?;
+    let public_key = keypair.public().into_protobuf_encoding();
+    let mut signed = Vec::with_capacity(4 + public_key.len() + signature.len() + value.len());
+    for field in [public_key, signature].iter() {
+        let len = u16::try_from(field.len()).map_err(|_| "Key or signature too long")?;
+        signed.extend_from_slice(&len.to_be_bytes());
+        signed.extend_from_slice(field);
+    }
+    signed.extend_from_slice(&value);
+    Ok(signed)
+}
+
+// Verify a record if it is in the signed namespace, and return the unwrapped value.
+// Returns Ok(None) for records that are not signed.
+pub fn verify(record: &Record) -> Result<Option<Vec<u8>>, String> {
+    let owner = match key_owner(&record.key) {
+        Some(owner) => owner?,
+        None => return Ok(None),
+    };
+    let (public_key, rest) = split_field(&record.value)?;
+    let (signature, value) = split_field(rest)?;
+    let public_key = PublicKey::from_protobuf_encoding(public_key)
+        .map_err(|e| format!("Invalid public key: {:?}", e))?;
+    if !public_key.verify(&signed_payload(&record.key, value), signature) {
+        return Err("Invalid signature".into());
+    }
+    if public_key.into_peer_id() != owner {
+        return Err("Record was not signed by the owner of the key".into());
+    }
+    Ok(Some(value.to_vec()))
+}
+
+// The signature covers key and value, so that a signed value can not be stored under a
+// different key.
+fn signed_payload(key: &RecordKey, value: &[u8]) -> Vec<u8> {
+    let mut payload = key.to_vec();
+    payload.extend_from_slice(value);
+    payload
+}
+
+// Split a length-prefixed field from the start of the bytes.
+fn split_field(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let invalid = || String::from("Invalid signed record encoding");
+    if bytes.len() < 2 {
+        return Err(invalid());
+    }
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let rest = &bytes[2..];
+    (rest.len() >= len)
+        .then(|| rest.split_at(len))
+        .ok_or_else(invalid)
+}
+
+// Kademlia behaviour that rejects signed records with an invalid signature before they reach the
+// record store.
+// Kademlia stores the records that remote peers send in the store, hence a peer that tries to
+// overwrite a key in the namespace of another peer would otherwise succeed. The request of such a
+// peer is reset, without storing the record or logging it, so that the peer can't flood the log.
+pub struct ValidatingKademlia {
+    inner: Kademlia<MemoryStore>,
+    // Resets of rejected requests, that are sent to the handlers on the next poll.
+    rejected: VecDeque<(PeerId, ConnectionId, KademliaRequestId)>,
+}
+
+impl ValidatingKademlia {
+    pub fn new(inner: Kademlia<MemoryStore>) -> Self {
+        ValidatingKademlia {
+            inner,
+            rejected: VecDeque::new(),
+        }
+    }
+}
+
+impl Deref for ValidatingKademlia {
+    type Target = Kademlia<MemoryStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for ValidatingKademlia {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl NetworkBehaviour for ValidatingKademlia {
+    type ProtocolsHandler = KademliaHandlerProto<QueryId>;
+    type OutEvent = KademliaEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner.inject_connection_closed(peer_id, id, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner.inject_address_change(peer_id, id, old, new)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: KademliaHandlerEvent<QueryId>,
+    ) {
+        match event {
+            KademliaHandlerEvent::PutRecord { record, request_id } if verify(&record).is_err() => {
+                self.rejected.push_back((peer_id, connection, request_id))
+            }
+            event => self.inner.inject_event(peer_id, connection, event),
+        }
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listener(&mut self, id: ListenerId) {
+        self.inner.inject_new_listener(id)
+    }
+
+    fn inject_new_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+        self.inner.inject_new_listen_addr(id, addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+        self.inner.inject_expired_listen_addr(id, addr)
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn error::Error + 'static)) {
+        self.inner.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &io::Error>) {
+        self.inner.inject_listener_closed(id, reason)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_external_addr(addr)
+    }
+
+    fn inject_expired_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_expired_external_addr(addr)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<KademliaHandlerIn<QueryId>, KademliaEvent>> {
+        if let Some((peer_id, connection, request_id)) = self.rejected.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(connection),
+                event: KademliaHandlerIn::Reset(request_id),
+            });
+        }
+        self.inner.poll(cx, params)
+    }
+}
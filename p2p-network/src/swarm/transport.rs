@@ -1,38 +1,168 @@
+use crate::config::{TransportConfig, YamuxLimits};
+#[cfg(feature = "websocket")]
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+    task::{Context, Poll},
+};
+#[cfg(feature = "websocket")]
+use libp2p::websocket::WsConfig;
 use libp2p::{
     core::{
+        either::{EitherOutput, EitherTransport},
         muxing::StreamMuxerBox,
-        transport::{self, upgrade::Version},
+        transport::{self, upgrade::Version, MemoryTransport},
     },
     dns::DnsConfig,
     identity::Keypair,
+    multiaddr::Protocol,
     noise::{self, NoiseConfig},
+    pnet::{PnetConfig, PreSharedKey},
+    relay::{self, Relay, RelayConfig},
     tcp::TcpConfig,
     yamux::YamuxConfig,
-    PeerId, Transport,
+    Multiaddr, PeerId, Transport,
 };
+#[cfg(feature = "websocket")]
+use std::{io, pin::Pin};
 
 use core::time::Duration;
+use std::{fs, str::FromStr};
 
 // Low transport layer that is used in the swarm.
 pub struct TransportLayer {
     keypair: Keypair,
+    // Pre-shared key of the private network, if the node should only connect to peers that
+    // use the same key.
+    psk: Option<PreSharedKey>,
+    // Use the in-memory transport instead of TCP.
+    memory: bool,
+    // Yamux config with the configured limits.
+    yamux: YamuxConfig,
+    // Addresses on which the swarm should listen.
+    listen_addrs: Vec<Multiaddr>,
+    // Idle timeout of relayed connections, if the relay protocol is enabled.
+    relay_idle_timeout: Option<Duration>,
+    // /p2p-circuit addresses on which the swarm listens via relays.
+    relay_listen_addrs: Vec<Multiaddr>,
 }
 
 impl TransportLayer {
-    // Create a new TransportLayer with a new generated ed25519 keypair
-    pub fn new() -> Self {
+    // Create a new TransportLayer with a new generated ed25519 keypair.
+    // If a swarm key file is configured, the pre-shared key is read from it.
+    // Apart from TCP, the node listens on the configured WebSocket address.
+    // In memory mode, the node only listens on an in-memory address.
+    // If relays are configured, the node additionally listens via each relay.
+    pub fn new(config: &TransportConfig) -> Result<Self, String> {
+        let psk = match &config.swarm_key_file {
+            Some(path) => {
+                let key = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read swarm key file {}: {}", path, e))?;
+                let psk = PreSharedKey::from_str(&key)
+                    .map_err(|e| format!("Invalid swarm key file {}: {:?}", path, e))?;
+                Some(psk)
+            }
+            None => None,
+        };
+        let mut transport = TransportLayer::with_options(psk, config.memory);
+        transport.yamux = yamux_config(&config.yamux)?;
+        if let Some(addrs) = &config.listen_addrs {
+            transport.listen_addrs = addrs.clone();
+        }
+        if let Some(addr) = &config.ws_listen_addr {
+            if config.memory {
+                return Err("WebSocket is not supported with the in-memory transport".into());
+            }
+            transport.listen_addrs.push(ws_listen_addr(addr)?);
+        }
+        let relay = &config.relay;
+        if relay.enabled {
+            transport.relay_idle_timeout =
+                Some(Duration::from_secs(relay.connection_idle_timeout_secs));
+        } else if !relay.relays.is_empty() {
+            return Err("Listening via relays requires that the relay protocol is enabled".into());
+        }
+        for addr in relay.relays.iter() {
+            transport
+                .relay_listen_addrs
+                .push(circuit_listen_addr(addr)?);
+        }
+        Ok(transport)
+    }
+
+    fn with_options(psk: Option<PreSharedKey>, memory: bool) -> Self {
         let keypair = Keypair::generate_ed25519();
-        TransportLayer { keypair }
+        // Listen to a TCP or memory address assigned by the OS, respectively the transport.
+        let listen_addr = if memory {
+            "/memory/0"
+        } else {
+            "/ip4/0.0.0.0/tcp/0"
+        };
+        TransportLayer {
+            keypair,
+            psk,
+            memory,
+            yamux: YamuxConfig::default(),
+            listen_addrs: vec![listen_addr.parse().unwrap()],
+            relay_idle_timeout: None,
+            relay_listen_addrs: Vec::new(),
+        }
     }
 
     // Create a libp2p transport using TCP with DNS wrapper to allow dns addresses.
+    // With the `websocket` feature, WebSocket connections over the same TCP transport are
+    // supported as alternative.
+    // In memory mode, the in-memory transport is used instead, so that only nodes within the same
+    // process can connect, e.g. in tests.
+    // If the relay protocol is enabled, the transport is wrapped so that /p2p-circuit addresses
+    // are dialed and listened on via a relay. The relay behaviour is returned as well, and has to
+    // be part of the swarm's network behaviour.
+    // In a private network, the connection is encrypted with the pre-shared key before any
+    // protocol is negotiated, so that peers with a different key fail the handshake.
     // Upgrade transport with noise-protocol for encryption and Yamux multiplexing.
-    pub async fn build(&self) -> transport::Boxed<(PeerId, StreamMuxerBox)> {
-        // TCP protocol for sending data
-        let tcp_config = TcpConfig::new().nodelay(true);
+    pub async fn build(&self) -> (transport::Boxed<(PeerId, StreamMuxerBox)>, Option<Relay>) {
+        let transport = if self.memory {
+            EitherTransport::Left(MemoryTransport)
+        } else {
+            // TCP protocol for sending data
+            let tcp_config = TcpConfig::new().nodelay(true);
+
+            // Enable dns adresses in multiaddress
+            let transport = DnsConfig::system(tcp_config).await.unwrap();
 
-        // Enable dns adresses in multiaddress
-        let transport = DnsConfig::system(tcp_config).await.unwrap();
+            // Use WebSocket for `/ws` addresses, and plain TCP for all others
+            #[cfg(feature = "websocket")]
+            let transport = WsConfig::new(transport.clone())
+                .map(|conn, _| FlushOnRead(conn))
+                .or_transport(transport);
+
+            EitherTransport::Right(transport)
+        };
+
+        // Relay connections via other nodes
+        let (transport, relay) = match self.relay_idle_timeout {
+            Some(timeout) => {
+                let config = RelayConfig {
+                    connection_idle_timeout: timeout,
+                    ..Default::default()
+                };
+                let (transport, relay) = relay::new_transport_and_behaviour(config, transport);
+                (EitherTransport::Left(transport), Some(relay))
+            }
+            None => (EitherTransport::Right(transport), None),
+        };
+
+        // Private network handshake with the pre-shared key
+        let psk = self.psk;
+        let transport = transport.and_then(move |socket, _| async move {
+            match psk {
+                Some(psk) => PnetConfig::new(psk)
+                    .handshake(socket)
+                    .await
+                    .map(EitherOutput::First),
+                None => Ok(EitherOutput::Second(socket)),
+            }
+        });
 
         // Noise encryption with Diffie-Hellman key exchange
         let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
@@ -40,12 +170,13 @@ impl TransportLayer {
             .unwrap();
 
         // Upgrade the transport with Yamux multiplexing and noise-authentication with xx-handshake
-        transport
+        let transport = transport
             .upgrade(Version::V1)
             .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
-            .multiplex(YamuxConfig::default())
+            .multiplex(self.yamux.clone())
             .timeout(Duration::from_secs(30))
-            .boxed()
+            .boxed();
+        (transport, relay)
     }
 
     // Return the underlying keypair.
@@ -59,4 +190,222 @@ impl TransportLayer {
     pub fn local_peer_id(&self) -> PeerId {
         PeerId::from(self.keypair.public())
     }
+
+    // Addresses on which the swarm should listen.
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.listen_addrs.clone()
+    }
+
+    // Addresses on which the swarm should listen via relays.
+    pub fn relay_listen_addrs(&self) -> Vec<Multiaddr> {
+        self.relay_listen_addrs.clone()
+    }
+}
+
+// WebSocket connection that flushes written data before reading.
+// Data written to a WebSocket is only sent as frame on flush, but the private network handshake
+// writes its nonce without flushing it and then waits for the nonce of the remote.
+#[cfg(feature = "websocket")]
+struct FlushOnRead<S>(S);
+
+#[cfg(feature = "websocket")]
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for FlushOnRead<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.0).poll_flush(cx))?;
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for FlushOnRead<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+// Create the yamux config with the configured limits.
+fn yamux_config(limits: &YamuxLimits) -> Result<YamuxConfig, String> {
+    // Yamux panics for smaller receive windows.
+    if limits.receive_window_size < 256 * 1024 {
+        return Err("Yamux receive window size has to be at least 256 KiB".into());
+    }
+    let mut config = YamuxConfig::default();
+    config
+        .set_receive_window_size(limits.receive_window_size)
+        .set_max_buffer_size(limits.max_buffer_size)
+        .set_max_num_streams(limits.max_num_streams);
+    Ok(config)
+}
+
+// Whether the address is a WebSocket address.
+pub fn is_websocket(addr: &Multiaddr) -> bool {
+    addr.iter()
+        .any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_)))
+}
+
+// Whether the address is a /p2p-circuit address, i.g. connections to it are relayed.
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::P2pCircuit))
+}
+
+// Create the address on which the node listens via a relay.
+// The relay address has to contain the id of the relay.
+fn circuit_listen_addr(relay_addr: &Multiaddr) -> Result<Multiaddr, String> {
+    match relay_addr.iter().last() {
+        Some(Protocol::P2p(_)) => Ok(relay_addr.clone().with(Protocol::P2pCircuit)),
+        _ => Err(format!(
+            "Relay address {} has to end with /p2p/<peer-id>",
+            relay_addr
+        )),
+    }
+}
+
+// Parse the configured WebSocket listening address.
+fn ws_listen_addr(addr: &str) -> Result<Multiaddr, String> {
+    if !cfg!(feature = "websocket") {
+        return Err("WebSocket support requires the `websocket` feature".into());
+    }
+    let addr = Multiaddr::from_str(addr)
+        .map_err(|e| format!("Invalid WebSocket address {}: {}", addr, e))?;
+    if !is_websocket(&addr) {
+        return Err(format!("Not a WebSocket address: {}", addr));
+    }
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use futures::prelude::*;
+    use libp2p::core::transport::ListenerEvent;
+
+    // Listen with one transport layer and dial it from another one.
+    // Returns whether both sides successfully upgraded the connection.
+    async fn connect(listener_psk: Option<PreSharedKey>, dialer_psk: Option<PreSharedKey>) -> bool {
+        connect_on("/ip4/127.0.0.1/tcp/0", listener_psk, dialer_psk).await
+    }
+
+    async fn connect_on(
+        listen_addr: &str,
+        listener_psk: Option<PreSharedKey>,
+        dialer_psk: Option<PreSharedKey>,
+    ) -> bool {
+        let is_memory = listen_addr.starts_with("/memory");
+        let listener = TransportLayer::with_options(listener_psk, is_memory)
+            .build()
+            .await
+            .0;
+        let dialer = TransportLayer::with_options(dialer_psk, is_memory)
+            .build()
+            .await
+            .0;
+
+        let mut listener = listener.listen_on(listen_addr.parse().unwrap()).unwrap();
+        let addr = match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+            _ => panic!("Listener did not report its address"),
+        };
+
+        // Accept the incoming connection and upgrade it.
+        let accept = async move {
+            loop {
+                if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+                    return upgrade.await.is_ok();
+                }
+            }
+        };
+        let dial = async move { dialer.dial(addr).unwrap().await.is_ok() };
+        let (accepted, dialed) = future::join(accept, dial).await;
+        accepted && dialed
+    }
+
+    #[test]
+    fn same_psk_connects() {
+        let psk = PreSharedKey::new([1; 32]);
+        assert!(task::block_on(connect(Some(psk), Some(psk))));
+    }
+
+    #[test]
+    fn no_psk_connects() {
+        assert!(task::block_on(connect(None, None)));
+    }
+
+    #[test]
+    fn different_psk_fails() {
+        let psk_a = PreSharedKey::new([1; 32]);
+        let psk_b = PreSharedKey::new([2; 32]);
+        assert!(!task::block_on(connect(Some(psk_a), Some(psk_b))));
+    }
+
+    #[test]
+    fn psk_and_public_peer_fail() {
+        let psk = PreSharedKey::new([1; 32]);
+        assert!(!task::block_on(connect(Some(psk), None)));
+    }
+
+    #[test]
+    fn memory_connects() {
+        let psk = PreSharedKey::new([1; 32]);
+        assert!(task::block_on(connect_on(
+            "/memory/0",
+            Some(psk),
+            Some(psk)
+        )));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn websocket_connects() {
+        let psk = PreSharedKey::new([1; 32]);
+        assert!(task::block_on(connect_on(
+            "/ip4/127.0.0.1/tcp/0/ws",
+            Some(psk),
+            Some(psk)
+        )));
+    }
+
+    #[test]
+    fn ws_listen_addr_is_validated() {
+        assert!(ws_listen_addr("/ip4/0.0.0.0/tcp/0").is_err());
+        assert_eq!(
+            ws_listen_addr("/ip4/0.0.0.0/tcp/8080/ws").is_ok(),
+            cfg!(feature = "websocket")
+        );
+    }
+
+    #[test]
+    fn circuit_listen_addr_requires_relay_id() {
+        assert!(circuit_listen_addr(&"/ip4/10.0.0.1/tcp/4001".parse().unwrap()).is_err());
+        let relay: Multiaddr =
+            "/ip4/10.0.0.1/tcp/4001/p2p/12D3KooWQGKtjQbm1A8L7omSywQSnHzLRVRWUyfKS5rDgGoyVA7f"
+                .parse()
+                .unwrap();
+        let addr = circuit_listen_addr(&relay).unwrap();
+        assert_eq!(addr, relay.with(Protocol::P2pCircuit));
+        assert!(is_relayed(&addr));
+    }
+
+    #[test]
+    fn parse_swarm_key_file() {
+        let key = "/key/swarm/psk/1.0.0/\n/base16/\n\
+                   0101010101010101010101010101010101010101010101010101010101010101\n";
+        assert!(PreSharedKey::from_str(key).ok() == Some(PreSharedKey::new([1; 32])));
+    }
 }
@@ -0,0 +1,269 @@
+// Download of the payload of an update in the background.
+// Unlike the fetch command, which polls the swarm until the file was downloaded, the download is
+// driven by the behaviour events that the swarm task receives anyway, so that commands are still
+// handled while the payload is fetched.
+
+use super::{
+    behaviour::{Behaviour, BehaviourEvent},
+    file_exchange::{self, Download, FileExchangeEvent, FileRequest, FileResponse, Manifest},
+};
+use crate::types::{FetchInfo, UpdateManifest};
+use libp2p::{
+    kad::{GetProvidersError, GetProvidersOk, KademliaEvent, QueryId, QueryResult},
+    request_response::RequestId,
+    swarm::NetworkBehaviour,
+    PeerId, Swarm,
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Outcome of a download, once it completed or failed.
+type Fetched = Option<Result<FetchInfo, String>>;
+
+enum State {
+    // Looking up the providers of the payload in the DHT.
+    Lookup(QueryId),
+    // Looking up the address of the current provider in the DHT.
+    Locate(QueryId),
+    // Waiting for the manifest from the current provider.
+    Manifest(RequestId),
+    // Waiting for the first missing chunk from the current provider.
+    Chunk(RequestId),
+}
+
+// Payload of an update that is being fetched.
+// Like with the fetch command, the manifest is verified against the root hash and each chunk
+// against the manifest, and the download continues with the next provider if one fails.
+pub struct UpdateFetch {
+    pub manifest: UpdateManifest,
+    // Path to which the payload is fetched.
+    pub path: String,
+    timeout: Duration,
+    deadline: Instant,
+    state: State,
+    // Providers that were not asked yet.
+    providers: VecDeque<PeerId>,
+    provider: Option<PeerId>,
+    // Partial file, once the manifest was received.
+    download: Option<(Download, FetchInfo)>,
+    missing: VecDeque<usize>,
+    last_err: String,
+}
+
+impl UpdateFetch {
+    // Look up the providers of the payload. The download fails if it did not complete within the
+    // timeout.
+    pub fn start(
+        swarm: &mut Swarm<Behaviour>,
+        manifest: UpdateManifest,
+        path: String,
+        timeout: Duration,
+    ) -> Self {
+        let key = file_exchange::provider_key(&manifest.root);
+        let query_id = swarm.behaviour_mut().get_providers(key);
+        let last_err = format!("No provider of {} could be reached", manifest.root);
+        UpdateFetch {
+            manifest,
+            path,
+            timeout,
+            deadline: Instant::now() + timeout,
+            state: State::Lookup(query_id),
+            providers: VecDeque::new(),
+            provider: None,
+            download: None,
+            missing: VecDeque::new(),
+            last_err,
+        }
+    }
+
+    // Time until the download times out.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    // Fails the download if the timeout elapsed.
+    pub fn check_timeout(&self) -> Fetched {
+        match Instant::now() >= self.deadline {
+            true => Some(Err(format!(
+                "Fetching the payload did not complete within {}s",
+                self.timeout.as_secs()
+            ))),
+            false => None,
+        }
+    }
+
+    // Advance the download with a behaviour event. Events that do not belong to the download are
+    // ignored. Returns the outcome once the download completed or failed.
+    pub fn inject_event(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        event: &BehaviourEvent,
+    ) -> Fetched {
+        if let Some(res) = self.check_timeout() {
+            return Some(res);
+        }
+        match (&self.state, event) {
+            (
+                State::Lookup(query_id),
+                BehaviourEvent::Kademlia(KademliaEvent::QueryResult { id, result, .. }),
+            ) if id == query_id => self.on_providers(swarm, result),
+            (
+                State::Locate(query_id),
+                BehaviourEvent::Kademlia(KademliaEvent::QueryResult { id, .. }),
+            ) if id == query_id => {
+                // The request dials the provider, and fails if no address was found.
+                self.request(swarm);
+                None
+            }
+            (
+                State::Manifest(request_id),
+                BehaviourEvent::FileExchange(FileExchangeEvent {
+                    request_id: id,
+                    result,
+                }),
+            ) if id == request_id => self.on_manifest(swarm, result),
+            (
+                State::Chunk(request_id),
+                BehaviourEvent::FileExchange(FileExchangeEvent {
+                    request_id: id,
+                    result,
+                }),
+            ) if id == request_id => self.on_chunk(swarm, result),
+            _ => None,
+        }
+    }
+
+    fn on_providers(&mut self, swarm: &mut Swarm<Behaviour>, result: &QueryResult) -> Fetched {
+        let providers = match result {
+            QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. }))
+            | QueryResult::GetProviders(Err(GetProvidersError::Timeout { providers, .. })) => {
+                providers
+            }
+            _ => return None,
+        };
+        let local_peer_id = *swarm.local_peer_id();
+        self.providers = providers
+            .iter()
+            .filter(|peer| **peer != local_peer_id)
+            .cloned()
+            .collect();
+        if providers.is_empty() {
+            self.last_err = format!("No providers of {} were found", self.manifest.root);
+        }
+        self.next_provider(swarm)
+    }
+
+    fn on_manifest(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        result: &Result<FileResponse, String>,
+    ) -> Fetched {
+        let root = &self.manifest.root;
+        let manifest = match result {
+            Ok(FileResponse::Manifest(bytes)) => Manifest::decode(root, bytes),
+            Ok(_) => Err(self.not_shared()),
+            Err(err) => Err(err.clone()),
+        };
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                self.last_err = err;
+                return self.next_provider(swarm);
+            }
+        };
+        let mut download = match Download::open(&self.path, manifest.clone()) {
+            Ok(download) => download,
+            Err(err) => return Some(Err(err)),
+        };
+        self.missing = download.missing_chunks().into();
+        let info = FetchInfo {
+            size: manifest.size,
+            chunks: manifest.chunks.len(),
+            resumed: manifest.chunks.len() - self.missing.len(),
+        };
+        self.download = Some((download, info));
+        self.next_chunk(swarm)
+    }
+
+    fn on_chunk(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        result: &Result<FileResponse, String>,
+    ) -> Fetched {
+        let index = *self.missing.front()?;
+        let res = match (result, &mut self.download) {
+            (Ok(FileResponse::Chunk(chunk)), Some((download, _))) => {
+                download.write_chunk(index, chunk)
+            }
+            (Ok(_), _) => Err(self.not_shared()),
+            (Err(err), _) => Err(err.clone()),
+        };
+        match res {
+            Ok(()) => {
+                self.missing.pop_front();
+                self.next_chunk(swarm)
+            }
+            // The chunks that were received so far are kept in the partial file.
+            Err(err) => {
+                self.last_err = err;
+                self.next_provider(swarm)
+            }
+        }
+    }
+
+    // Request the next missing chunk, or move the complete file to its path.
+    fn next_chunk(&mut self, swarm: &mut Swarm<Behaviour>) -> Fetched {
+        if !self.missing.is_empty() {
+            self.request(swarm);
+            return None;
+        }
+        let (download, info) = self.download.take()?;
+        Some(download.finish().map(|_| info))
+    }
+
+    // Continue with the next provider, fails if all of them were tried.
+    // If no address of the provider is known, it is looked up in the DHT first.
+    fn next_provider(&mut self, swarm: &mut Swarm<Behaviour>) -> Fetched {
+        let peer_id = match self.providers.pop_front() {
+            Some(peer_id) => peer_id,
+            None => return Some(Err(self.last_err.clone())),
+        };
+        self.provider = Some(peer_id);
+        if !swarm.is_connected(&peer_id)
+            && swarm.behaviour_mut().addresses_of_peer(&peer_id).is_empty()
+        {
+            let query_id = swarm.behaviour_mut().get_closest_peers(peer_id);
+            self.state = State::Locate(query_id);
+            return None;
+        }
+        self.request(swarm);
+        None
+    }
+
+    // Request the manifest from the current provider, or the next missing chunk once the manifest
+    // was received.
+    fn request(&mut self, swarm: &mut Swarm<Behaviour>) {
+        let peer_id = match self.provider {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        let root = self.manifest.root.clone();
+        let behaviour = swarm.behaviour_mut();
+        self.state = match (&self.download, self.missing.front()) {
+            (Some(_), Some(&index)) => {
+                let request = FileRequest::Chunk { root, index };
+                State::Chunk(behaviour.request_file(&peer_id, request))
+            }
+            _ => State::Manifest(behaviour.request_file(&peer_id, FileRequest::Manifest { root })),
+        };
+    }
+
+    fn not_shared(&self) -> String {
+        match self.provider {
+            Some(peer_id) => format!("Peer {} does not share {}", peer_id, self.manifest.root),
+            None => format!("{} is not shared", self.manifest.root),
+        }
+    }
+}
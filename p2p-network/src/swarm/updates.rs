@@ -0,0 +1,390 @@
+use crate::{config::UpdatesConfig, types::*};
+use async_std::task;
+use futures::Future;
+use libp2p::{
+    identity::{ed25519, Keypair, PublicKey},
+    PeerId,
+};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Gossipsub topic on which releases are announced.
+pub const UPDATES_TOPIC: &str = "updates";
+
+// Prefix of the signed data, so that the signature of a manifest can not be used for other data
+// that is signed with the same key.
+const SIGNATURE_DOMAIN: &[u8] = b"/p2p-network/update/1.0.0/";
+
+// Number of announced updates that are kept, the oldest ones that are no longer pending are
+// removed first.
+const MAX_UPDATES: usize = 32;
+
+// Interval in which it is checked whether the apply hook exited.
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Verification and installation of the updates that maintainers announce.
+pub struct Updates {
+    // Maintainers whose releases are installed.
+    trusted: Vec<PeerId>,
+    // Key with which the local node signs releases.
+    signing_key: Option<Keypair>,
+    target: String,
+    // Installed version, i.g. the configured one or the last applied update.
+    version: Vec<u64>,
+    staging_dir: PathBuf,
+    apply_hook: Vec<String>,
+    // Time after which the apply hook is killed.
+    apply_timeout: Duration,
+    // Announced updates, most recent last.
+    updates: Vec<UpdateInfo>,
+}
+
+impl Updates {
+    pub fn new(config: &UpdatesConfig) -> Result<Self, String> {
+        let trusted = config
+            .trusted_publishers
+            .iter()
+            .map(|peer| {
+                PeerId::from_str(peer).map_err(|_| format!("Invalid trusted publisher {}", peer))
+            })
+            .collect::<Result<_, _>>()?;
+        let signing_key = match &config.signing_key_file {
+            Some(path) => Some(load_signing_key(path)?),
+            None => None,
+        };
+        let version = parse_version(&config.version)
+            .map_err(|e| format!("Invalid installed version: {}", e))?;
+        Ok(Updates {
+            trusted,
+            signing_key,
+            target: config.target.clone(),
+            version,
+            staging_dir: config.staging_dir.clone().into(),
+            apply_hook: config.apply_hook.clone(),
+            apply_timeout: config.apply_timeout()?,
+            updates: Vec::new(),
+        })
+    }
+
+    // Whether the node installs updates, i.g. trusts at least one maintainer.
+    pub fn is_enabled(&self) -> bool {
+        !self.trusted.is_empty()
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    // Sign the manifest of a release with the signing key of the local node.
+    pub fn sign(&self, manifest: UpdateManifest) -> Result<SignedUpdate, String> {
+        parse_version(&manifest.version)?;
+        let keypair = self
+            .signing_key
+            .as_ref()
+            .ok_or("No signing key is configured")?;
+        let signature = keypair
+            .sign(&signed_payload(&manifest))
+            .map_err(|e| format!("Failed to sign manifest: {:?}", e))?;
+        Ok(SignedUpdate {
+            manifest,
+            public_key: keypair.public().into_protobuf_encoding(),
+            signature,
+        })
+    }
+
+    // Peer id of the signing key of the local node.
+    pub fn publisher(&self) -> Option<PeerId> {
+        self.signing_key.as_ref().map(|k| k.public().into_peer_id())
+    }
+
+    // Verify an announced update, and queue it for installation.
+    // Fails if it is not signed by a trusted maintainer, or is not newer than the installed
+    // version. An update that failed before is queued again.
+    pub fn announce(&mut self, update: SignedUpdate) -> Result<(), String> {
+        let publisher = verify(&update)?;
+        if !self.trusted.contains(&publisher) {
+            return Err(format!("Publisher {} is not trusted", publisher));
+        }
+        let manifest = update.manifest;
+        if manifest.target != self.target {
+            return Err(format!("Update is for target {}", manifest.target));
+        }
+        if parse_version(&manifest.version)? <= self.version {
+            return Err(format!("Version {} is not newer", manifest.version));
+        }
+        match self.updates.iter_mut().find(|u| u.manifest == manifest) {
+            Some(info) if matches!(info.state, UpdateState::Failed(_)) => {
+                info.state = UpdateState::Pending
+            }
+            Some(_) => {}
+            None => {
+                self.updates.push(UpdateInfo {
+                    manifest,
+                    publisher,
+                    state: UpdateState::Pending,
+                });
+                if self.updates.len() > MAX_UPDATES {
+                    if let Some(index) = self.updates.iter().position(|u| {
+                        !matches!(
+                            u.state,
+                            UpdateState::Pending | UpdateState::Fetching | UpdateState::Applying
+                        )
+                    }) {
+                        self.updates.remove(index);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Pending update with the highest version, and the path to which its payload is fetched.
+    pub fn next_pending(&self) -> Option<(UpdateManifest, String)> {
+        let manifest = self
+            .updates
+            .iter()
+            .filter(|u| u.state == UpdateState::Pending)
+            .map(|u| &u.manifest)
+            .max_by_key(|m| parse_version(&m.version).unwrap_or_default())?;
+        let file_name = format!("{}-{}", manifest.target, manifest.version);
+        let path = self.staging_dir.join(file_name);
+        Some((manifest.clone(), path.to_string_lossy().into_owned()))
+    }
+
+    pub fn set_state(&mut self, manifest: &UpdateManifest, state: UpdateState) {
+        if let Some(info) = self.updates.iter_mut().find(|u| &u.manifest == manifest) {
+            info.state = state;
+        }
+    }
+
+    // Create the staging directory before the payload of an update is fetched.
+    pub fn prepare_staging(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.staging_dir).map_err(|e| {
+            format!(
+                "Failed to create staging directory {}: {}",
+                self.staging_dir.display(),
+                e
+            )
+        })
+    }
+
+    // Apply a staged update. Without apply hook, the update is only staged. Otherwise the update
+    // is applying and the returned future runs the hook with the path of the staged payload and
+    // the version, so that it can be awaited in the background. Its outcome is passed to
+    // `finish`.
+    pub fn apply(
+        &mut self,
+        manifest: &UpdateManifest,
+        path: &str,
+    ) -> Option<impl Future<Output = UpdateState>> {
+        let (program, args) = match self.apply_hook.split_first() {
+            Some(hook) => hook,
+            None => {
+                self.set_state(manifest, UpdateState::Staged(path.into()));
+                return None;
+            }
+        };
+        let mut command = process::Command::new(program);
+        command.args(args).arg(path).arg(&manifest.version);
+        self.set_state(manifest, UpdateState::Applying);
+        Some(run_hook(command, self.apply_timeout))
+    }
+
+    // Whether the payload of an update is fetched, or the apply hook is running for it.
+    pub fn is_installing(&self) -> bool {
+        self.updates
+            .iter()
+            .any(|u| matches!(u.state, UpdateState::Fetching | UpdateState::Applying))
+    }
+
+    // Set the outcome of the apply hook.
+    // Pending updates with an older version are dropped once an update was applied.
+    pub fn finish(&mut self, manifest: &UpdateManifest, state: UpdateState) {
+        if state == UpdateState::Applied {
+            self.version = parse_version(&manifest.version).unwrap_or_default();
+            let superseded = format!("Superseded by version {}", manifest.version);
+            for info in self.updates.iter_mut() {
+                if info.state == UpdateState::Pending {
+                    info.state = UpdateState::Failed(superseded.clone());
+                }
+            }
+        }
+        self.set_state(manifest, state);
+    }
+
+    pub fn updates(&self) -> Vec<UpdateInfo> {
+        self.updates.clone()
+    }
+}
+
+// Run the apply hook without blocking the swarm task, and kill it once the timeout elapsed.
+async fn run_hook(mut command: process::Command, timeout: Duration) -> UpdateState {
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => return UpdateState::Failed(format!("Failed to run apply hook: {}", e)),
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return UpdateState::Applied,
+            Ok(Some(status)) => {
+                return UpdateState::Failed(format!("Apply hook failed with {}", status))
+            }
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return UpdateState::Failed(format!(
+                    "Apply hook did not exit within {}s",
+                    timeout.as_secs()
+                ));
+            }
+            Ok(None) => task::sleep(HOOK_POLL_INTERVAL).await,
+            Err(e) => return UpdateState::Failed(format!("Failed to wait for apply hook: {}", e)),
+        }
+    }
+}
+
+// Verify the signature of an announced update, and return the peer id of the signing key.
+pub fn verify(update: &SignedUpdate) -> Result<PeerId, String> {
+    let public_key = PublicKey::from_protobuf_encoding(&update.public_key)
+        .map_err(|e| format!("Invalid public key: {:?}", e))?;
+    if !public_key.verify(&signed_payload(&update.manifest), &update.signature) {
+        return Err("Invalid signature".into());
+    }
+    Ok(public_key.into_peer_id())
+}
+
+fn signed_payload(manifest: &UpdateManifest) -> Vec<u8> {
+    let mut payload = SIGNATURE_DOMAIN.to_vec();
+    payload.extend(serde_json::to_vec(manifest).expect("Could not serialize manifest."));
+    payload
+}
+
+// Parse a version of the format <major>.<minor>.<patch> into its components, so that versions
+// can be compared.
+fn parse_version(version: &str) -> Result<Vec<u64>, String> {
+    version
+        .split('.')
+        .map(u64::from_str)
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid version {}", version))
+}
+
+// Load the ed25519 signing key from a file, generate and store a new one if the file does not
+// exist yet.
+fn load_signing_key(path: &str) -> Result<Keypair, String> {
+    let keypair = match fs::read(path) {
+        Ok(mut bytes) => ed25519::Keypair::decode(&mut bytes)
+            .map_err(|e| format!("Invalid signing key {}: {:?}", path, e))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let keypair = ed25519::Keypair::generate();
+            if let Some(dir) = Path::new(path).parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            fs::write(path, &keypair.encode()[..])
+                .map_err(|e| format!("Failed to write signing key {}: {}", path, e))?;
+            keypair
+        }
+        Err(e) => return Err(format!("Failed to read signing key {}: {}", path, e)),
+    };
+    Ok(Keypair::Ed25519(keypair))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(version: &str) -> UpdateManifest {
+        UpdateManifest {
+            version: version.into(),
+            target: "riscv64gc-unknown-linux-gnu".into(),
+            root: "00".repeat(32),
+            size: 1024,
+        }
+    }
+
+    #[test]
+    fn only_newer_signed_updates_are_accepted() {
+        let signing_key = Keypair::generate_ed25519();
+        let maintainer = Updates {
+            trusted: Vec::new(),
+            signing_key: Some(signing_key.clone()),
+            target: String::new(),
+            version: Vec::new(),
+            staging_dir: PathBuf::new(),
+            apply_hook: Vec::new(),
+            apply_timeout: Duration::from_secs(1),
+            updates: Vec::new(),
+        };
+        let mut node = Updates {
+            trusted: vec![signing_key.public().into_peer_id()],
+            signing_key: None,
+            target: "riscv64gc-unknown-linux-gnu".into(),
+            version: vec![1, 2, 0],
+            staging_dir: PathBuf::new(),
+            apply_hook: Vec::new(),
+            apply_timeout: Duration::from_secs(1),
+            updates: Vec::new(),
+        };
+
+        node.announce(maintainer.sign(manifest("1.10.0")).unwrap())
+            .unwrap();
+        assert_eq!(node.next_pending().unwrap().0, manifest("1.10.0"));
+
+        assert!(node
+            .announce(maintainer.sign(manifest("1.2.0")).unwrap())
+            .is_err());
+        assert!(maintainer.sign(manifest("1.x")).is_err());
+
+        let mut other_target = manifest("2.0.0");
+        other_target.target = "x86_64-unknown-linux-gnu".into();
+        assert!(node
+            .announce(maintainer.sign(other_target).unwrap())
+            .is_err());
+
+        let mut tampered = maintainer.sign(manifest("2.0.0")).unwrap();
+        tampered.manifest.root = "11".repeat(32);
+        assert_eq!(node.announce(tampered), Err("Invalid signature".into()));
+
+        let untrusted = Updates {
+            signing_key: Some(Keypair::generate_ed25519()),
+            ..maintainer
+        };
+        assert!(node
+            .announce(untrusted.sign(manifest("2.0.0")).unwrap())
+            .is_err());
+        assert_eq!(node.updates().len(), 1);
+    }
+
+    #[test]
+    fn apply_hook_is_killed_after_timeout() {
+        let hook = |script: &str| {
+            let mut command = process::Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        };
+        let timeout = Duration::from_secs(1);
+        task::block_on(async {
+            assert_eq!(
+                run_hook(hook("exit 0"), timeout).await,
+                UpdateState::Applied
+            );
+            assert!(matches!(
+                run_hook(hook("exit 1"), timeout).await,
+                UpdateState::Failed(_)
+            ));
+            let start = Instant::now();
+            assert_eq!(
+                run_hook(hook("exec sleep 30"), timeout).await,
+                UpdateState::Failed("Apply hook did not exit within 1s".into())
+            );
+            assert!(start.elapsed() < Duration::from_secs(10));
+        })
+    }
+}
@@ -1,15 +1,25 @@
-use libp2p::{gossipsub::MessageId, kad::Record, Multiaddr, PeerId};
+use libp2p::{
+    gossipsub::MessageId,
+    kad::{Quorum, Record},
+    Multiaddr, PeerId,
+};
 use serde::{Deserialize, Serialize};
-use std::{fmt, time::Duration};
+use std::{fmt, str::FromStr, time::Duration};
 
 pub type Topic = String;
 
+// Random id of a published device command, under which the receivers acknowledge it.
+pub type CommandId = u64;
+
 // Command that is created based on user input and transferred to the swarm task.
 // This Command represents a kademlia or gossibsub operation.
 #[derive(Debug, Clone)]
 pub enum Command {
     // Explicitely connect a peer by address.
+    // An address that ends with /p2p/<peer-id> only connects to the peer with that id.
     Connect(Multiaddr),
+    // Connect a peer by id. If no address of the peer is known, it is looked up in the DHT.
+    ConnectPeer(PeerId),
     // Subscribe to a gossibsub topic so that all messages published to that topic
     // will be received.
     SubscribeGossipTopic(Topic),
@@ -24,22 +34,110 @@ pub enum Command {
         data: GossipMessage,
         topic: Topic,
     },
+    // Publish a device command, e.g. GossipMessage::SetLed, and collect the acknowledgments of
+    // the receivers until the configured ack window elapsed. The acknowledgments are published to
+    // the same topic, the local peer subscribes to it meanwhile if it does not already.
+    PublishCommand {
+        data: GossipMessage,
+        topic: Topic,
+    },
+    // Acknowledge a received device command to its publisher, by publishing the status to the
+    // topic of the command.
+    Acknowledge {
+        topic: Topic,
+        id: CommandId,
+        status: AckStatus,
+    },
+    // Request the messages that the peers subscribing to the topic retained, e.g. after the local
+    // peer subscribed late. Messages that were already received are skipped, the others are
+    // forwarded like received gossip messages.
+    CatchUp(Topic),
     // Query for a kademlia record that has to be published to the DHT.
-    GetRecord(String),
+    // The query succeeds once the number of peers in the quorum returned the record.
+    GetRecord {
+        key: String,
+        quorum: Quorum,
+    },
     // Publish a record to the DHT so that any peer can query for it.
-    // This will query the peer closest to the record key (using XOR metric)
-    // to store the record.
+    // This will query the peers closest to the record key (using XOR metric)
+    // to store the record, and succeeds once the number of peers in the quorum stored it.
+    // If no ttl is set, the configured default record ttl is used. A ttl of 0 disables expiry.
+    // A signed record is stored under the key `/pk/<local-peer-id>/<key>`, and only accepted by
+    // other peers if it is signed by the owner of that namespace.
     PutRecord {
         key: String,
         value: Vec<u8>,
+        quorum: Quorum,
+        ttl: Option<Duration>,
+        signed: bool,
+    },
+    // Announce in the DHT that the local peer provides the value for a key, e.g. a
+    // capability like "has-led".
+    StartProviding(String),
+    // Stop announcing that the local peer provides the value for a key.
+    StopProviding(String),
+    // Query the DHT for peers that provide the value for a key.
+    GetProviders(String),
+    // Ban a peer: close all connections to it, reject new ones and ignore its messages.
+    // The ban list is persisted if a ban list file is configured.
+    Ban(PeerId),
+    // Remove a peer from the ban list.
+    Unban(PeerId),
+    // Close all connections to a peer. The peer may connect again later.
+    Disconnect(PeerId),
+    // Pin a peer by address, so that it is redialed whenever its connection closes.
+    Pin(Multiaddr),
+    // Stop redialing a pinned peer.
+    Unpin(Multiaddr),
+    // Return the connected, pinned and banned peers.
+    GetPeers,
+    // Register the local peer under a namespace at a rendezvous point, so that other peers can
+    // discover it. If no ttl is set, the registration expires after 2 hours.
+    // If no rendezvous point is given, the configured one is used.
+    Register {
+        namespace: String,
+        ttl: Option<Duration>,
+        point: Option<Multiaddr>,
     },
+    // Remove the registration of the local peer from a rendezvous point.
+    Unregister {
+        namespace: String,
+        point: Option<Multiaddr>,
+    },
+    // Query a rendezvous point for the peers that registered under a namespace.
+    // The discovered peers are added as known peers.
+    Discover {
+        namespace: String,
+        point: Option<Multiaddr>,
+    },
+    // Share a file with other peers. The file is split into chunks, and the local peer is
+    // announced in the DHT as provider of the root hash of the file.
+    Share(String),
+    // Download a shared file by its root hash from the peers that provide it, and write it to
+    // the path. An interrupted download is resumed by fetching the same file to the same path.
+    Fetch {
+        root: String,
+        path: String,
+    },
+    // Release a new version: share the payload, sign a manifest with the signing key of the local
+    // node, and announce it to the nodes that trust the key. If no target is set, the target of
+    // the local node is used.
+    Release {
+        path: String,
+        version: String,
+        target: Option<String>,
+    },
+    // Return the updates that were announced to the local node, and whether they were installed.
+    GetUpdates,
+    // Return statistics about the swarm task.
+    GetStats,
     // Shutdown the swarm task.
     Shutdown,
 }
 
 #[derive(Debug, Clone)]
 pub enum CommandResult {
-    // Result of the attempt to connect a peer by address.
+    // Result of the attempt to connect a peer by address or peer id.
     ConnectResult(Result<PeerId, String>),
     // Result of the subscribe command.
     // Return Ok(true) if successfully subscribe,
@@ -51,13 +149,219 @@ pub enum CommandResult {
     UnsubscribResult(Result<bool, String>),
     // Result for publishing a message to a gossipsub topic.
     PublishResult(Result<MessageId, String>),
+    // Acknowledgments for a published device command, per peer.
+    PublishCommandResult(Result<Vec<PeerAck>, String>),
+    // Result of publishing an acknowledgment.
+    AcknowledgeResult(Result<(), String>),
+    // Number of retained messages that were new to the local peer.
+    CatchUpResult(Result<usize, String>),
     // Result of querying the DHT for a record.
-    // Can return multiple records if they use the same key.
-    GetRecordResult(Result<Vec<Record>, String>),
-    // Result of publishing a record to the DHT
-    PutRecordResult(Result<(), String>),
+    // Can return multiple records if peers store different values for the same key.
+    GetRecordResult(Result<Vec<FoundRecord>, String>),
+    // Result of publishing a record to the DHT.
+    // Returns the key under which the record was published.
+    PutRecordResult(Result<String, String>),
+    // Result of announcing the local peer as provider for a key.
+    StartProvidingResult(Result<(), String>),
+    // Acknowledge that the local peer stopped providing a key.
+    StopProvidingAck,
+    // Result of querying the DHT for providers of a key.
+    GetProvidersResult(Result<Vec<PeerId>, String>),
+    // Result of banning a peer.
+    // Returns Ok(false) if the peer was already banned.
+    BanResult(Result<bool, String>),
+    // Result of unbanning a peer.
+    // Returns Ok(false) if the peer was not banned.
+    UnbanResult(Result<bool, String>),
+    // Result of disconnecting a peer, fails if the peer was not connected.
+    DisconnectResult(Result<(), String>),
+    // Result of pinning a peer, false if the address was already pinned.
+    PinResult(bool),
+    // Result of unpinning a peer, false if the address was not pinned.
+    UnpinResult(bool),
+    // Overview of the known peers.
+    PeersResult(PeersInfo),
+    // Result of registering at a rendezvous point, returns the ttl of the registration.
+    RegisterResult(Result<Duration, String>),
+    // Result of unregistering, fails if the rendezvous point could not be connected.
+    UnregisterResult(Result<(), String>),
+    // Peers that were discovered at a rendezvous point.
+    DiscoverResult(Result<Vec<Registration>, String>),
+    // Result of sharing a file, returns the root hash under which it is shared.
+    ShareResult(Result<String, String>),
+    // Result of fetching a file.
+    FetchResult(Result<FetchInfo, String>),
+    // Result of releasing a version, returns the announced manifest and the peer id of the
+    // signing key.
+    ReleaseResult(Result<(UpdateManifest, PeerId), String>),
+    // Announced updates, most recent last.
+    UpdatesResult(Vec<UpdateInfo>),
+    // Statistics about the swarm task.
+    StatsResult(Stats),
     // Acknowledge shutdown command
     ShutdownAck,
+    // The command did not complete within the timeout.
+    Timeout(Duration),
+}
+
+// Distinct record that was found in the DHT, together with the peers that returned it.
+#[derive(Debug, Clone)]
+pub struct FoundRecord {
+    pub record: Record,
+    // Peers that answered with this record, None if it was found in the local store.
+    pub peers: Vec<Option<PeerId>>,
+    // Whether the record was signed by the owner of its key, and the signature was verified.
+    pub is_signed: bool,
+}
+
+// Acknowledgment of a published device command by a peer.
+#[derive(Debug, Clone)]
+pub struct PeerAck {
+    pub peer_id: PeerId,
+    // None if the peer is known to subscribe to the topic, but did not acknowledge the command
+    // within the ack window.
+    pub status: Option<AckStatus>,
+}
+
+// Outcome of a device command on a receiving peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AckStatus {
+    Applied,
+    // The command is applied once its execution time is reached.
+    Scheduled,
+    // The command is invalid for the device, e.g. it addresses a LED that does not exist.
+    Rejected(String),
+    // The command is valid, but applying it failed.
+    Error(String),
+}
+
+impl fmt::Display for AckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckStatus::Applied => write!(f, "applied"),
+            AckStatus::Scheduled => write!(f, "scheduled"),
+            AckStatus::Rejected(reason) => write!(f, "rejected: {}", reason),
+            AckStatus::Error(error) => write!(f, "error: {}", error),
+        }
+    }
+}
+
+// Statistics that are collected by the swarm task.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    // Number of received gossip messages that were dropped because the user task did not keep up.
+    pub dropped_messages: u64,
+    // Number of publish commands that were rejected because they exceeded the rate limit.
+    pub rate_limited_publishes: u64,
+    // Number of received gossip messages that were rejected because their source exceeded the
+    // rate limit.
+    pub rate_limited_messages: u64,
+}
+
+// Connected, pinned and banned peers of the local node, and how the local node is reachable.
+#[derive(Debug, Clone)]
+pub struct PeersInfo {
+    pub connected: Vec<PeerId>,
+    pub pinned: Vec<PinnedPeer>,
+    pub banned: Vec<PeerId>,
+    // Addresses on which the local node listens, including /p2p-circuit addresses via relays.
+    pub listen_addrs: Vec<Multiaddr>,
+    // Addresses of the local node as observed by remote peers.
+    pub observed_addrs: Vec<Multiaddr>,
+    pub reachability: Reachability,
+}
+
+// Whether the local node is reachable from the outside, as detected by AutoNAT: a connected peer
+// is asked to dial the local node back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reachability {
+    // No peer dialed the local node back yet.
+    Unknown,
+    // A peer dialed the local node back at that address.
+    Public(Multiaddr),
+    // The dial-back failed, the node is e.g. behind a NAT.
+    Private,
+}
+
+// Peer that is kept connected by redialing its address.
+#[derive(Debug, Clone)]
+pub struct PinnedPeer {
+    pub addr: Multiaddr,
+    // Peer id, once the peer was connected or if the address contains it.
+    pub peer_id: Option<PeerId>,
+    pub state: PinnedState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinnedState {
+    Connected,
+    Dialing,
+    // Waiting for the next dial after a closed connection or failed dials.
+    Backoff { retry_in: Duration, failures: u32 },
+}
+
+// Peer that registered at a rendezvous point.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    // Remaining time until the registration expires.
+    pub ttl: Duration,
+}
+
+// Outcome of a completed file download.
+#[derive(Debug, Clone)]
+pub struct FetchInfo {
+    // Size of the file in bytes.
+    pub size: u64,
+    pub chunks: usize,
+    // Number of chunks that were already downloaded by a previous, interrupted fetch.
+    pub resumed: usize,
+}
+
+// Release of a firmware or application version.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpdateManifest {
+    // Version in the format <major>.<minor>.<patch>, with any number of components.
+    pub version: String,
+    // Target that the payload is built for, e.g. "riscv64gc-unknown-linux-gnu".
+    pub target: String,
+    // Root hash of the payload, under which it is shared.
+    pub root: String,
+    // Size of the payload in bytes.
+    pub size: u64,
+}
+
+// Manifest of a release, signed by a maintainer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedUpdate {
+    pub manifest: UpdateManifest,
+    // Protobuf encoded public key of the maintainer.
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+// Update that was announced by a trusted maintainer.
+#[derive(Debug, Clone)]
+pub struct UpdateInfo {
+    pub manifest: UpdateManifest,
+    pub publisher: PeerId,
+    pub state: UpdateState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateState {
+    // Waiting to be fetched.
+    Pending,
+    // The payload is being fetched.
+    Fetching,
+    // The payload was fetched to the path, but no apply hook is configured.
+    Staged(String),
+    // The payload was fetched and the apply hook is running.
+    Applying,
+    // The apply hook succeeded.
+    Applied,
+    Failed(String),
 }
 
 // Example for Gossibsub Messages that could be published to certain topics.
@@ -65,16 +369,138 @@ pub enum CommandResult {
 pub enum GossipMessage {
     // Plain message String
     Message(String),
-    // Instruct to set the user-LED on the device (if there is one)
-    SetLed(LedState),
+    // Instruct to set the user-LEDs on the device (if there are some).
+    // The receivers acknowledge the command under its id.
+    SetLed {
+        id: CommandId,
+        command: LedCommand,
+    },
+    // Acknowledgment of a received device command.
+    Ack {
+        id: CommandId,
+        status: AckStatus,
+    },
+    // Announce a new release on the updates topic.
+    Update(SignedUpdate),
+    // Message of an application specific type, that is registered in the
+    // `registry::MessageRegistry` under the tag.
+    Custom {
+        tag: String,
+        data: Vec<u8>,
+    },
+    // Message that the receivers execute at a later time, or only until it expires.
+    Scheduled {
+        schedule: Schedule,
+        message: Box<GossipMessage>,
+    },
+    // Received message on an encrypted topic, that could not be decrypted with the topic key.
+    // It is only created locally, and never sent or received.
+    #[serde(skip)]
+    Undecryptable {
+        source: Option<PeerId>,
+        error: String,
+    },
+}
+
+impl GossipMessage {
+    // Name of the message type, under which the latest message of a topic is retained.
+    pub fn kind(&self) -> &str {
+        match self {
+            GossipMessage::Message(_) => "message",
+            GossipMessage::SetLed { .. } => "set-led",
+            GossipMessage::Ack { .. } => "ack",
+            GossipMessage::Update(_) => "update",
+            GossipMessage::Custom { tag, .. } => tag,
+            GossipMessage::Scheduled { message, .. } => message.kind(),
+            GossipMessage::Undecryptable { .. } => "undecryptable",
+        }
+    }
+
+    // Id of a device command, that the receivers acknowledge. None for other messages.
+    pub fn command_id(&self) -> Option<CommandId> {
+        match self {
+            GossipMessage::SetLed { id, .. } => Some(*id),
+            GossipMessage::Scheduled { message, .. } => message.command_id(),
+            _ => None,
+        }
+    }
+
+    // Attach the schedule to the message, unless it has neither an execution nor an expiry time.
+    pub fn with_schedule(self, schedule: Schedule) -> Self {
+        match schedule == Schedule::default() {
+            true => self,
+            false => GossipMessage::Scheduled {
+                schedule,
+                message: Box::new(self),
+            },
+        }
+    }
+}
+
+// Time at which the receivers execute a message, and after which they ignore it.
+// Times are milliseconds since the UNIX epoch, hence the clocks of the peers have to be
+// synchronized, e.g. via NTP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    // Execute the message immediately if None.
+    pub execute_at: Option<u64>,
+    // The message never expires if None.
+    pub expires_at: Option<u64>,
+}
+
+impl Schedule {
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now > expires_at)
+    }
+}
+
+// Set the state of one or all LEDs, or channels of another actuator, of a device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedCommand {
+    // Index of the LED, all LEDs if None.
+    pub channel: Option<u8>,
+    pub state: LedState,
+}
+
+impl LedCommand {
+    // Command for all LEDs of the device.
+    pub fn all(state: LedState) -> Self {
+        LedCommand {
+            channel: None,
+            state,
+        }
+    }
+}
+
+impl fmt::Display for LedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.channel {
+            Some(channel) => write!(f, "LED {}: {}", channel, self.state),
+            None => write!(f, "all LEDs: {}", self.state),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum LedState {
     On,
     Off,
-    // Frequently blink LED
-    Blink(Duration),
+    // Brightness in percent, i.g. the PWM duty cycle.
+    Brightness(u8),
+    Color(Rgb),
+    // Blink LED with the durations of the on and off phase.
+    Blink {
+        on: Duration,
+        off: Duration,
+    },
+    // Turn LED on for the duration, and off afterwards.
+    OnFor(Duration),
+    // Show the colors of the steps in order, `repeat` times or forever if 0. The LED is off once
+    // the pattern finished.
+    Pattern {
+        steps: Vec<PatternStep>,
+        repeat: u32,
+    },
 }
 
 impl fmt::Display for LedState {
@@ -82,7 +508,139 @@ impl fmt::Display for LedState {
         match self {
             LedState::On => write!(f, "on"),
             LedState::Off => write!(f, "off"),
-            LedState::Blink(duration) => write!(f, "blink every {}s", duration.as_secs()),
+            LedState::Brightness(brightness) => write!(f, "brightness {}%", brightness),
+            LedState::Color(color) => write!(f, "color {}", color),
+            LedState::Blink { on, off } => write!(
+                f,
+                "blink {}ms on, {}ms off",
+                on.as_millis(),
+                off.as_millis()
+            ),
+            LedState::OnFor(duration) => write!(f, "on for {}ms", duration.as_millis()),
+            LedState::Pattern { steps, repeat } => {
+                let steps: Vec<_> = steps.iter().map(|step| step.to_string()).collect();
+                match repeat {
+                    0 => write!(f, "pattern {} forever", steps.join(",")),
+                    _ => write!(f, "pattern {} {} times", steps.join(","), repeat),
+                }
+            }
+        }
+    }
+}
+
+// Step of a LED pattern, black turns the LED off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatternStep {
+    pub color: Rgb,
+    pub duration: Duration,
+}
+
+impl fmt::Display for PatternStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.color, self.duration.as_millis())
+    }
+}
+
+// Parse a step of the format <rrggbb>:<milliseconds>.
+impl FromStr for PatternStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let color = parts.next().unwrap_or_default().parse()?;
+        let duration = parts
+            .next()
+            .and_then(|ms| u64::from_str(ms).ok())
+            .map(Duration::from_millis)
+            .ok_or_else(|| format!("Invalid pattern step {}, expected <rrggbb>:<ms>", s))?;
+        Ok(PatternStep { color, duration })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(255, 255, 255);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    // Scale the color to the brightness in percent.
+    pub fn dimmed(self, brightness: u8) -> Self {
+        let dim = |c: u8| (c as u16 * brightness as u16 / 100) as u8;
+        Rgb::new(dim(self.r), dim(self.g), dim(self.b))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+// Parse a hex color of the format [#]rrggbb.
+impl FromStr for Rgb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim_start_matches('#');
+        match hex::decode(hex).as_deref() {
+            Ok([r, g, b]) => Ok(Rgb::new(*r, *g, *b)),
+            _ => Err(format!("Invalid color {}, expected <rrggbb>", s)),
+        }
+    }
+}
+
+// Encoding of binary values when they are passed via the command line, or printed.
+#[derive(Debug, Clone, Copy)]
+pub enum ValueEncoding {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl ValueEncoding {
+    pub fn decode(&self, value: &str) -> Result<Vec<u8>, String> {
+        match self {
+            ValueEncoding::Utf8 => Ok(value.as_bytes().to_vec()),
+            ValueEncoding::Hex => hex::decode(value.trim_start_matches("0x"))
+                .map_err(|e| format!("Invalid hex: {}", e)),
+            ValueEncoding::Base64 => {
+                base64::decode(value).map_err(|e| format!("Invalid base64: {}", e))
+            }
+        }
+    }
+
+    // Encode a value into a printable string.
+    // Values that are no valid UTF-8 are encoded as hex if UTF-8 was requested.
+    pub fn encode(&self, value: &[u8]) -> String {
+        match self {
+            ValueEncoding::Utf8 => match std::str::from_utf8(value) {
+                Ok(s) => format!("{:?}", s),
+                Err(_) => format!("0x{} (no valid UTF-8)", hex::encode(value)),
+            },
+            ValueEncoding::Hex => format!("0x{}", hex::encode(value)),
+            ValueEncoding::Base64 => base64::encode(value),
+        }
+    }
+}
+
+impl FromStr for ValueEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(ValueEncoding::Utf8),
+            "hex" => Ok(ValueEncoding::Hex),
+            "base64" => Ok(ValueEncoding::Base64),
+            _ => Err(format!("Unknown encoding {}", s)),
         }
     }
 }
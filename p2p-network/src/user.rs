@@ -1,36 +1,74 @@
-use crate::{cli, types::*};
-use async_std::io::{self, BufReader};
+use crate::cli;
+use async_std::{
+    future,
+    io::{self, BufReader},
+    task,
+};
+use clap::ArgMatches;
 use futures::{
-    channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    channel::mpsc::{Receiver, Sender},
     prelude::*,
     select,
-    task::{Context, Poll},
 };
-use libp2p::Multiaddr;
-use std::{str::FromStr, time::Duration};
+use libp2p::{kad::Quorum, Multiaddr, PeerId};
+use p2p_network::{
+    channel::BoundedReceiver,
+    led::{LedController, LedError, SimulatedDriver, MAX_STEP_DURATION},
+    registry::MessageRegistry,
+    schedule::{self, Clock, Scheduled, Scheduler, SystemClock},
+    types::*,
+};
+use std::{
+    fs,
+    num::NonZeroUsize,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Where the values of records that were found in the DHT are written to.
+enum RecordOutput {
+    // Print the value in the given encoding.
+    Print(ValueEncoding),
+    // Write the value to a file.
+    File(String),
+}
 
 // Task that handles all user and periphery interaction
 pub struct UserTask {
     // Channel to send commands to swarm task
-    cmd_tx: UnboundedSender<Command>,
+    cmd_tx: Sender<Command>,
     // Channel that the swarm task uses to return the results for a command
-    cmd_res_rx: UnboundedReceiver<CommandResult>,
+    cmd_res_rx: Receiver<CommandResult>,
     // Channel for incoming gossibsub messages that are received in the network.
-    message_rx: UnboundedReceiver<(Topic, GossipMessage)>,
+    message_rx: BoundedReceiver<(Topic, GossipMessage)>,
+    // Output of the currently running get-record command.
+    record_output: RecordOutput,
+    // Application specific message types, that are published and printed generically.
+    registry: MessageRegistry,
+    // LEDs that are set by received LED commands.
+    leds: LedController<SimulatedDriver>,
+    // Received messages that wait for their execution time.
+    scheduler: Scheduler<(Topic, GossipMessage), SystemClock>,
 }
 
 impl UserTask {
     // Create new instance of a User Task
     pub fn new(
-        cmd_tx: UnboundedSender<Command>,
-        cmd_res_rx: UnboundedReceiver<CommandResult>,
-        message_rx: UnboundedReceiver<(Topic, GossipMessage)>,
+        cmd_tx: Sender<Command>,
+        cmd_res_rx: Receiver<CommandResult>,
+        message_rx: BoundedReceiver<(Topic, GossipMessage)>,
+        registry: MessageRegistry,
+        leds: LedController<SimulatedDriver>,
     ) -> Self {
         let _ = super::cli::build_app().print_long_help();
         UserTask {
             cmd_tx,
             cmd_res_rx,
             message_rx,
+            record_output: RecordOutput::Print(ValueEncoding::Utf8),
+            registry,
+            leds,
+            scheduler: Scheduler::new(SystemClock),
         }
     }
 
@@ -41,6 +79,22 @@ impl UserTask {
         let mut stdin = BufReader::new(io::stdin()).lines();
 
         loop {
+            // Wait until the next step of a LED pattern is due.
+            let next_led_update = self.leds.next_update();
+            let led_timer = async move {
+                match next_led_update {
+                    Some(at) => task::sleep(at.saturating_duration_since(Instant::now())).await,
+                    None => future::pending().await,
+                }
+            };
+            // Wait until the next scheduled message is due.
+            let next_due = self.scheduler.next_due();
+            let schedule_timer = async move {
+                match next_due {
+                    Some(remaining) => task::sleep(remaining).await,
+                    None => future::pending().await,
+                }
+            };
             // simultainously poll both futures, select the one that return first.
             select! {
                 // Poll for input via stdin
@@ -69,19 +123,102 @@ impl UserTask {
                 }
                 // Poll for incoming gossipsub messages
                 message = self.message_rx.next().fuse() => match message {
-                    Some((topic, message)) => Self::print_incoming(topic, message),
+                    Some((topic, message)) => {
+                        if let Err(err) = self.print_incoming(topic, message).await {
+                            println!("> Aborting due to error: {}", err);
+                            break;
+                        }
+                    }
                     None => {
                         println!("> Message channel closed unexpected. Aborting.");
                         let _ = self.handle_command(Command::Shutdown).await;
                         break;
                     }
+                },
+                _ = led_timer.fuse() => {
+                    if let Err(err) = self.leds.update(Instant::now()) {
+                        println!("> Failed to set LED: {}\n", err);
+                    }
+                }
+                _ = schedule_timer.fuse() => {
+                    if let Err(err) = self.execute_due().await {
+                        println!("> Aborting due to error: {}", err);
+                        break;
+                    }
                 }
             }
         }
     }
 
     // Print to standard output the gossipsub message that was received
-    fn print_incoming(topic: String, message: GossipMessage) {
+    // Scheduled messages are queued until their execution time, and ignored once they expired.
+    async fn print_incoming(
+        &mut self,
+        topic: String,
+        message: GossipMessage,
+    ) -> Result<(), String> {
+        let (schedule, message) = match message {
+            GossipMessage::Scheduled { schedule, message } => (schedule, *message),
+            message => return self.execute(topic, message, true).await,
+        };
+        let command_id = message.command_id();
+        match self.scheduler.schedule(&schedule, (topic.clone(), message)) {
+            Scheduled::Due((topic, message)) => self.execute(topic, message, true).await,
+            Scheduled::Queued(execute_at) => {
+                let remaining = execute_at.saturating_sub(self.scheduler.clock().now()) / 1000;
+                println!(
+                    "> Scheduled message for topic {} at {}, in {}s.\n",
+                    topic,
+                    Self::format_time(execute_at),
+                    remaining
+                );
+                match command_id {
+                    Some(id) => {
+                        let status = AckStatus::Scheduled;
+                        self.handle_command(Command::Acknowledge { topic, id, status })
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            }
+            Scheduled::Expired => {
+                println!("> Ignored expired message for topic {}.\n", topic);
+                Ok(())
+            }
+            Scheduled::Rejected(reason) => {
+                println!(
+                    "> Ignored scheduled message for topic {}: {}.\n",
+                    topic, reason
+                );
+                match command_id {
+                    Some(id) => {
+                        let status = AckStatus::Rejected(reason);
+                        self.handle_command(Command::Acknowledge { topic, id, status })
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    // Execute the scheduled messages that are due. Device commands were already acknowledged
+    // when they were queued.
+    async fn execute_due(&mut self) -> Result<(), String> {
+        for (topic, message) in self.scheduler.due() {
+            self.execute(topic, message, false).await?;
+        }
+        Ok(())
+    }
+
+    // Print the message, and apply device commands. If `acknowledge` is set, the outcome of a
+    // device command is acknowledged to its publisher.
+    async fn execute(
+        &mut self,
+        topic: String,
+        message: GossipMessage,
+        acknowledge: bool,
+    ) -> Result<(), String> {
         match message {
             GossipMessage::Message(msg) => {
                 println!(
@@ -89,10 +226,51 @@ impl UserTask {
                     topic, msg
                 )
             }
-            GossipMessage::SetLed(state) => {
-                println!("> Received command to set led state: {}\n", state)
+            GossipMessage::SetLed { id, command } => {
+                println!("> Received command {} to set {}\n", id, command);
+                let status = match self.leds.apply(&command, Instant::now()) {
+                    Ok(()) => AckStatus::Applied,
+                    Err(LedError::Invalid(reason)) => AckStatus::Rejected(reason),
+                    Err(LedError::Driver(error)) => AckStatus::Error(error),
+                };
+                if status != AckStatus::Applied {
+                    println!("> LED command {} was not applied: {}\n", id, status);
+                }
+                if acknowledge {
+                    let ack = Command::Acknowledge { topic, id, status };
+                    self.handle_command(ack).await?;
+                }
+            }
+            // Acknowledgments are collected by the swarm task.
+            GossipMessage::Ack { .. } => {}
+            GossipMessage::Update(update) => println!(
+                "> Received update to version {} for {}, it is installed in the background.\n",
+                update.manifest.version, update.manifest.target
+            ),
+            GossipMessage::Custom { tag, data } => {
+                match self.registry.handle(&topic, &tag, &data) {
+                    Ok(payload) => println!(
+                        "> Received {} message for topic {}:\n{}\n",
+                        tag, topic, payload
+                    ),
+                    Err(err) => println!(
+                        "> Received {} message for topic {} that can not be handled: {}\n",
+                        tag, topic, err
+                    ),
+                }
+            }
+            GossipMessage::Scheduled { .. } => {
+                println!("> Ignored nested scheduled message for topic {}.\n", topic)
+            }
+            GossipMessage::Undecryptable { source, error } => {
+                let source = source.map_or_else(|| "unknown peer".into(), |p| p.to_string());
+                println!(
+                    "> Failed to decrypt message for topic {} from {}: {}\n",
+                    topic, source, error
+                )
             }
         }
+        Ok(())
     }
 
     // Handle a user command, block task until a result is returned.
@@ -103,13 +281,37 @@ impl UserTask {
             .next()
             .await
             .ok_or_else(|| String::from("Channel Error"))?;
+        if let CommandResult::Timeout(timeout) = res {
+            println!("> Command timed out after {}s.\n", timeout.as_secs());
+            return Ok(());
+        }
         match command {
             Command::SubscribeGossipTopic(..) => self.match_subscribe_res(res),
             Command::UnsubscribeGossipTopic(..) => self.match_unsubscribe_res(res),
             Command::PublishGossipData { .. } => self.match_publish_res(res),
-            Command::GetRecord(..) => self.match_get_record_res(res),
+            Command::PublishCommand { .. } => self.match_publish_command_res(res),
+            Command::Acknowledge { .. } => self.match_acknowledge_res(res),
+            Command::CatchUp(..) => self.match_catch_up_res(res),
+            Command::GetRecord { .. } => self.match_get_record_res(res),
             Command::PutRecord { .. } => self.match_put_record_res(res),
-            Command::Connect(..) => self.match_connect_res(res),
+            Command::Connect(..) | Command::ConnectPeer(..) => self.match_connect_res(res),
+            Command::StartProviding(..) => self.match_start_providing_res(res),
+            Command::StopProviding(..) => self.match_stop_providing_res(res),
+            Command::GetProviders(..) => self.match_get_providers_res(res),
+            Command::Ban(..) => self.match_ban_res(res),
+            Command::Unban(..) => self.match_unban_res(res),
+            Command::Disconnect(..) => self.match_disconnect_res(res),
+            Command::Pin(..) => self.match_pin_res(res),
+            Command::Unpin(..) => self.match_unpin_res(res),
+            Command::GetPeers => self.match_peers_res(res),
+            Command::Register { .. } => self.match_register_res(res),
+            Command::Unregister { .. } => self.match_unregister_res(res),
+            Command::Discover { .. } => self.match_discover_res(res),
+            Command::Share(..) => self.match_share_res(res),
+            Command::Fetch { .. } => self.match_fetch_res(res),
+            Command::Release { .. } => self.match_release_res(res),
+            Command::GetUpdates => self.match_updates_res(res),
+            Command::GetStats => self.match_stats_res(res),
             Command::Shutdown => self.match_shutdown_res(res),
         };
         Ok(())
@@ -160,19 +362,113 @@ impl UserTask {
         }
     }
 
+    // Print the acknowledgments of a published device command per peer.
+    fn match_publish_command_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::PublishCommandResult(Ok(acks)) => {
+                let applied = acks
+                    .iter()
+                    .filter(|ack| ack.status == Some(AckStatus::Applied))
+                    .count();
+                let scheduled = match acks
+                    .iter()
+                    .filter(|ack| ack.status == Some(AckStatus::Scheduled))
+                    .count()
+                {
+                    0 => String::new(),
+                    count => format!(" and scheduled by {}", count),
+                };
+                println!(
+                    "> Sucessfully published command, applied by {}{} of {} peers:",
+                    applied,
+                    scheduled,
+                    acks.len()
+                );
+                for ack in acks {
+                    match ack.status {
+                        Some(status) => println!("\t{}: {}", ack.peer_id, status),
+                        None => println!("\t{}: no acknowledgment", ack.peer_id),
+                    }
+                }
+                println!();
+            }
+            CommandResult::PublishCommandResult(Err(err)) => {
+                println!("> Failed to publish: {:?}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Only print if the acknowledgment of a received command could not be published.
+    fn match_acknowledge_res(&mut self, res: CommandResult) {
+        if let CommandResult::AcknowledgeResult(Err(err)) = res {
+            println!("> Failed to acknowledge command: {:?}.\n", err);
+        }
+    }
+
+    // Print the outcome of the catch-up command.
+    fn match_catch_up_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::CatchUpResult(Ok(count)) => {
+                println!("> Received {} retained messages.\n", count);
+            }
+            CommandResult::CatchUpResult(Err(err)) => {
+                println!("> Failed to catch up: {:?}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
     // Print the outcome of the get-record command.
     fn match_get_record_res(&mut self, res: CommandResult) {
         match res {
             CommandResult::GetRecordResult(Ok(vec)) => {
                 println!("> Found Record:");
-                for record in vec {
-                    if let Ok(message) = String::from_utf8(record.value.to_vec()) {
-                        let pub_str = record
-                            .publisher
-                            .map(|p| format! {",\n\tpublisher: {:?}", p})
-                            .unwrap_or_else(String::new);
-                        println!("\t{:?},\n\tValue: {:?}{}.\n", record.key, message, pub_str);
-                    }
+                for (index, found) in vec.into_iter().enumerate() {
+                    let FoundRecord {
+                        record,
+                        peers,
+                        is_signed,
+                    } = found;
+                    let value_str = match &self.record_output {
+                        RecordOutput::Print(encoding) => encoding.encode(&record.value),
+                        RecordOutput::File(path) => {
+                            // Additional distinct values are written to numbered files.
+                            let path = match index {
+                                0 => path.clone(),
+                                _ => format!("{}.{}", path, index),
+                            };
+                            match fs::write(&path, &record.value) {
+                                Ok(()) => format!("written to {}", path),
+                                Err(e) => format!("failed to write to {}: {}", path, e),
+                            }
+                        }
+                    };
+                    let pub_str = record
+                        .publisher
+                        .map(|p| format! {",\n\tpublisher: {:?}", p})
+                        .unwrap_or_else(String::new);
+                    let signed_str = if is_signed {
+                        ",\n\tsignature: verified"
+                    } else {
+                        ""
+                    };
+                    let expires_str = record
+                        .expires
+                        .map(|e| {
+                            let ttl = e.saturating_duration_since(Instant::now());
+                            format! {",\n\texpires in: {}s", ttl.as_secs()}
+                        })
+                        .unwrap_or_else(String::new);
+                    let peers_str = peers
+                        .iter()
+                        .map(|p| p.map(|p| p.to_string()).unwrap_or_else(|| "local".into()))
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    println!(
+                        "\t{:?},\n\tValue: {}{}{}{},\n\treturned by: {}.\n",
+                        record.key, value_str, pub_str, signed_str, expires_str, peers_str
+                    );
                 }
             }
             CommandResult::GetRecordResult(Err(error)) => {
@@ -185,8 +481,8 @@ impl UserTask {
     // Print the outcome of the put-record command.
     fn match_put_record_res(&mut self, res: CommandResult) {
         match res {
-            CommandResult::PutRecordResult(Ok(())) => {
-                println!("> Successfully published record.\n");
+            CommandResult::PutRecordResult(Ok(key)) => {
+                println!("> Successfully published record with key {:?}.\n", key);
             }
             CommandResult::PutRecordResult(Err(err)) => {
                 println!("> Failed to get record {:?}.\n", err);
@@ -202,12 +498,299 @@ impl UserTask {
                 println!("> Successfully connected to Peer {}.\n", peer_id);
             }
             CommandResult::ConnectResult(Err(err)) => {
-                println!("> Failed to dial the address: {}.\n", err);
+                println!("> Failed to connect: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the provide command.
+    fn match_start_providing_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::StartProvidingResult(Ok(())) => {
+                println!("> Successfully announced as provider.\n");
+            }
+            CommandResult::StartProvidingResult(Err(err)) => {
+                println!("> Failed to announce as provider: {:?}.\n", err);
             }
             _ => {}
         }
     }
 
+    // Print the outcome of the stop-providing command.
+    fn match_stop_providing_res(&mut self, res: CommandResult) {
+        if let CommandResult::StopProvidingAck = res {
+            println!("> Stopped providing.\n");
+        }
+    }
+
+    // Print the outcome of the find-providers command.
+    fn match_get_providers_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::GetProvidersResult(Ok(providers)) => {
+                println!("> Found Providers:");
+                for peer_id in providers {
+                    println!("\t{}", peer_id);
+                }
+                println!();
+            }
+            CommandResult::GetProvidersResult(Err(err)) => {
+                println!("> Failed to find providers: {:?}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the ban command.
+    fn match_ban_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::BanResult(Ok(true)) => {
+                println!("> Successfully banned peer.\n");
+            }
+            CommandResult::BanResult(Ok(false)) => {
+                println!("> Peer is already banned.\n");
+            }
+            CommandResult::BanResult(Err(err)) => {
+                println!(
+                    "> Banned peer, but failed to persist the ban list: {}.\n",
+                    err
+                );
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the unban command.
+    fn match_unban_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::UnbanResult(Ok(true)) => {
+                println!("> Successfully unbanned peer.\n");
+            }
+            CommandResult::UnbanResult(Ok(false)) => {
+                println!("> Peer is not banned.\n");
+            }
+            CommandResult::UnbanResult(Err(err)) => {
+                println!(
+                    "> Unbanned peer, but failed to persist the ban list: {}.\n",
+                    err
+                );
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the disconnect command.
+    fn match_disconnect_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::DisconnectResult(Ok(())) => {
+                println!("> Disconnected peer.\n");
+            }
+            CommandResult::DisconnectResult(Err(err)) => {
+                println!("> Failed to disconnect: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the pin command.
+    fn match_pin_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::PinResult(true) => {
+                println!("> Pinned peer, it is dialed in the background.\n");
+            }
+            CommandResult::PinResult(false) => {
+                println!("> Address is already pinned.\n");
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the unpin command.
+    fn match_unpin_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::UnpinResult(true) => {
+                println!("> Unpinned peer.\n");
+            }
+            CommandResult::UnpinResult(false) => {
+                println!("> Address is not pinned.\n");
+            }
+            _ => {}
+        }
+    }
+
+    // Print the connected, pinned and banned peers.
+    fn match_peers_res(&mut self, res: CommandResult) {
+        if let CommandResult::PeersResult(info) = res {
+            println!("> Connected peers:");
+            for peer_id in info.connected {
+                println!("\t{}", peer_id);
+            }
+            println!("> Pinned peers:");
+            for pinned in info.pinned {
+                let peer_str = pinned
+                    .peer_id
+                    .map(|p| format!(" ({})", p))
+                    .unwrap_or_else(String::new);
+                let state_str = match pinned.state {
+                    PinnedState::Connected => "connected".into(),
+                    PinnedState::Dialing => "dialing".into(),
+                    PinnedState::Backoff { retry_in, failures } => format!(
+                        "redialing in {}s, {} failed attempts",
+                        retry_in.as_secs(),
+                        failures
+                    ),
+                };
+                println!("\t{}{}: {}", pinned.addr, peer_str, state_str);
+            }
+            println!("> Banned peers:");
+            for peer_id in info.banned {
+                println!("\t{}", peer_id);
+            }
+            println!("> Listening on:");
+            for addr in info.listen_addrs {
+                println!("\t{}", addr);
+            }
+            println!("> Observed addresses:");
+            for addr in info.observed_addrs {
+                println!("\t{}", addr);
+            }
+            match info.reachability {
+                Reachability::Unknown => println!("> Reachability: unknown"),
+                Reachability::Public(addr) => println!("> Reachability: public at {}", addr),
+                Reachability::Private => println!("> Reachability: private"),
+            }
+            println!();
+        }
+    }
+
+    // Print the outcome of the register command.
+    fn match_register_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::RegisterResult(Ok(ttl)) => {
+                println!("> Successfully registered for {}s.\n", ttl.as_secs());
+            }
+            CommandResult::RegisterResult(Err(err)) => {
+                println!("> Failed to register: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the unregister command.
+    fn match_unregister_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::UnregisterResult(Ok(())) => {
+                println!("> Unregistered.\n");
+            }
+            CommandResult::UnregisterResult(Err(err)) => {
+                println!("> Failed to unregister: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the peers that were discovered at a rendezvous point.
+    fn match_discover_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::DiscoverResult(Ok(registrations)) => {
+                println!("> Discovered peers:");
+                for registration in registrations {
+                    println!(
+                        "\t{} (expires in {}s)",
+                        registration.peer_id,
+                        registration.ttl.as_secs()
+                    );
+                    for addr in registration.addrs {
+                        println!("\t\t{}", addr);
+                    }
+                }
+                println!();
+            }
+            CommandResult::DiscoverResult(Err(err)) => {
+                println!("> Failed to discover peers: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the share command.
+    fn match_share_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::ShareResult(Ok(root)) => {
+                println!("> Shared file with root hash {}.\n", root);
+            }
+            CommandResult::ShareResult(Err(err)) => {
+                println!("> Failed to share file: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the fetch command.
+    fn match_fetch_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::FetchResult(Ok(info)) => {
+                println!(
+                    "> Fetched {} bytes in {} chunks ({} already downloaded).\n",
+                    info.size, info.chunks, info.resumed
+                );
+            }
+            CommandResult::FetchResult(Err(err)) => {
+                println!("> Failed to fetch file: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the outcome of the release command.
+    fn match_release_res(&mut self, res: CommandResult) {
+        match res {
+            CommandResult::ReleaseResult(Ok((manifest, publisher))) => {
+                println!(
+                    "> Released version {} for {} with root hash {}, signed by {}.\n",
+                    manifest.version, manifest.target, manifest.root, publisher
+                );
+            }
+            CommandResult::ReleaseResult(Err(err)) => {
+                println!("> Failed to release: {}.\n", err);
+            }
+            _ => {}
+        }
+    }
+
+    // Print the announced updates and their state.
+    fn match_updates_res(&mut self, res: CommandResult) {
+        if let CommandResult::UpdatesResult(updates) = res {
+            println!("> Updates:");
+            for update in updates {
+                let state = match update.state {
+                    UpdateState::Pending => "pending".to_string(),
+                    UpdateState::Fetching => "fetching".to_string(),
+                    UpdateState::Staged(path) => format!("staged at {}", path),
+                    UpdateState::Applying => "applying".to_string(),
+                    UpdateState::Applied => "applied".to_string(),
+                    UpdateState::Failed(err) => format!("failed: {}", err),
+                };
+                println!(
+                    "\t{} for {} by {}: {}",
+                    update.manifest.version, update.manifest.target, update.publisher, state
+                );
+            }
+            println!();
+        }
+    }
+
+    // Print the statistics of the swarm task.
+    fn match_stats_res(&mut self, res: CommandResult) {
+        if let CommandResult::StatsResult(stats) = res {
+            println!("> Dropped gossip messages: {}", stats.dropped_messages);
+            println!(
+                "> Rate limited publishes: {}, received messages: {}\n",
+                stats.rate_limited_publishes, stats.rate_limited_messages
+            );
+        }
+    }
+
     // Print the outcome of the shutdown command
     fn match_shutdown_res(&mut self, res: CommandResult) {
         if let CommandResult::ShutdownAck = res {
@@ -216,15 +799,178 @@ impl UserTask {
     }
 
     // Send a command via the channel to the swarm Task.
-    // Fails if the channel is full or closed.
+    // Waits until the channel has capacity, fails if the channel is closed.
     async fn send_channel(&mut self, command: &Command) -> Result<(), String> {
-        future::poll_fn(|tcx: &mut Context<'_>| match self.cmd_tx.poll_ready(tcx) {
-            Poll::Ready(Ok(())) => Poll::Ready(self.cmd_tx.start_send(command.clone())),
-            Poll::Ready(err) => Poll::Ready(err),
-            _ => Poll::Pending,
-        })
-        .await
-        .map_err(|err| format!("Error in channel for sending command: {:?}", err))
+        self.cmd_tx
+            .send(command.clone())
+            .await
+            .map_err(|err| format!("Error in channel for sending command: {:?}", err))
+    }
+
+    // Parse the quorum argument, which is either "one", "majority", "all" or a number of peers.
+    // Defaults to one peer if no quorum was set.
+    fn parse_quorum(quorum: Option<&str>) -> Option<Quorum> {
+        let quorum = match quorum {
+            None | Some("one") => Quorum::One,
+            Some("majority") => Quorum::Majority,
+            Some("all") => Quorum::All,
+            Some(n) => match NonZeroUsize::from_str(n) {
+                Ok(n) => Quorum::N(n),
+                Err(_) => {
+                    println!("> The quorum has to be one, majority, all or a number > 0.\n");
+                    return None;
+                }
+            },
+        };
+        Some(quorum)
+    }
+
+    // Parse the arguments of the led subcommand, None if no state was set.
+    fn parse_led(matches: &ArgMatches) -> Result<Option<LedCommand>, String> {
+        let number = |matches: &ArgMatches, name: &str| -> Result<u64, String> {
+            let value = matches.value_of(name).unwrap_or_default();
+            u64::from_str(value).map_err(|_| format!("The {} has to be a number", name))
+        };
+        let channel = match matches.value_of("channel") {
+            Some(channel) => Some(
+                u8::from_str(channel)
+                    .map_err(|_| String::from("The channel has to be a number"))?,
+            ),
+            None => None,
+        };
+        let state = match matches.subcommand() {
+            ("on", _) => LedState::On,
+            ("off", _) => LedState::Off,
+            ("brightness", Some(matches)) => {
+                let brightness = number(matches, "value")?;
+                LedState::Brightness(brightness.min(u8::MAX as u64) as u8)
+            }
+            ("color", Some(matches)) => {
+                LedState::Color(matches.value_of("value").unwrap_or_default().parse()?)
+            }
+            ("blink", Some(matches)) => match matches.value_of("frequency") {
+                // Equal on and off phases.
+                Some(freq) => {
+                    let freq = f64::from_str(freq)
+                        .ok()
+                        .filter(|freq| *freq > 0.0)
+                        .ok_or("The frequency has to be a number of Hz > 0")?;
+                    // The phase of a low frequency may not fit into a duration.
+                    let phase = Some(0.5 / freq)
+                        .filter(|secs| *secs <= MAX_STEP_DURATION.as_secs_f64())
+                        .map(Duration::from_secs_f64)
+                        .ok_or("The phases of the frequency can last at most 24h")?;
+                    LedState::Blink {
+                        on: phase,
+                        off: phase,
+                    }
+                }
+                None => LedState::Blink {
+                    on: Duration::from_millis(number(matches, "on")?),
+                    off: Duration::from_millis(number(matches, "off")?),
+                },
+            },
+            ("on-for", Some(matches)) => {
+                LedState::OnFor(Duration::from_millis(number(matches, "duration")?))
+            }
+            ("pattern", Some(matches)) => {
+                let steps = matches
+                    .value_of("steps")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(PatternStep::from_str)
+                    .collect::<Result<_, _>>()?;
+                let repeat = match matches.value_of("repeat") {
+                    Some(_) => number(matches, "repeat")?.min(u32::MAX as u64) as u32,
+                    None => 1,
+                };
+                LedState::Pattern { steps, repeat }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(LedCommand { channel, state }))
+    }
+
+    // Parse the execution and expiry time of a published message. The expiry is relative to now.
+    fn parse_schedule(matches: &ArgMatches) -> Result<Schedule, String> {
+        let now = SystemClock.now();
+        let secs = |name: &str| -> Result<Option<u64>, String> {
+            match matches.value_of(name) {
+                Some(secs) => u64::from_str(secs)
+                    .ok()
+                    .and_then(|secs| secs.checked_mul(1000)?.checked_add(now))
+                    .map(Some)
+                    .ok_or_else(|| {
+                        format!("The value of --{} has to be a number of seconds", name)
+                    }),
+                None => Ok(None),
+            }
+        };
+        let execute_at = match matches.value_of("at") {
+            Some(time) => Some(schedule::parse_time(time, now)?),
+            None => secs("in")?,
+        };
+        let expires_at = secs("expire-after")?;
+        if let Some(execute_at) = execute_at {
+            if execute_at.saturating_sub(now) > schedule::MAX_LEAD_TIME.as_millis() as u64 {
+                return Err(format!(
+                    "Messages can be scheduled at most {}s ahead",
+                    schedule::MAX_LEAD_TIME.as_secs()
+                ));
+            }
+        }
+        match (execute_at, expires_at) {
+            (Some(execute_at), Some(expires_at)) if expires_at < execute_at => {
+                Err("The message would expire before it is executed".into())
+            }
+            _ => Ok(Schedule {
+                execute_at,
+                expires_at,
+            }),
+        }
+    }
+
+    // Format a time in milliseconds since the UNIX epoch as time of day in UTC.
+    fn format_time(time: u64) -> String {
+        let secs = time / 1000 % (24 * 60 * 60);
+        format!(
+            "{:02}:{:02}:{:02} UTC",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+
+    // Parse the peer id argument.
+    fn parse_peer_id(peer: &str) -> Option<PeerId> {
+        match PeerId::from_str(peer) {
+            Ok(peer_id) => Some(peer_id),
+            Err(_) => {
+                println!("> Failed to parse the given peer id.\n");
+                None
+            }
+        }
+    }
+
+    // Parse the multiaddress argument.
+    fn parse_addr(addr: &str) -> Option<Multiaddr> {
+        match Multiaddr::from_str(addr) {
+            Ok(addr) => Some(addr),
+            Err(_) => {
+                println!("> Failed to parse the given address into a Multiaddress.\n");
+                None
+            }
+        }
+    }
+
+    // Parse the namespace and the optional address of the rendezvous point.
+    fn parse_rendezvous_args(matches: &ArgMatches) -> Option<(String, Option<Multiaddr>)> {
+        let namespace = matches.value_of("namespace")?.to_string();
+        let point = match matches.value_of("address") {
+            Some(addr) => Some(Self::parse_addr(addr)?),
+            None => None,
+        };
+        Some((namespace, point))
     }
 
     // Parse an users input line to the respective Command
@@ -259,10 +1005,29 @@ impl UserTask {
                     _ if args.contains(&"unsubscribe".to_string()) => {
                         (true, cli::unsubscribe_cmd())
                     }
+                    _ if args.contains(&"catch-up".to_string()) => (true, cli::catch_up_cmd()),
                     _ if args.contains(&"publish".to_string()) => (true, cli::publish_cmd()),
                     _ if args.contains(&"get-record".to_string()) => (true, cli::get_record_cmd()),
                     _ if args.contains(&"put-record".to_string()) => (true, cli::put_record_cmd()),
                     _ if args.contains(&"connect".to_string()) => (true, cli::connect_cmd()),
+                    _ if args.contains(&"provide".to_string()) => (true, cli::provide_cmd()),
+                    _ if args.contains(&"stop-providing".to_string()) => {
+                        (true, cli::stop_providing_cmd())
+                    }
+                    _ if args.contains(&"find-providers".to_string()) => {
+                        (true, cli::find_providers_cmd())
+                    }
+                    _ if args.contains(&"ban".to_string()) => (true, cli::ban_cmd()),
+                    _ if args.contains(&"unban".to_string()) => (true, cli::unban_cmd()),
+                    _ if args.contains(&"disconnect".to_string()) => (true, cli::disconnect_cmd()),
+                    _ if args.contains(&"pin".to_string()) => (true, cli::pin_cmd()),
+                    _ if args.contains(&"unpin".to_string()) => (true, cli::unpin_cmd()),
+                    _ if args.contains(&"register".to_string()) => (true, cli::register_cmd()),
+                    _ if args.contains(&"unregister".to_string()) => (true, cli::unregister_cmd()),
+                    _ if args.contains(&"discover".to_string()) => (true, cli::discover_cmd()),
+                    _ if args.contains(&"share".to_string()) => (true, cli::share_cmd()),
+                    _ if args.contains(&"fetch".to_string()) => (true, cli::fetch_cmd()),
+                    _ if args.contains(&"release".to_string()) => (true, cli::release_cmd()),
                     _ => (false, app),
                 };
                 let subcommand_string = is_sub.then(|| "\n p2p SUBCOMMAND \n").unwrap_or("\n");
@@ -290,66 +1055,258 @@ impl UserTask {
             return Some(Command::UnsubscribeGossipTopic(topic.to_string()));
         }
 
+        if let Some(topic) = matches
+            .subcommand_matches("catch-up")
+            .and_then(|matches| matches.value_of("topic"))
+        {
+            return Some(Command::CatchUp(topic.to_string()));
+        }
+
         if let Some((topic, matches)) = matches
             .subcommand_matches("publish")
             .and_then(|matches| matches.value_of("topic").map(|t| (t, matches)))
         {
             let topic = topic.to_string();
+            let schedule = match Self::parse_schedule(matches) {
+                Ok(schedule) => schedule,
+                Err(err) => {
+                    println!("> Invalid schedule: {}\n", err);
+                    return None;
+                }
+            };
 
             if let Some(value) = matches
                 .subcommand_matches("message")
                 .and_then(|matches| matches.value_of("value"))
             {
-                let data = GossipMessage::Message(value.to_string());
+                let data = GossipMessage::Message(value.to_string()).with_schedule(schedule);
                 return Some(Command::PublishGossipData { topic, data });
             }
 
-            if let Some(matches) = matches.subcommand_matches("led") {
-                let data = if matches.subcommand_matches("on").is_some() {
-                    Some(GossipMessage::SetLed(LedState::On))
-                } else if matches.subcommand_matches("off").is_some() {
-                    Some(GossipMessage::SetLed(LedState::Off))
-                } else if let Some(freq) = matches
-                    .subcommand_matches("blink")
-                    .and_then(|matches| matches.value_of("frequency"))
-                    .and_then(|s| u64::from_str(s).ok())
-                {
-                    Some(GossipMessage::SetLed(LedState::Blink(Duration::from_secs(
-                        freq,
-                    ))))
-                } else {
-                    None
+            if let Some((tag, value)) = matches.subcommand_matches("custom").and_then(|matches| {
+                matches
+                    .value_of("type")
+                    .and_then(|tag| matches.value_of("value").map(|value| (tag, value)))
+            }) {
+                return match self.registry.parse(tag, value) {
+                    Ok(data) => {
+                        let data = data.with_schedule(schedule);
+                        Some(Command::PublishGossipData { topic, data })
+                    }
+                    Err(err) => {
+                        let tags = self.registry.tags().join(", ");
+                        println!("> {}. Registered message types: {}\n", err, tags);
+                        None
+                    }
                 };
-                if let Some(data) = data {
-                    return Some(Command::PublishGossipData { topic, data });
+            }
+
+            if let Some(matches) = matches.subcommand_matches("led") {
+                match Self::parse_led(matches) {
+                    Ok(Some(command)) => {
+                        let data = GossipMessage::SetLed {
+                            id: rand::random(),
+                            command,
+                        }
+                        .with_schedule(schedule);
+                        return Some(Command::PublishCommand { topic, data });
+                    }
+                    // Print the help of the led subcommand.
+                    Ok(None) => {}
+                    Err(err) => {
+                        println!("> Invalid LED command: {}\n", err);
+                        return None;
+                    }
                 }
             }
         }
 
-        if let Some(key) = matches
+        if let Some((key, matches)) = matches
             .subcommand_matches("get-record")
-            .and_then(|matches| matches.value_of("key"))
+            .and_then(|matches| matches.value_of("key").map(|k| (k, matches)))
         {
-            return Some(Command::GetRecord(key.to_string()));
+            let quorum = Self::parse_quorum(matches.value_of("quorum"))?;
+            self.record_output = match matches.value_of("out") {
+                Some(path) => RecordOutput::File(path.to_string()),
+                None => {
+                    let format = matches.value_of("format").unwrap_or("utf8");
+                    RecordOutput::Print(ValueEncoding::from_str(format).ok()?)
+                }
+            };
+            let key = key.to_string();
+            return Some(Command::GetRecord { key, quorum });
         }
 
-        if let Some((key, value)) = matches
+        if let Some((key, matches)) = matches
             .subcommand_matches("put-record")
-            .and_then(|matches| matches.value_of("key").map(|k| (k, matches)))
-            .and_then(|(k, matches)| matches.value_of("value").map(|v| (k.to_string(), v.into())))
+            .and_then(|matches| matches.value_of("key").map(|k| (k.to_string(), matches)))
+        {
+            // Read the value either from a file, or from the argument in the given encoding.
+            let value = match (
+                matches.value_of("file"),
+                matches.value_of("hex"),
+                matches.value_of("base64"),
+                matches.value_of("value"),
+            ) {
+                (Some(path), ..) => {
+                    fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+                }
+                (_, Some(hex), ..) => ValueEncoding::Hex.decode(hex),
+                (_, _, Some(base64), _) => ValueEncoding::Base64.decode(base64),
+                (_, _, _, Some(value)) => ValueEncoding::Utf8.decode(value),
+                _ => Err("No value was given".into()),
+            };
+            let value = match value {
+                Ok(value) => value,
+                Err(err) => {
+                    println!("> {}.\n", err);
+                    return None;
+                }
+            };
+            let quorum = Self::parse_quorum(matches.value_of("quorum"))?;
+            let ttl = match matches.value_of("ttl").map(u64::from_str) {
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                Some(Err(_)) => {
+                    println!("> The ttl has to be a number of seconds.\n");
+                    return None;
+                }
+                None => None,
+            };
+            return Some(Command::PutRecord {
+                key,
+                value,
+                quorum,
+                ttl,
+                signed: matches.is_present("signed"),
+            });
+        }
+
+        if let Some(matches) = matches.subcommand_matches("connect") {
+            if let Some(addr) = matches.value_of("address") {
+                return Self::parse_addr(addr).map(Command::Connect);
+            }
+            if let Some(peer) = matches.value_of("peer") {
+                return Self::parse_peer_id(peer).map(Command::ConnectPeer);
+            }
+        }
+
+        if let Some(key) = matches
+            .subcommand_matches("provide")
+            .and_then(|matches| matches.value_of("key"))
+        {
+            return Some(Command::StartProviding(key.to_string()));
+        }
+
+        if let Some(key) = matches
+            .subcommand_matches("stop-providing")
+            .and_then(|matches| matches.value_of("key"))
         {
-            return Some(Command::PutRecord { key, value });
+            return Some(Command::StopProviding(key.to_string()));
         }
 
-        if let Some(addr_string) = matches
-            .subcommand_matches("connect")
+        if let Some(key) = matches
+            .subcommand_matches("find-providers")
+            .and_then(|matches| matches.value_of("key"))
+        {
+            return Some(Command::GetProviders(key.to_string()));
+        }
+
+        if let Some(peer) = matches
+            .subcommand_matches("ban")
+            .and_then(|matches| matches.value_of("peer"))
+        {
+            return Self::parse_peer_id(peer).map(Command::Ban);
+        }
+
+        if let Some(peer) = matches
+            .subcommand_matches("unban")
+            .and_then(|matches| matches.value_of("peer"))
+        {
+            return Self::parse_peer_id(peer).map(Command::Unban);
+        }
+
+        if let Some(peer) = matches
+            .subcommand_matches("disconnect")
+            .and_then(|matches| matches.value_of("peer"))
+        {
+            return Self::parse_peer_id(peer).map(Command::Disconnect);
+        }
+
+        if let Some(addr) = matches
+            .subcommand_matches("pin")
             .and_then(|matches| matches.value_of("address"))
         {
-            if let Ok(addr) = Multiaddr::from_str(addr_string) {
-                return Some(Command::Connect(addr));
-            } else {
-                println!("> Failed to parse the given address into a Multiaddress.\n");
-            }
+            return Self::parse_addr(addr).map(Command::Pin);
+        }
+
+        if let Some(addr) = matches
+            .subcommand_matches("unpin")
+            .and_then(|matches| matches.value_of("address"))
+        {
+            return Self::parse_addr(addr).map(Command::Unpin);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("register") {
+            let (namespace, point) = Self::parse_rendezvous_args(matches)?;
+            let ttl = match matches.value_of("ttl").map(u64::from_str) {
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                Some(Err(_)) => {
+                    println!("> The ttl has to be a number of seconds.\n");
+                    return None;
+                }
+                None => None,
+            };
+            return Some(Command::Register {
+                namespace,
+                ttl,
+                point,
+            });
+        }
+
+        if let Some(matches) = matches.subcommand_matches("unregister") {
+            let (namespace, point) = Self::parse_rendezvous_args(matches)?;
+            return Some(Command::Unregister { namespace, point });
+        }
+
+        if let Some(matches) = matches.subcommand_matches("discover") {
+            let (namespace, point) = Self::parse_rendezvous_args(matches)?;
+            return Some(Command::Discover { namespace, point });
+        }
+
+        if let Some(path) = matches
+            .subcommand_matches("share")
+            .and_then(|matches| matches.value_of("file"))
+        {
+            return Some(Command::Share(path.to_string()));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("fetch") {
+            let root = matches.value_of("hash")?.to_string();
+            let path = matches.value_of("out")?.to_string();
+            return Some(Command::Fetch { root, path });
+        }
+
+        if let Some(matches) = matches.subcommand_matches("release") {
+            let path = matches.value_of("file")?.to_string();
+            let version = matches.value_of("version")?.to_string();
+            let target = matches.value_of("target").map(String::from);
+            return Some(Command::Release {
+                path,
+                version,
+                target,
+            });
+        }
+
+        if matches.subcommand_matches("updates").is_some() {
+            return Some(Command::GetUpdates);
+        }
+
+        if matches.subcommand_matches("peers").is_some() {
+            return Some(Command::GetPeers);
+        }
+
+        if matches.subcommand_matches("stats").is_some() {
+            return Some(Command::GetStats);
         }
 
         if matches.subcommand_matches("shutdown").is_some() {
@@ -0,0 +1,1349 @@
+// End-to-end tests with multiple nodes in one process.
+// Each node is a swarm task that is driven by sending commands through its channels, the same
+// way the user task does it.
+// The nodes use the in-memory transport and have mDNS disabled, so that they only connect to
+// each other if explicitly told so.
+
+use async_std::{
+    future,
+    task::{self, JoinHandle},
+};
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    prelude::*,
+};
+use libp2p::{
+    core::{
+        transport::{ListenerEvent, MemoryTransport},
+        upgrade::Version,
+    },
+    identity::{ed25519, Keypair, PublicKey},
+    kad::{
+        record::{store::MemoryStore, Key as RecordKey},
+        Kademlia, KademliaEvent, QueryResult, Quorum, Record,
+    },
+    multiaddr::Protocol,
+    noise::{self, NoiseConfig},
+    swarm::SwarmEvent,
+    yamux::YamuxConfig,
+    Multiaddr, PeerId, Swarm, Transport,
+};
+use p2p_network::{
+    channel::{self, BoundedReceiver},
+    config::{Config, RateLimit, RetentionPolicy, TopicRateLimits},
+    led::{LedController, LedError, SimulatedDriver},
+    swarm::SwarmTask,
+    types::*,
+};
+use std::{
+    fs,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+// A node with the channels to its swarm task.
+struct Node {
+    cmd_tx: Sender<Command>,
+    cmd_res_rx: Receiver<CommandResult>,
+    message_rx: BoundedReceiver<(Topic, GossipMessage)>,
+    peer_id: PeerId,
+    // Address on which the node listens directly, empty if it does not.
+    addr: Multiaddr,
+    // Handle of the spawned swarm task, that resolves once the task returned.
+    handle: JoinHandle<()>,
+}
+
+impl Node {
+    // Start a swarm task that listens on a memory address.
+    async fn start() -> Node {
+        Node::start_with(Config::default()).await
+    }
+
+    // Start a swarm task with a custom config, that is changed to use the memory transport.
+    async fn start_with(mut config: Config) -> Node {
+        config.transport.memory = true;
+        config.discovery.mdns = false;
+        let (cmd_tx, cmd_rx) = mpsc::channel(8);
+        let (cmd_res_tx, cmd_res_rx) = mpsc::channel(8);
+        let (message_tx, message_rx) = channel::bounded(64, config.channels.overflow);
+
+        let mut swarm_task = SwarmTask::new(cmd_rx, cmd_res_tx, message_tx, &config)
+            .await
+            .unwrap();
+        let peer_id = swarm_task.local_peer_id();
+        let addrs = swarm_task.start_listening().await.unwrap();
+        let addr = addrs.into_iter().next().unwrap_or_else(Multiaddr::empty);
+        let handle = task::spawn(swarm_task.run());
+        Node {
+            cmd_tx,
+            cmd_res_rx,
+            message_rx,
+            peer_id,
+            addr,
+            handle,
+        }
+    }
+
+    // Send a command to the swarm task and wait for its result.
+    async fn run(&mut self, cmd: Command) -> CommandResult {
+        self.cmd_tx.send(cmd).await.unwrap();
+        self.next_result().await.unwrap()
+    }
+
+    // Wait for the next command result, None if the swarm task returned.
+    async fn next_result(&mut self) -> Option<CommandResult> {
+        with_timeout(self.cmd_res_rx.next()).await
+    }
+
+    // Connect to the other node, returns its peer id.
+    async fn connect(&mut self, other: &Node) -> PeerId {
+        match self.run(Command::Connect(other.addr.clone())).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => peer_id,
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    // Try to connect to the other node, returns the error.
+    async fn connect_err(&mut self, other: &Node) -> String {
+        match self.run(Command::Connect(other.addr.clone())).await {
+            CommandResult::ConnectResult(Err(err)) => err,
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    async fn subscribe(&mut self, topic: &str) {
+        match self.run(Command::SubscribeGossipTopic(topic.into())).await {
+            CommandResult::SubscribeResult(Ok(true)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    // Publish a message, retry until the subscription of a remote peer is known.
+    async fn publish(&mut self, topic: &str, data: GossipMessage) {
+        for _ in 0..50 {
+            let cmd = Command::PublishGossipData {
+                topic: topic.into(),
+                data: data.clone(),
+            };
+            match self.run(cmd).await {
+                CommandResult::PublishResult(Ok(_)) => return,
+                CommandResult::PublishResult(Err(_)) => {
+                    task::sleep(Duration::from_millis(100)).await
+                }
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+        panic!("No peer subscribed to {}", topic);
+    }
+
+    // Poll the peers view until the first pinned peer is in a state that matches.
+    async fn wait_for_pinned(&mut self, f: impl Fn(&PinnedState) -> bool) -> PinnedPeer {
+        with_timeout(async {
+            loop {
+                match self.run(Command::GetPeers).await {
+                    CommandResult::PeersResult(info) => match info.pinned.first() {
+                        Some(pinned) if f(&pinned.state) => return pinned.clone(),
+                        _ => task::sleep(Duration::from_millis(100)).await,
+                    },
+                    res => panic!("Unexpected result: {:?}", res),
+                }
+            }
+        })
+        .await
+    }
+
+    // Poll the peers view until the reachability is known.
+    async fn wait_for_reachability(&mut self) -> Reachability {
+        with_timeout(async {
+            loop {
+                match self.peers_info().await.reachability {
+                    Reachability::Unknown => task::sleep(Duration::from_millis(100)).await,
+                    reachability => return reachability,
+                }
+            }
+        })
+        .await
+    }
+
+    // Poll the peers view until the node listens on the address.
+    async fn wait_for_listen_addr(&mut self, addr: &Multiaddr) {
+        with_timeout(async {
+            while !self.peers_info().await.listen_addrs.contains(addr) {
+                task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+    }
+
+    async fn peers_info(&mut self) -> PeersInfo {
+        match self.run(Command::GetPeers).await {
+            CommandResult::PeersResult(info) => info,
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
+
+// Config of a node with the relay protocol enabled.
+fn relay_config() -> Config {
+    let mut config = Config::default();
+    config.transport.relay.enabled = true;
+    config
+}
+
+// Start a relay, and a node behind a simulated NAT that does not listen directly but only via
+// the relay. Returns both nodes and the /p2p-circuit address of the NATed node.
+async fn start_nated_node() -> (Node, Node, Multiaddr) {
+    let mut config = relay_config();
+    let relay = Node::start_with(config.clone()).await;
+    let relay_addr = relay.addr.clone().with(Protocol::P2p(relay.peer_id.into()));
+
+    config.transport.listen_addrs = Some(Vec::new());
+    config.transport.relay.relays = vec![relay_addr.clone()];
+    let mut nated = Node::start_with(config).await;
+    let circuit_addr = relay_addr.with(Protocol::P2pCircuit);
+    nated.wait_for_listen_addr(&circuit_addr).await;
+    let nated_addr = circuit_addr.with(Protocol::P2p(nated.peer_id.into()));
+    (relay, nated, nated_addr)
+}
+
+async fn with_timeout<T>(f: impl Future<Output = T>) -> T {
+    future::timeout(Duration::from_secs(10), f)
+        .await
+        .expect("Timeout")
+}
+
+#[test]
+fn publish_to_connected_peer() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let mut b = Node::start().await;
+        b.connect(&a).await;
+        a.subscribe("test").await;
+        b.subscribe("test").await;
+
+        b.publish("test", GossipMessage::Message("hello".into()))
+            .await;
+        match with_timeout(a.message_rx.next()).await {
+            Some((topic, GossipMessage::Message(msg))) => {
+                assert_eq!(topic, "test");
+                assert_eq!(msg, "hello");
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    })
+}
+
+#[test]
+fn publish_without_subscribers_fails() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        a.connect(&b).await;
+        a.subscribe("test").await;
+
+        let cmd = Command::PublishGossipData {
+            topic: "test".into(),
+            data: GossipMessage::Message("hello".into()),
+        };
+        match a.run(cmd).await {
+            CommandResult::PublishResult(Err(err)) => {
+                assert_eq!(err, "No known peers are subscribing to that topic.")
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn put_and_get_record() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        let mut c = Node::start().await;
+        a.connect(&b).await;
+        c.connect(&b).await;
+
+        let put = Command::PutRecord {
+            key: "key".into(),
+            value: b"value".to_vec(),
+            quorum: Quorum::One,
+            ttl: None,
+            signed: false,
+        };
+        match a.run(put).await {
+            CommandResult::PutRecordResult(Ok(key)) => assert_eq!(key, "key"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let get = Command::GetRecord {
+            key: "key".into(),
+            quorum: Quorum::One,
+        };
+        match c.run(get).await {
+            CommandResult::GetRecordResult(Ok(records)) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].record.value, b"value");
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn record_larger_than_kademlia_message_is_rejected() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        a.connect(&b).await;
+
+        // The value alone would fit into a message, but not together with the long key and the
+        // signature.
+        let put = Command::PutRecord {
+            key: "k".repeat(1024),
+            value: vec![0; 15 * 1024],
+            quorum: Quorum::One,
+            ttl: None,
+            signed: true,
+        };
+        match a.run(put).await {
+            CommandResult::PutRecordResult(Err(err)) => {
+                assert!(err.contains("limited to 16384 bytes"), "{}", err)
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let put = Command::PutRecord {
+            key: "key".into(),
+            value: vec![0; 15 * 1024],
+            quorum: Quorum::One,
+            ttl: None,
+            signed: true,
+        };
+        match a.run(put).await {
+            CommandResult::PutRecordResult(Ok(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+// Start a plain Kademlia node that does not validate records, to forge records in the namespace
+// of other peers. Returns the swarm and its address.
+async fn start_forger() -> (Swarm<Kademlia<MemoryStore>>, Multiaddr) {
+    let keypair = Keypair::generate_ed25519();
+    let peer_id = keypair.public().into_peer_id();
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(&keypair)
+        .unwrap();
+    let transport = MemoryTransport
+        .upgrade(Version::V1)
+        .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(YamuxConfig::default())
+        .boxed();
+    let kademlia = Kademlia::new(peer_id, MemoryStore::new(peer_id));
+    let mut swarm = Swarm::new(transport, kademlia, peer_id);
+    Swarm::listen_on(&mut swarm, "/memory/0".parse().unwrap()).unwrap();
+    loop {
+        if let SwarmEvent::NewListenAddr(addr) = swarm.next_event().await {
+            return (swarm, addr);
+        }
+    }
+}
+
+// Sign the value with the keypair of the forger, in the encoding of signed records.
+fn forge_signed_value(keypair: &Keypair, key: &RecordKey, value: &[u8]) -> Vec<u8> {
+    let mut payload = key.to_vec();
+    payload.extend_from_slice(value);
+    let signature = keypair.sign(&payload).unwrap();
+    let mut signed = Vec::new();
+    for field in [keypair.public().into_protobuf_encoding(), signature].iter() {
+        signed.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        signed.extend_from_slice(field);
+    }
+    signed.extend_from_slice(value);
+    signed
+}
+
+#[test]
+fn signed_record_can_only_be_written_by_owner() {
+    task::block_on(async {
+        let mut owner = Node::start().await;
+        let b = Node::start().await;
+        let mut c = Node::start().await;
+        owner.connect(&b).await;
+
+        let put = Command::PutRecord {
+            key: "led-state".into(),
+            value: b"on".to_vec(),
+            quorum: Quorum::One,
+            ttl: None,
+            signed: true,
+        };
+        let key = match owner.run(put).await {
+            CommandResult::PutRecordResult(Ok(key)) => key,
+            res => panic!("Unexpected result: {:?}", res),
+        };
+
+        // Another peer tries to overwrite the record with a value that is signed by itself.
+        let (mut forger, forger_addr) = start_forger().await;
+        let forger_id = *forger.local_peer_id();
+        let forger_key = Keypair::generate_ed25519();
+        let record_key = RecordKey::new(&key);
+        let forged = forge_signed_value(&forger_key, &record_key, b"off");
+        forger
+            .behaviour_mut()
+            .add_address(&b.peer_id, b.addr.clone());
+        forger
+            .behaviour_mut()
+            .put_record(Record::new(record_key, forged), Quorum::One)
+            .unwrap();
+        let put_result = with_timeout(async {
+            loop {
+                if let KademliaEvent::QueryResult {
+                    result: QueryResult::PutRecord(res),
+                    ..
+                } = forger.next().await
+                {
+                    return res;
+                }
+            }
+        })
+        .await;
+        assert!(put_result.is_err());
+        // Keep serving the forged record from the local store of the forger.
+        task::spawn(async move {
+            loop {
+                forger.next().await;
+            }
+        });
+
+        // The forged record is returned by the forger, but dropped because of its signature.
+        c.connect(&owner).await;
+        c.connect(&b).await;
+        match c.run(Command::Connect(forger_addr)).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => assert_eq!(peer_id, forger_id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let get = Command::GetRecord {
+            key,
+            quorum: Quorum::N(NonZeroUsize::new(3).unwrap()),
+        };
+        match c.run(get).await {
+            CommandResult::GetRecordResult(Ok(records)) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].record.value, b"on");
+                assert!(records[0].is_signed);
+                assert!(!records[0].peers.contains(&Some(forger_id)));
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn connect_to_unreachable_address_fails() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        let addr = b.addr.clone();
+        // Stop the other node, so that nothing listens on its address anymore.
+        drop(b);
+        task::sleep(Duration::from_millis(100)).await;
+
+        match a.run(Command::Connect(addr)).await {
+            CommandResult::ConnectResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn connect_to_address_with_peer_id() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let mut b = Node::start().await;
+        let c = Node::start().await;
+        let c_id = b.connect(&c).await;
+
+        let addr = c.addr.clone().with(Protocol::P2p(c_id.into()));
+        match a.run(Command::Connect(addr)).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => assert_eq!(peer_id, c_id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // The dial fails if another peer listens on the address.
+        let addr = b.addr.clone().with(Protocol::P2p(c_id.into()));
+        match a.run(Command::Connect(addr)).await {
+            CommandResult::ConnectResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn connect_by_peer_id() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let mut b = Node::start().await;
+        let c = Node::start().await;
+        let c_id = b.connect(&c).await;
+        a.connect(&b).await;
+
+        // The address of c is only known to b, and found via the DHT.
+        match a.run(Command::ConnectPeer(c_id)).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => assert_eq!(peer_id, c_id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match a.run(Command::GetPeers).await {
+            CommandResult::PeersResult(info) => assert!(info.connected.contains(&c_id)),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn connect_times_out() {
+    task::block_on(async {
+        let mut config = Config::default();
+        config.commands.timeout_secs = 1;
+        let mut a = Node::start_with(config).await;
+
+        // Listener that accepts connections, but never answers the handshake.
+        let mut listener = MemoryTransport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+        let addr = match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+            _ => panic!("Listener did not report its address"),
+        };
+        match a.run(Command::Connect(addr)).await {
+            CommandResult::Timeout(timeout) => assert_eq!(timeout, Duration::from_secs(1)),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        drop(listener);
+    })
+}
+
+#[test]
+fn shutdown_after_pending_commands() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+
+        // Commands that were sent before the shutdown are still executed in order.
+        let subscribe = Command::SubscribeGossipTopic("test".into());
+        a.cmd_tx.send(subscribe).await.unwrap();
+        a.cmd_tx.send(Command::Shutdown).await.unwrap();
+        match a.next_result().await {
+            Some(CommandResult::SubscribeResult(Ok(true))) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match a.next_result().await {
+            Some(CommandResult::ShutdownAck) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // The swarm task returns after the acknowledgment and closes its channels.
+        with_timeout(&mut a.handle).await;
+        assert!(a.next_result().await.is_none());
+        assert!(with_timeout(a.message_rx.next()).await.is_none());
+        assert!(a.cmd_tx.send(Command::GetStats).await.is_err());
+    })
+}
+
+#[test]
+fn connection_limit_is_enforced() {
+    task::block_on(async {
+        let mut config = Config::default();
+        config.limits.max_established_outgoing = Some(1);
+        let mut a = Node::start_with(config).await;
+        let b = Node::start().await;
+        let c = Node::start().await;
+        a.connect(&b).await;
+
+        let err = a.connect_err(&c).await;
+        assert!(err.contains("limit"), "{}", err);
+    })
+}
+
+#[test]
+fn total_connection_limit_is_enforced() {
+    task::block_on(async {
+        let mut config = Config::default();
+        config.limits.max_established = Some(1);
+        let mut a = Node::start_with(config).await;
+        let b = Node::start().await;
+        let mut c = Node::start().await;
+        a.connect(&b).await;
+
+        // The incoming connection is within the per-direction limits, but closed right after it
+        // was established.
+        c.connect(&a).await;
+        with_timeout(async {
+            while a.peers_info().await.connected != vec![b.peer_id] {
+                task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+    })
+}
+
+#[test]
+fn ban_and_unban_peer() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        let b_id = a.connect(&b).await;
+
+        match a.run(Command::Ban(b_id)).await {
+            CommandResult::BanResult(Ok(true)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // The existing connection was closed, and new connections are rejected.
+        match a.run(Command::Disconnect(b_id)).await {
+            CommandResult::DisconnectResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let err = a.connect_err(&b).await;
+        assert!(err.contains("banned"), "{}", err);
+
+        match a.run(Command::Unban(b_id)).await {
+            CommandResult::UnbanResult(Ok(true)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        a.connect(&b).await;
+    })
+}
+
+#[test]
+fn ban_list_is_persisted() {
+    task::block_on(async {
+        let b = Node::start().await;
+        let path = std::env::temp_dir().join(format!("p2p-ban-list-{}", std::process::id()));
+        let mut config = Config::default();
+        config.peers.ban_list_file = Some(path.to_string_lossy().into_owned());
+
+        let mut a = Node::start_with(config.clone()).await;
+        let b_id = a.connect(&b).await;
+        match a.run(Command::Ban(b_id)).await {
+            CommandResult::BanResult(Ok(true)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        drop(a);
+
+        // A restarted node loads the ban list.
+        let mut a = Node::start_with(config).await;
+        let err = a.connect_err(&b).await;
+        assert!(err.contains("banned"), "{}", err);
+        let _ = fs::remove_file(path);
+    })
+}
+
+#[test]
+fn ban_is_only_applied_if_persisted() {
+    task::block_on(async {
+        let b = Node::start().await;
+        let c = Node::start().await;
+        let path = std::env::temp_dir().join(format!("p2p-ban-list-fail-{}", std::process::id()));
+        let mut config = Config::default();
+        config.peers.ban_list_file = Some(path.to_string_lossy().into_owned());
+
+        let mut a = Node::start_with(config).await;
+        let b_id = a.connect(&b).await;
+        let c_id = a.connect(&c).await;
+        match a.run(Command::Ban(b_id)).await {
+            CommandResult::BanResult(Ok(true)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Writing the ban list fails once its path is a directory.
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        match a.run(Command::Ban(c_id)).await {
+            CommandResult::BanResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match a.run(Command::Unban(b_id)).await {
+            CommandResult::UnbanResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match a.run(Command::Disconnect(c_id)).await {
+            CommandResult::DisconnectResult(Ok(())) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let err = a.connect_err(&b).await;
+        assert!(err.contains("banned"), "{}", err);
+        assert_eq!(a.peers_info().await.banned, vec![b_id]);
+        let _ = fs::remove_dir(path);
+    })
+}
+
+#[test]
+fn disconnect_peer() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        let b_id = a.connect(&b).await;
+
+        match a.run(Command::Disconnect(b_id)).await {
+            CommandResult::DisconnectResult(Ok(())) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match a.run(Command::Disconnect(b_id)).await {
+            CommandResult::DisconnectResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // Disconnected peers are not banned.
+        a.connect(&b).await;
+    })
+}
+
+#[test]
+fn pinned_peer_is_redialed() {
+    task::block_on(async {
+        let mut a = Node::start().await;
+        let b = Node::start().await;
+        match a.run(Command::Pin(b.addr.clone())).await {
+            CommandResult::PinResult(true) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let pinned = a.wait_for_pinned(|s| s == &PinnedState::Connected).await;
+        let b_id = pinned.peer_id.unwrap();
+
+        // The peer is dialed again after the connection was closed.
+        match a.run(Command::Disconnect(b_id)).await {
+            CommandResult::DisconnectResult(Ok(())) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        a.wait_for_pinned(|s| matches!(s, PinnedState::Backoff { .. }))
+            .await;
+        a.wait_for_pinned(|s| s == &PinnedState::Connected).await;
+
+        match a.run(Command::Unpin(b.addr.clone())).await {
+            CommandResult::UnpinResult(true) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match a.run(Command::GetPeers).await {
+            CommandResult::PeersResult(info) => {
+                assert!(info.pinned.is_empty());
+                assert_eq!(info.connected, vec![b_id]);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn unreachable_pinned_peer_backs_off() {
+    task::block_on(async {
+        // Dials of a pinned address are considered failed after the command timeout, if the swarm
+        // does not report the failure.
+        let mut config = Config::default();
+        config.commands.timeout_secs = 1;
+        let unreachable: Multiaddr = format!("/memory/{}", rand::random::<u64>() | 1)
+            .parse()
+            .unwrap();
+        config.peers.pinned = vec![unreachable.clone()];
+        let mut a = Node::start_with(config).await;
+        let pinned = a
+            .wait_for_pinned(
+                |s| matches!(s, PinnedState::Backoff { failures, .. } if *failures > 0),
+            )
+            .await;
+        assert_eq!(pinned.peer_id, None);
+
+        // Failed dials of a pinned peer id are reported right away, long before the timeout.
+        let mut config = Config::default();
+        config.commands.timeout_secs = 3600;
+        let peer_id = PeerId::random();
+        config.peers.pinned = vec![unreachable.with(Protocol::P2p(peer_id.into()))];
+        let mut b = Node::start_with(config).await;
+        let pinned = b
+            .wait_for_pinned(
+                |s| matches!(s, PinnedState::Backoff { failures, .. } if *failures > 0),
+            )
+            .await;
+        assert_eq!(pinned.peer_id, Some(peer_id));
+    })
+}
+
+#[test]
+fn connect_via_relay() {
+    task::block_on(async {
+        let (mut relay, mut nated, nated_addr) = start_nated_node().await;
+        let mut source = Node::start_with(relay_config()).await;
+
+        match source.run(Command::Connect(nated_addr)).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => assert_eq!(peer_id, nated.peer_id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // The NATed node dialed the relay back, but the relay can't dial the address under which
+        // it observed the NATed node.
+        assert_eq!(nated.wait_for_reachability().await, Reachability::Private);
+        assert_eq!(
+            relay.wait_for_reachability().await,
+            Reachability::Public(relay.addr.clone())
+        );
+    })
+}
+
+#[test]
+fn connect_by_peer_id_via_relay() {
+    task::block_on(async {
+        let (relay, nated, _) = start_nated_node().await;
+        let mut source = Node::start_with(relay_config()).await;
+        source.connect(&relay).await;
+
+        // The relay learned the /p2p-circuit address of the NATed node via identify, and returns
+        // it in the DHT query.
+        match source.run(Command::ConnectPeer(nated.peer_id)).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => assert_eq!(peer_id, nated.peer_id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn register_and_discover_at_rendezvous_point() {
+    task::block_on(async {
+        let mut config = Config::default();
+        config.discovery.rendezvous_server = true;
+        let point = Node::start_with(config).await;
+
+        let mut config = Config::default();
+        config.discovery.rendezvous_point =
+            Some(point.addr.clone().with(Protocol::P2p(point.peer_id.into())));
+        let mut a = Node::start_with(config.clone()).await;
+        let mut b = Node::start_with(config).await;
+
+        let register = Command::Register {
+            namespace: "boards".into(),
+            ttl: None,
+            point: None,
+        };
+        match a.run(register).await {
+            CommandResult::RegisterResult(Ok(ttl)) => {
+                assert_eq!(ttl, Duration::from_secs(2 * 60 * 60))
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let discover = Command::Discover {
+            namespace: "boards".into(),
+            point: None,
+        };
+        match b.run(discover).await {
+            CommandResult::DiscoverResult(Ok(registrations)) => {
+                assert_eq!(registrations.len(), 1);
+                assert_eq!(registrations[0].peer_id, a.peer_id);
+                assert!(registrations[0].addrs.contains(&a.addr));
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // The discovered peer is known by its address now.
+        match b.run(Command::ConnectPeer(a.peer_id)).await {
+            CommandResult::ConnectResult(Ok(peer_id)) => assert_eq!(peer_id, a.peer_id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let unregister = Command::Unregister {
+            namespace: "boards".into(),
+            point: None,
+        };
+        match a.run(unregister).await {
+            CommandResult::UnregisterResult(Ok(())) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn register_at_peer_that_is_no_rendezvous_point_fails() {
+    task::block_on(async {
+        let other = Node::start().await;
+        let mut a = Node::start().await;
+        let register = Command::Register {
+            namespace: "boards".into(),
+            ttl: None,
+            point: Some(other.addr.clone()),
+        };
+        match a.run(register).await {
+            CommandResult::RegisterResult(Err(err)) => {
+                assert_eq!(err, "Peer is not a rendezvous point")
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+#[test]
+fn share_and_fetch_file() {
+    task::block_on(async {
+        let temp_path = |name: &str| {
+            std::env::temp_dir()
+                .join(format!("p2p-file-{}-{}", std::process::id(), name))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let shared_path = temp_path("shared");
+        fs::write(&shared_path, &data).unwrap();
+
+        let mut a = Node::start().await;
+        let mut b = Node::start().await;
+        b.connect(&a).await;
+        let root = match a.run(Command::Share(shared_path.clone())).await {
+            CommandResult::ShareResult(Ok(root)) => root,
+            res => panic!("Unexpected result: {:?}", res),
+        };
+
+        let out_path = temp_path("out");
+        let fetch = Command::Fetch {
+            root: root.clone(),
+            path: out_path.clone(),
+        };
+        match b.run(fetch).await {
+            CommandResult::FetchResult(Ok(info)) => {
+                assert_eq!(info.size, data.len() as u64);
+                assert_eq!(info.chunks, 4);
+                assert_eq!(info.resumed, 0);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(fs::read(&out_path).unwrap(), data);
+
+        // Unknown files have no providers.
+        let fetch = Command::Fetch {
+            root: "00".repeat(32),
+            path: out_path.clone(),
+        };
+        match b.run(fetch).await {
+            CommandResult::FetchResult(Err(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let _ = fs::remove_file(shared_path);
+        let _ = fs::remove_file(out_path);
+    })
+}
+
+fn update_temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("p2p-update-{}-{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Start a maintainer that signs releases with a new key, which is written to the path.
+// Returns the node and the peer id of the signing key.
+async fn start_maintainer(key_path: &str) -> (Node, PeerId) {
+    let signing_key = ed25519::Keypair::generate();
+    fs::write(key_path, &signing_key.encode()[..]).unwrap();
+    let publisher = PublicKey::Ed25519(signing_key.public()).into_peer_id();
+    let mut config = Config::default();
+    config.updates.signing_key_file = Some(key_path.into());
+    (Node::start_with(config).await, publisher)
+}
+
+// Release a version, retry until the subscription of a trusting node to the updates topic is
+// known.
+async fn release(maintainer: &mut Node, path: &str, version: &str) -> (UpdateManifest, PeerId) {
+    for _ in 0..50 {
+        let release = Command::Release {
+            path: path.into(),
+            version: version.into(),
+            target: None,
+        };
+        match maintainer.run(release).await {
+            CommandResult::ReleaseResult(Ok(res)) => return res,
+            CommandResult::ReleaseResult(Err(_)) => task::sleep(Duration::from_millis(100)).await,
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+    panic!("Release was not announced");
+}
+
+// Poll the announced updates until the first one is in a state that matches.
+async fn wait_for_update(node: &mut Node, f: impl Fn(&UpdateState) -> bool) -> UpdateInfo {
+    with_timeout(async {
+        loop {
+            match node.run(Command::GetUpdates).await {
+                CommandResult::UpdatesResult(updates) => match updates.first() {
+                    Some(update) if f(&update.state) => return update.clone(),
+                    _ => task::sleep(Duration::from_millis(100)).await,
+                },
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    })
+    .await
+}
+
+#[test]
+fn release_is_installed_by_trusting_node() {
+    task::block_on(async {
+        let payload = b"dummy firmware".to_vec();
+        let payload_path = update_temp_path("payload");
+        fs::write(&payload_path, &payload).unwrap();
+        let key_path = update_temp_path("key");
+        let (mut maintainer, publisher) = start_maintainer(&key_path).await;
+
+        // The node records the applied version via the apply hook.
+        let applied_path = update_temp_path("applied");
+        let staging_dir = update_temp_path("staging");
+        let mut config = Config::default();
+        config.updates.trusted_publishers = vec![publisher.to_string()];
+        config.updates.version = "0.1.0".into();
+        config.updates.staging_dir = staging_dir.clone();
+        config.updates.apply_hook = vec![
+            "sh".into(),
+            "-c".into(),
+            format!("echo $1 > {}", applied_path),
+        ];
+        let mut node = Node::start_with(config).await;
+        node.connect(&maintainer).await;
+
+        let (manifest, signed_by) = release(&mut maintainer, &payload_path, "0.2.0").await;
+        assert_eq!(signed_by, publisher);
+
+        let update = wait_for_update(&mut node, |state| {
+            !matches!(
+                state,
+                UpdateState::Pending | UpdateState::Fetching | UpdateState::Applying
+            )
+        })
+        .await;
+        assert_eq!(update.state, UpdateState::Applied);
+        assert_eq!(update.manifest, manifest);
+        assert_eq!(update.publisher, publisher);
+        assert_eq!(fs::read_to_string(&applied_path).unwrap().trim(), "0.2.0");
+        let staged_path = format!("{}/{}-0.2.0", staging_dir, manifest.target);
+        assert_eq!(fs::read(staged_path).unwrap(), payload);
+
+        let _ = fs::remove_dir_all(staging_dir);
+        for path in [payload_path, key_path, applied_path].iter() {
+            let _ = fs::remove_file(path);
+        }
+    })
+}
+
+#[test]
+fn commands_are_handled_while_update_is_fetched() {
+    task::block_on(async {
+        let payload_path = update_temp_path("fetched-payload");
+        fs::write(&payload_path, b"dummy firmware").unwrap();
+        let key_path = update_temp_path("fetched-key");
+        let (mut maintainer, publisher) = start_maintainer(&key_path).await;
+
+        let staging_dir = update_temp_path("fetched-staging");
+        let mut config = Config::default();
+        config.updates.trusted_publishers = vec![publisher.to_string()];
+        config.updates.staging_dir = staging_dir.clone();
+        let mut node = Node::start_with(config).await;
+        node.connect(&maintainer).await;
+
+        // A peer that is not polled once it is connected, so that the lookup of the providers of
+        // the payload waits for it.
+        let (mut silent, silent_addr) = start_forger().await;
+        let accept = async {
+            loop {
+                if let SwarmEvent::ConnectionEstablished { .. } = silent.next_event().await {
+                    return;
+                }
+            }
+        };
+        let connect = node.run(Command::Connect(silent_addr));
+        match futures::future::join(connect, accept).await {
+            (CommandResult::ConnectResult(Ok(_)), ()) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let (manifest, _) = release(&mut maintainer, &payload_path, "0.2.0").await;
+        let update = wait_for_update(&mut node, |state| state != &UpdateState::Pending).await;
+        assert_eq!(update.state, UpdateState::Fetching);
+        // Other commands are handled while the payload is fetched.
+        assert!(node
+            .peers_info()
+            .await
+            .connected
+            .contains(&maintainer.peer_id));
+
+        // The fetch completes once the peer responds.
+        task::spawn(async move {
+            loop {
+                silent.next_event().await;
+            }
+        });
+        let update = wait_for_update(&mut node, |state| {
+            !matches!(state, UpdateState::Pending | UpdateState::Fetching)
+        })
+        .await;
+        let staged_path = format!("{}/{}-0.2.0", staging_dir, manifest.target);
+        assert_eq!(update.state, UpdateState::Staged(staged_path));
+
+        let _ = fs::remove_dir_all(staging_dir);
+        for path in [payload_path, key_path].iter() {
+            let _ = fs::remove_file(path);
+        }
+    })
+}
+
+#[test]
+fn rate_limits_are_enforced() {
+    task::block_on(async {
+        let limit = RateLimit {
+            per_sec: 0.001,
+            burst: 1,
+        };
+        let mut config = Config::default();
+        config.rate_limits.topics.insert(
+            "test".into(),
+            TopicRateLimits {
+                incoming: Some(limit),
+                ..Default::default()
+            },
+        );
+        let mut a = Node::start_with(config).await;
+        let mut config = Config::default();
+        config.rate_limits.topics.insert(
+            "limited".into(),
+            TopicRateLimits {
+                publish: Some(limit),
+                ..Default::default()
+            },
+        );
+        let mut b = Node::start_with(config).await;
+        b.connect(&a).await;
+        a.subscribe("test").await;
+        b.subscribe("test").await;
+
+        // The second publish to the limited topic fails, regardless of the result of the first.
+        for _ in 0..2 {
+            let cmd = Command::PublishGossipData {
+                topic: "limited".into(),
+                data: GossipMessage::Message("hello".into()),
+            };
+            if let CommandResult::PublishResult(Err(err)) = b.run(cmd).await {
+                if err.starts_with("Rate limit of topic limited exceeded") {
+                    break;
+                }
+            }
+        }
+        match b.run(Command::GetStats).await {
+            CommandResult::StatsResult(stats) => assert_eq!(stats.rate_limited_publishes, 1),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Only the first message of b is within the incoming limit of a.
+        b.publish("test", GossipMessage::Message("first".into()))
+            .await;
+        b.publish("test", GossipMessage::Message("second".into()))
+            .await;
+        match with_timeout(a.message_rx.next()).await {
+            Some((_, GossipMessage::Message(msg))) => assert_eq!(msg, "first"),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        with_timeout(async {
+            loop {
+                match a.run(Command::GetStats).await {
+                    CommandResult::StatsResult(stats) if stats.rate_limited_messages == 1 => break,
+                    CommandResult::StatsResult(_) => task::sleep(Duration::from_millis(100)).await,
+                    res => panic!("Unexpected result: {:?}", res),
+                }
+            }
+        })
+        .await;
+        assert!(a.message_rx.next().now_or_never().is_none());
+    })
+}
+
+#[test]
+fn encrypted_topic_is_only_readable_with_key() {
+    task::block_on(async {
+        let config_with_key = |key: &str| {
+            let mut config = Config::default();
+            config
+                .encryption
+                .topic_keys
+                .insert("secret".into(), key.repeat(32));
+            config
+        };
+        // A is only connected to b, so that it could only receive the messages of c via b.
+        let mut config = config_with_key("01");
+        config.limits.max_established = Some(1);
+        let mut a = Node::start_with(config).await;
+        let mut b = Node::start_with(config_with_key("01")).await;
+        let mut c = Node::start_with(config_with_key("02")).await;
+        b.connect(&a).await;
+        c.connect(&b).await;
+        a.subscribe("secret").await;
+        b.subscribe("secret").await;
+        c.subscribe("secret").await;
+
+        a.publish("secret", GossipMessage::Message("hello".into()))
+            .await;
+        match with_timeout(b.message_rx.next()).await {
+            Some((_, GossipMessage::Message(msg))) => assert_eq!(msg, "hello"),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // Messages encrypted with another key are reported.
+        c.publish("secret", GossipMessage::Message("hello".into()))
+            .await;
+        match with_timeout(b.message_rx.next()).await {
+            Some((_, GossipMessage::Undecryptable { source, .. })) => {
+                assert_eq!(source, Some(c.peer_id))
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        // ... and rejected, so that they are not forwarded to other peers with the key.
+        task::sleep(Duration::from_millis(500)).await;
+        assert!(a.message_rx.next().now_or_never().is_none());
+    })
+}
+
+#[test]
+fn catch_up_on_retained_messages() {
+    task::block_on(async {
+        let mut config = Config::default();
+        config
+            .retention
+            .topics
+            .insert("led".into(), RetentionPolicy::LatestPerType);
+        let mut a = Node::start_with(config).await;
+        let mut b = Node::start().await;
+        b.connect(&a).await;
+        a.subscribe("led").await;
+        b.subscribe("led").await;
+
+        // Only the latest message of each type is retained.
+        let set_led = |state| GossipMessage::SetLed {
+            id: 0,
+            command: LedCommand::all(state),
+        };
+        b.publish("led", set_led(LedState::On)).await;
+        b.publish("led", GossipMessage::Message("hello".into()))
+            .await;
+        b.publish("led", set_led(LedState::Off)).await;
+        for _ in 0..3 {
+            with_timeout(a.message_rx.next()).await.unwrap();
+        }
+
+        let mut c = Node::start().await;
+        c.connect(&a).await;
+        c.subscribe("led").await;
+        // Retry until the subscription of a is known.
+        let mut count = None;
+        for _ in 0..50 {
+            match c.run(Command::CatchUp("led".into())).await {
+                CommandResult::CatchUpResult(Ok(n)) => {
+                    count = Some(n);
+                    break;
+                }
+                CommandResult::CatchUpResult(Err(_)) => {
+                    task::sleep(Duration::from_millis(100)).await
+                }
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+        assert_eq!(count, Some(2));
+        match with_timeout(c.message_rx.next()).await {
+            Some((_, GossipMessage::Message(msg))) => assert_eq!(msg, "hello"),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        match with_timeout(c.message_rx.next()).await {
+            Some((
+                _,
+                GossipMessage::SetLed {
+                    command:
+                        LedCommand {
+                            state: LedState::Off,
+                            ..
+                        },
+                    ..
+                },
+            )) => {}
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // Messages that were already received are skipped.
+        match c.run(Command::CatchUp("led".into())).await {
+            CommandResult::CatchUpResult(Ok(0)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    })
+}
+
+// Apply the LED commands that the node receives, and acknowledge them the same way as the user
+// task does it.
+fn acknowledge_led_commands(mut node: Node, channels: u8) -> JoinHandle<()> {
+    task::spawn(async move {
+        let mut leds = LedController::new(SimulatedDriver::new(channels, false)).unwrap();
+        while let Some((topic, message)) = node.message_rx.next().await {
+            if let GossipMessage::SetLed { id, command } = message {
+                let status = match leds.apply(&command, Instant::now()) {
+                    Ok(()) => AckStatus::Applied,
+                    Err(LedError::Invalid(reason)) => AckStatus::Rejected(reason),
+                    Err(LedError::Driver(error)) => AckStatus::Error(error),
+                };
+                match node.run(Command::Acknowledge { topic, id, status }).await {
+                    CommandResult::AcknowledgeResult(Ok(())) => {}
+                    res => panic!("Unexpected result: {:?}", res),
+                }
+            }
+        }
+    })
+}
+
+#[test]
+fn device_commands_are_acknowledged() {
+    task::block_on(async {
+        let mut config = Config::default();
+        config.commands.ack_window_ms = 500;
+        let mut a = Node::start_with(config).await;
+        let mut b = Node::start().await;
+        let mut c = Node::start().await;
+        let mut d = Node::start().await;
+        for node in [&mut b, &mut c, &mut d].iter_mut() {
+            node.connect(&a).await;
+            node.subscribe("led").await;
+        }
+        let (b_id, c_id, d_id) = (b.peer_id, c.peer_id, d.peer_id);
+        // The LED of channel 1 only exists on b, d does not acknowledge commands.
+        acknowledge_led_commands(b, 2);
+        acknowledge_led_commands(c, 1);
+
+        let command = LedCommand {
+            channel: Some(1),
+            state: LedState::On,
+        };
+        let data = GossipMessage::SetLed { id: 42, command };
+        // Retry until the subscriptions of all peers are known.
+        for _ in 0..20 {
+            let cmd = Command::PublishCommand {
+                topic: "led".into(),
+                data: data.clone(),
+            };
+            let acks = match a.run(cmd).await {
+                CommandResult::PublishCommandResult(Ok(acks)) => acks,
+                CommandResult::PublishCommandResult(Err(_)) => continue,
+                res => panic!("Unexpected result: {:?}", res),
+            };
+            if acks.len() < 3 {
+                continue;
+            }
+            let status = |peer_id| {
+                let ack = acks.iter().find(|ack| ack.peer_id == peer_id).unwrap();
+                ack.status.clone()
+            };
+            assert_eq!(status(b_id), Some(AckStatus::Applied));
+            let rejected = AckStatus::Rejected("LED 1 does not exist".into());
+            assert_eq!(status(c_id), Some(rejected));
+            assert_eq!(status(d_id), None);
+            // Acknowledgments are not forwarded as gossip messages.
+            assert!(a.message_rx.next().now_or_never().is_none());
+            return;
+        }
+        panic!("Not all peers acknowledged the command");
+    })
+}